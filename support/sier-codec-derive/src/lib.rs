@@ -0,0 +1,140 @@
+//! `#[derive(SierEncode, SierDecode)]` for structs with named fields, whose
+//! types all implement `sier_codec::SierValue`. Use through the `derive`
+//! feature of `sier-codec` rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::{
+    ext::IdentExt, parse_macro_input, spanned::Spanned, Data, DeriveInput, Fields, Ident, LitStr,
+};
+
+#[proc_macro_derive(SierEncode)]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, "SierEncode", encode)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(SierDecode)]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, "SierDecode", decode)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Field<'a> {
+    ident: &'a Ident,
+    /// The field name in the schema, `ident` without any `r#`.
+    name: LitStr,
+    /// `ident`'s `Type`.
+    sier_type: TokenStream2,
+    /// `self.ident` as a `Value`.
+    to_value: TokenStream2,
+    /// `ident`'s value from `obj`, an `Object`.
+    from_object: TokenStream2,
+}
+
+fn expand(
+    input: &DeriveInput,
+    trait_name: &str,
+    body: fn(&DeriveInput, &[Field]) -> TokenStream2,
+) -> syn::Result<TokenStream2> {
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(named) => Some(named),
+            _ => None,
+        },
+        _ => None,
+    };
+    let named = named.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            format!(
+                "{} can only be derived for structs with named fields",
+                trait_name
+            ),
+        )
+    })?;
+
+    let fields = named
+        .named
+        .iter()
+        .map(|field| {
+            let ident = field.ident.as_ref().expect("named field");
+            let ty = &field.ty;
+            let name = LitStr::new(&ident.unraw().to_string(), ident.span());
+            // Spanned so that an unsupported field type is reported on the
+            // field rather than the derive.
+            Field {
+                ident,
+                sier_type: quote_spanned! {ty.span()=>
+                    <#ty as ::sier_codec::SierValue>::sier_type()
+                },
+                to_value: quote_spanned! {ty.span()=>
+                    <#ty as ::sier_codec::SierValue>::to_value(&self.#ident)
+                },
+                from_object: quote_spanned! {ty.span()=>
+                    ::sier_codec::__private::field::<#ty>(&obj, #name)?
+                },
+                name,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(body(input, &fields))
+}
+
+/// Checks that the schema has the struct's fields before touching any values.
+fn check_fields(fields: &[Field]) -> TokenStream2 {
+    let names = fields.iter().map(|f| &f.name);
+    let types = fields.iter().map(|f| &f.sier_type);
+    quote! {
+        ::sier_codec::__private::check_fields(def, &[#((#names, #types)),*])?;
+    }
+}
+
+fn encode(input: &DeriveInput, fields: &[Field]) -> TokenStream2 {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let check = check_fields(fields);
+    let values = fields.iter().map(|f| &f.to_value);
+
+    quote! {
+        impl #impl_generics ::sier_codec::SierEncode for #name #ty_generics #where_clause {
+            fn encode(
+                &self,
+                def: &::sier_codec::StructDef,
+            ) -> ::core::result::Result<::std::vec::Vec<u8>, ::sier_codec::Error> {
+                #check
+                let values = ::std::vec![#(#values),*];
+                ::core::result::Result::Ok(::sier_codec::Object::new(def, values).serialize())
+            }
+        }
+    }
+}
+
+fn decode(input: &DeriveInput, fields: &[Field]) -> TokenStream2 {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let check = check_fields(fields);
+    let idents = fields.iter().map(|f| f.ident);
+    let values = fields.iter().map(|f| &f.from_object);
+
+    quote! {
+        impl #impl_generics ::sier_codec::SierDecode for #name #ty_generics #where_clause {
+            fn decode(
+                def: &::sier_codec::StructDef,
+                bytes: &[u8],
+            ) -> ::core::result::Result<Self, ::sier_codec::Error> {
+                #check
+                let obj = def.decode(bytes)?;
+                ::core::result::Result::Ok(#name {
+                    #(#idents: #values),*
+                })
+            }
+        }
+    }
+}
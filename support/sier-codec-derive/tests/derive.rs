@@ -0,0 +1,6 @@
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/supported.rs");
+    t.compile_fail("tests/ui/unsupported_type.rs");
+}
@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+
+use sier_codec::{Parser, SierDecode, SierEncode};
+
+#[derive(SierEncode, SierDecode, Debug, PartialEq)]
+struct Reading {
+    sensor: String,
+    seq: u64,
+    r#match: u8,
+    samples: Vec<i32>,
+    digest: [u8; 4],
+    tags: BTreeMap<String, bool>,
+    note: Option<String>,
+}
+
+fn main() {
+    let parser = Parser::from_schema(
+        r#"
+        struct Reading {
+            sensor :string;
+            seq :u64;
+            match :u8;
+            samples :List<i32>;
+            digest :Array<u8, 4>;
+            tags :Map<string, bool>;
+            note :Optional<string>;
+        }
+        "#,
+    )
+    .unwrap();
+    let def = parser.get("Reading").unwrap();
+
+    let mut tags = BTreeMap::new();
+    tags.insert("calibrated".to_string(), true);
+    let reading = Reading {
+        sensor: "t0".to_string(),
+        seq: 42,
+        r#match: 3,
+        samples: vec![-1, 0, 1],
+        digest: [1, 2, 3, 4],
+        tags,
+        note: None,
+    };
+
+    let bytes = reading.encode(def).unwrap();
+    assert_eq!(bytes, parser.parse(&bytes).unwrap().serialize());
+    assert_eq!(Reading::decode(def, &bytes).unwrap(), reading);
+
+    let other = Parser::from_schema("struct Reading { sensor :string; }").unwrap();
+    assert!(reading.encode(other.get("Reading").unwrap()).is_err());
+}
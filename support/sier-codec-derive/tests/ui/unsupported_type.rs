@@ -0,0 +1,11 @@
+use std::collections::HashSet;
+
+use sier_codec::SierEncode;
+
+#[derive(SierEncode)]
+struct Reading {
+    seq: u64,
+    seen: HashSet<u64>,
+}
+
+fn main() {}
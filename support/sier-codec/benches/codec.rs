@@ -0,0 +1,107 @@
+//! Encode and decode throughput for a few representative schemas.
+//!
+//! Run with `cargo bench -p sier-codec`. Criterion reports time per iteration
+//! and bytes/sec, and compares against the previous run saved in
+//! `target/criterion`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use sier_codec::Parser;
+
+const SCHEMA: &str = r#"
+struct Fixed {
+    a :u64;
+    b :u64;
+    c :u32;
+    d :u8;
+    e :bool;
+}
+
+struct Strings {
+    a :string;
+    b :string;
+    c :string;
+    d :string;
+}
+
+struct Nested {
+    items :List<List<List<u32>>>;
+}
+"#;
+
+fn parser() -> Parser {
+    let mut parser = Parser::default();
+    parser.add_file_defs(SCHEMA).unwrap();
+    parser
+}
+
+fn bench_codec(c: &mut Criterion, name: &str, parser: &Parser, encoded: &[u8]) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+
+    let obj = parser.parse(encoded).unwrap();
+    group.bench_function("encode", |b| b.iter(|| black_box(&obj).serialize()));
+    group.bench_function("decode", |b| {
+        b.iter(|| parser.parse(black_box(encoded)).unwrap())
+    });
+
+    group.finish();
+}
+
+fn fixed_width(c: &mut Criterion) {
+    let parser = parser();
+    let encoded = parser
+        .struct_def("Fixed")
+        .unwrap()
+        .builder()
+        .set("a", u64::MAX)
+        .set("b", 42u64)
+        .set("c", 42u32)
+        .set("d", 42u8)
+        .set("e", true)
+        .try_build()
+        .unwrap()
+        .serialize();
+
+    bench_codec(c, "fixed_width", &parser, &encoded);
+}
+
+fn string_heavy(c: &mut Criterion) {
+    let parser = parser();
+    let encoded = parser
+        .struct_def("Strings")
+        .unwrap()
+        .builder()
+        .set("a", "a".repeat(16))
+        .set("b", "b".repeat(256))
+        .set("c", "c".repeat(4096))
+        .set("d", "⡌⠁⠧⠑".repeat(64))
+        .try_build()
+        .unwrap()
+        .serialize();
+
+    bench_codec(c, "string_heavy", &parser, &encoded);
+}
+
+fn nested_list(c: &mut Criterion) {
+    let parser = parser();
+    let items = (0..16)
+        .map(|i| {
+            (0..16)
+                .map(|j| (0..16).map(|k| i * j * k).collect())
+                .collect()
+        })
+        .collect::<Vec<Vec<Vec<u32>>>>();
+    let encoded = parser
+        .struct_def("Nested")
+        .unwrap()
+        .builder()
+        .set("items", items)
+        .try_build()
+        .unwrap()
+        .serialize();
+
+    bench_codec(c, "nested_list", &parser, &encoded);
+}
+
+criterion_group!(benches, fixed_width, string_heavy, nested_list);
+criterion_main!(benches);
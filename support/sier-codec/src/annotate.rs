@@ -0,0 +1,378 @@
+use core::ops::Range;
+
+use crate::{schema::var_int, DecodeOptions, Error, FieldDef, StructDef, Type, Value};
+
+/// Which bytes of a buffer belong to which field, from
+/// [`StructDef::annotate_bytes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteSpan {
+    /// Dotted path to the field, e.g. `header.sent_at` or `items[2]`. The
+    /// length prefix of a string or list is reported as `<path>.<len>`.
+    pub field_path: String,
+    pub byte_range: Range<usize>,
+    pub decoded_preview: String,
+}
+
+const TRUNCATED: &str = "<truncated>";
+
+pub(crate) fn annotate(def: &StructDef, bytes: &[u8]) -> Result<Vec<ByteSpan>, Error> {
+    let mut annotator = Annotator {
+        bytes,
+        offset: 0,
+        limit: bytes.len(),
+        enclosing: Vec::new(),
+        spans: Vec::new(),
+    };
+
+    match annotator.struct_(def, "") {
+        Ok(()) if annotator.offset < bytes.len() => {
+            annotator.push("<trailing>", bytes.len() - annotator.offset, String::new());
+        }
+        Ok(()) | Err(Stop::Truncated) => {}
+        Err(Stop::Invalid(e)) => return Err(e),
+    }
+    Ok(annotator.spans)
+}
+
+enum Stop {
+    /// Ran out of bytes; a span marking where has already been pushed.
+    Truncated,
+    Invalid(Error),
+}
+
+struct Annotator<'d, 'i> {
+    bytes: &'i [u8],
+    offset: usize,
+    limit: usize,
+    /// Structs currently being walked, for `Type::Recursive`.
+    enclosing: Vec<&'d StructDef>,
+    spans: Vec<ByteSpan>,
+}
+
+impl<'d, 'i> Annotator<'d, 'i> {
+    fn remaining(&self) -> &'i [u8] {
+        &self.bytes[self.offset..self.limit]
+    }
+
+    fn push(&mut self, path: &str, len: usize, decoded_preview: String) {
+        self.spans.push(ByteSpan {
+            field_path: path.to_string(),
+            byte_range: self.offset..self.offset + len,
+            decoded_preview,
+        });
+        self.offset += len;
+    }
+
+    fn truncated(&mut self, path: &str) -> Stop {
+        self.push(path, self.limit - self.offset, TRUNCATED.to_string());
+        Stop::Truncated
+    }
+
+    fn struct_(&mut self, def: &'d StructDef, path: &str) -> Result<(), Stop> {
+        self.enclosing.push(def);
+        for field in def.fields() {
+            let field_path = if path.is_empty() {
+                field.name().to_string()
+            } else {
+                format!("{}.{}", path, field.name())
+            };
+            if field.is_varint() {
+                self.varint(field, &field_path)?;
+            } else {
+                self.value(field.type_(), &field_path)?;
+            }
+        }
+        self.enclosing.pop();
+        Ok(())
+    }
+
+    fn value(&mut self, type_: &'d Type, path: &str) -> Result<(), Stop> {
+        match type_ {
+            Type::String => {
+                let len = self.length_prefix(path)?;
+                let payload = self.take(len, path)?;
+                let s = std::str::from_utf8(payload).map_err(|e| Stop::Invalid(e.into()))?;
+                self.push(path, len, format!("{:?}", s));
+            }
+            Type::Bytes => {
+                let len = self.length_prefix(path)?;
+                self.take(len, path)?;
+                self.push(path, len, format!("{} bytes", len));
+            }
+            Type::List(t) => {
+                let len = self.length_prefix(path)?;
+                if self.remaining().len() < len {
+                    return Err(self.truncated(path));
+                }
+
+                let outer_limit = self.limit;
+                self.limit = self.offset + len;
+                let mut index = 0;
+                while self.offset < self.limit {
+                    let start = self.offset;
+                    self.value(t, &format!("{}[{}]", path, index))?;
+                    self.check_progress(start)?;
+                    index += 1;
+                }
+                self.limit = outer_limit;
+            }
+            Type::Array(t, len) => {
+                for index in 0..*len {
+                    self.value(t, &format!("{}[{}]", path, index))?;
+                }
+            }
+            Type::Map(k, v) => {
+                let len = self.length_prefix(path)?;
+                if self.remaining().len() < len {
+                    return Err(self.truncated(path));
+                }
+
+                let outer_limit = self.limit;
+                self.limit = self.offset + len;
+                let mut index = 0;
+                while self.offset < self.limit {
+                    let start = self.offset;
+                    self.value(k, &format!("{}[{}].key", path, index))?;
+                    self.value(v, &format!("{}[{}].value", path, index))?;
+                    self.check_progress(start)?;
+                    index += 1;
+                }
+                self.limit = outer_limit;
+            }
+            Type::Optional(t) => {
+                let present = match self.remaining().first() {
+                    Some(&b) => b,
+                    None => return Err(self.truncated(path)),
+                };
+                self.push(
+                    &format!("{}.<present>", path),
+                    1,
+                    (present == 1).to_string(),
+                );
+                match present {
+                    0 => {}
+                    1 => self.value(t, path)?,
+                    _ => {
+                        return Err(Stop::Invalid(Error::ValueParsing(nom::Err::Error(
+                            nom::error::make_error(
+                                self.bytes[self.offset - 1..].to_vec(),
+                                nom::error::ErrorKind::IsNot,
+                            ),
+                        ))))
+                    }
+                }
+            }
+            Type::Struct(def) => self.struct_(def, path)?,
+            Type::Recursive(name) => {
+                let def = self
+                    .enclosing
+                    .iter()
+                    .rev()
+                    .find(|d| d.type_name() == name)
+                    .copied()
+                    .ok_or_else(|| {
+                        Stop::Invalid(Error::UnrecognizedType {
+                            name: name.clone(),
+                            span: None,
+                        })
+                    })?;
+                self.struct_(def, path)?
+            }
+            t => {
+                let width = match t {
+                    Type::Unit => 0,
+                    Type::Bool | Type::U8 | Type::I8 => 1,
+                    Type::U16 | Type::I16 => 2,
+                    Type::U32 | Type::I32 | Type::F32 | Type::Enum(_) => 4,
+                    Type::U64 | Type::I64 | Type::F64 => 8,
+                    Type::String
+                    | Type::Bytes
+                    | Type::List(_)
+                    | Type::Array(..)
+                    | Type::Map(..)
+                    | Type::Optional(_)
+                    | Type::Struct(_)
+                    | Type::Recursive(_) => {
+                        unreachable!("handled above")
+                    }
+                };
+                if self.remaining().len() < width {
+                    return Err(self.truncated(path));
+                }
+
+                let (_, value) = t
+                    .parse(self.remaining(), &DecodeOptions::default())
+                    .map_err(Stop::Invalid)?;
+                self.push(path, width, preview(&value));
+            }
+        }
+        Ok(())
+    }
+
+    /// A field with [`FieldAttributes::varint`](crate::FieldAttributes::varint).
+    fn varint(&mut self, field: &'d FieldDef, path: &str) -> Result<(), Stop> {
+        if !self.remaining().iter().any(|b| b & 0x80 == 0) {
+            return Err(self.truncated(path));
+        }
+        let remaining = self.remaining();
+        let (rest, value) = field
+            .parse_with(remaining, &DecodeOptions::default(), None)
+            .map_err(Stop::Invalid)?;
+        self.push(path, remaining.len() - rest.len(), preview(&value));
+        Ok(())
+    }
+
+    /// See `schema::check_progress`; `start` is where the item began.
+    fn check_progress(&self, start: usize) -> Result<(), Stop> {
+        if self.offset == start {
+            return Err(Stop::Invalid(Error::ValueParsing(nom::Err::Error(
+                nom::error::make_error(
+                    self.bytes[start..self.limit].to_vec(),
+                    nom::error::ErrorKind::Many0,
+                ),
+            ))));
+        }
+        Ok(())
+    }
+
+    fn length_prefix(&mut self, path: &str) -> Result<usize, Stop> {
+        let remaining = self.remaining();
+        match var_int(remaining) {
+            Ok((rest, len)) => {
+                self.push(
+                    &format!("{}.<len>", path),
+                    remaining.len() - rest.len(),
+                    len.to_string(),
+                );
+                Ok(len)
+            }
+            Err(nom::Err::Error(e)) if e.code != nom::error::ErrorKind::TooLarge => {
+                Err(self.truncated(path))
+            }
+            Err(e) => Err(Stop::Invalid(Error::from(e))),
+        }
+    }
+
+    fn take(&mut self, len: usize, path: &str) -> Result<&'i [u8], Stop> {
+        let remaining = self.remaining();
+        if remaining.len() < len {
+            return Err(self.truncated(path));
+        }
+        Ok(&remaining[..len])
+    }
+}
+
+fn preview(value: &Value) -> String {
+    match value {
+        Value::Unit => "()".to_string(),
+        Value::Bool(v) => v.to_string(),
+        Value::U8(v) => v.to_string(),
+        Value::U16(v) => v.to_string(),
+        Value::U32(v) => v.to_string(),
+        Value::U64(v) => v.to_string(),
+        Value::I8(v) => v.to_string(),
+        Value::I16(v) => v.to_string(),
+        Value::I32(v) => v.to_string(),
+        Value::I64(v) => v.to_string(),
+        Value::F32(v) => v.to_string(),
+        Value::F64(v) => v.to_string(),
+        Value::Enum(def, v) => match def.variant_with_value(*v) {
+            Some(variant) => format!("{}::{}", def.type_name(), variant.name),
+            None => v.to_string(),
+        },
+        v => format!("{:?}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ByteSpan, Error, Parser, StructDef, Type};
+
+    const SCHEMA: &'static str = r#"
+    struct Inner {
+        x :u32;
+    }
+
+    struct Foo {
+        a :u8;
+        s :string;
+        inner :Inner;
+        items :List<bool>;
+    }
+    "#;
+
+    fn span(field_path: &str, start: usize, end: usize, preview: &str) -> ByteSpan {
+        ByteSpan {
+            field_path: field_path.to_string(),
+            byte_range: start..end,
+            decoded_preview: preview.to_string(),
+        }
+    }
+
+    #[test]
+    fn spans_match_encoding() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(SCHEMA).unwrap();
+        let def = parser.struct_def("Foo").unwrap();
+
+        let bytes = [42, 3, 65, 66, 67, 7, 0, 0, 0, 2, 1, 0];
+        assert_eq!(
+            def.annotate_bytes(&bytes).unwrap(),
+            vec![
+                span("a", 0, 1, "42"),
+                span("s.<len>", 1, 2, "3"),
+                span("s", 2, 5, "\"ABC\""),
+                span("inner.x", 5, 9, "7"),
+                span("items.<len>", 9, 10, "2"),
+                span("items[0]", 10, 11, "true"),
+                span("items[1]", 11, 12, "false"),
+            ]
+        );
+    }
+
+    #[test]
+    fn marks_truncation() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(SCHEMA).unwrap();
+        let def = parser.struct_def("Foo").unwrap();
+
+        let bytes = [42, 3, 65];
+        assert_eq!(
+            def.annotate_bytes(&bytes).unwrap(),
+            vec![
+                span("a", 0, 1, "42"),
+                span("s.<len>", 1, 2, "3"),
+                span("s", 2, 3, "<truncated>"),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_width_items() {
+        let parser = Parser::from_schema(
+            "struct E {} struct Foo { a :List<()>; } struct Bar { a :List<E>; }",
+        )
+        .unwrap();
+        // Map keys always take a byte or more, so only by hand.
+        let map = StructDef {
+            type_name: "Baz".to_string(),
+            fields: vec![crate::FieldDef {
+                name: "a".to_string(),
+                type_: Type::Map(Box::new(Type::Unit), Box::new(Type::Unit)),
+                attributes: Default::default(),
+            }],
+        };
+
+        for def in [parser.get("Foo").unwrap(), parser.get("Bar").unwrap(), &map] {
+            assert!(
+                matches!(
+                    def.annotate_bytes(&[1, 0]),
+                    Err(Error::ValueParsing(nom::Err::Error(e)))
+                        if e.code == nom::error::ErrorKind::Many0
+                ),
+                "{}",
+                def
+            );
+        }
+    }
+}
@@ -0,0 +1,470 @@
+//! Compact binary form of struct definitions, for loading schemas without
+//! the text parser.
+//!
+//! A blob is `MAGIC`, a version byte, then a table of structs. Each struct
+//! only refers to structs earlier in the table, by index, so nested structs
+//! are stored once. Type tags match those used for struct IDs.
+
+use core::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+
+use crate::{
+    object::var_int as encode_var_int,
+    schema::{
+        ensure_shallow, ensure_unique_field_names, ensure_valid_array, var_int as decode_var_int,
+    },
+    EnumDef, EnumVariant, Error, FieldAttributes, FieldDef, StructDef, Type, MAX_TYPE_DEPTH,
+};
+
+pub(crate) const MAGIC: [u8; 4] = *b"SIER";
+pub(crate) const VERSION: u8 = 1;
+
+const IGNORE_IN_HASH: u8 = 0b001;
+const HAS_RANGE: u8 = 0b010;
+const HAS_MAX_LEN: u8 = 0b100;
+const VARINT: u8 = 0b1000;
+
+/// Encodes `defs` along with every struct they reference.
+pub(crate) fn encode<'a>(defs: impl IntoIterator<Item = &'a StructDef>) -> Vec<u8> {
+    let mut table = Vec::new();
+    for def in defs {
+        def.collect_dependencies(&mut table);
+    }
+
+    let mut result = Vec::from(MAGIC);
+    result.push(VERSION);
+    result.extend(encode_var_int(table.len()));
+    for def in &table {
+        encode_struct(def, &table, &mut result);
+    }
+    result
+}
+
+fn encode_struct(def: &StructDef, table: &[&StructDef], out: &mut Vec<u8>) {
+    encode_str(&def.type_name, out);
+    out.extend(encode_var_int(def.fields.len()));
+    for field in &def.fields {
+        encode_str(&field.name, out);
+        encode_type(&field.type_, table, out);
+        encode_attributes(&field.attributes, out);
+    }
+}
+
+fn encode_type(type_: &Type, table: &[&StructDef], out: &mut Vec<u8>) {
+    match type_ {
+        Type::U8 => out.push(0),
+        Type::U16 => out.push(12),
+        Type::U32 => out.push(1),
+        Type::U64 => out.push(2),
+        Type::I8 => out.push(8),
+        Type::I16 => out.push(9),
+        Type::I32 => out.push(10),
+        Type::I64 => out.push(11),
+        Type::F32 => out.push(13),
+        Type::F64 => out.push(14),
+        Type::String => out.push(3),
+        Type::Bytes => out.push(18),
+        Type::List(t) => {
+            out.push(4);
+            encode_type(t, table, out);
+        }
+        Type::Array(t, len) => {
+            out.push(20);
+            encode_type(t, table, out);
+            out.extend(encode_var_int(*len));
+        }
+        Type::Optional(t) => {
+            out.push(16);
+            encode_type(t, table, out);
+        }
+        Type::Recursive(name) => {
+            out.push(17);
+            encode_str(name, out);
+        }
+        Type::Map(k, v) => {
+            out.push(15);
+            encode_type(k, table, out);
+            encode_type(v, table, out);
+        }
+        Type::Unit => out.push(5),
+        Type::Struct(def) => {
+            let index = table
+                .iter()
+                .position(|d| d.id() == def.id())
+                .expect("dependencies are collected before encoding");
+            out.push(6);
+            out.extend(encode_var_int(index));
+        }
+        Type::Bool => out.push(7),
+        Type::Enum(def) => {
+            out.push(19);
+            encode_str(&def.type_name, out);
+            out.extend(encode_var_int(def.variants.len()));
+            for variant in &def.variants {
+                encode_str(&variant.name, out);
+                out.extend(encode_var_int(variant.value as usize));
+            }
+        }
+    }
+}
+
+fn encode_attributes(attributes: &FieldAttributes, out: &mut Vec<u8>) {
+    let mut flags = 0;
+    if attributes.ignore_in_hash {
+        flags |= IGNORE_IN_HASH;
+    }
+    if attributes.range.is_some() {
+        flags |= HAS_RANGE;
+    }
+    if attributes.max_len.is_some() {
+        flags |= HAS_MAX_LEN;
+    }
+    if attributes.varint {
+        flags |= VARINT;
+    }
+    out.push(flags);
+
+    if let Some((min, max)) = attributes.range {
+        out.extend(min.to_le_bytes());
+        out.extend(max.to_le_bytes());
+    }
+    if let Some(max) = attributes.max_len {
+        out.extend(encode_var_int(max));
+    }
+}
+
+fn encode_str(s: &str, out: &mut Vec<u8>) {
+    out.extend(encode_var_int(s.len()));
+    out.extend(s.bytes());
+}
+
+/// Decodes the struct table of a blob produced by [`encode`], in order.
+pub(crate) fn decode(bytes: &[u8]) -> Result<Vec<Arc<StructDef>>, Error> {
+    let mut reader = Reader { bytes };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(bad("missing magic number"));
+    }
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(bad(format!("unsupported version {}", version)));
+    }
+
+    let count = reader.var_int()?;
+    let mut table = Vec::new();
+    for _ in 0..count {
+        let def = reader.struct_def(&table)?;
+        table.push(Arc::new(def));
+    }
+
+    if !reader.bytes.is_empty() {
+        return Err(bad("trailing bytes"));
+    }
+    Ok(table)
+}
+
+fn bad(reason: impl Into<String>) -> Error {
+    Error::BadSchemaBlob(reason.into())
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8], Error> {
+        if self.bytes.len() < n {
+            return Err(bad("truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn var_int(&mut self) -> Result<usize, Error> {
+        let (rest, n) = decode_var_int(self.bytes).map_err(|_| bad("invalid varint"))?;
+        self.bytes = rest;
+        Ok(n)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.var_int()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| bad("invalid UTF-8 in name"))
+    }
+
+    fn struct_def(&mut self, table: &[Arc<StructDef>]) -> Result<StructDef, Error> {
+        let type_name = self.string()?;
+        let field_count = self.var_int()?;
+
+        let mut fields = Vec::new();
+        for _ in 0..field_count {
+            let name = self.string()?;
+            let type_ = self.type_(table, 0)?;
+            ensure_shallow(&type_)?;
+            let attributes = self.attributes()?;
+            if attributes.varint && !type_.has_varint_form() {
+                return Err(bad("@varint on a type without a varint form"));
+            }
+            fields.push(FieldDef {
+                name,
+                type_,
+                attributes,
+            });
+        }
+        ensure_unique_field_names(fields.iter().map(|f| f.name.as_str()))?;
+
+        Ok(StructDef { type_name, fields })
+    }
+
+    fn type_(&mut self, table: &[Arc<StructDef>], depth: usize) -> Result<Type, Error> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(bad("type nested too deeply"));
+        }
+
+        Ok(match self.byte()? {
+            0 => Type::U8,
+            12 => Type::U16,
+            1 => Type::U32,
+            2 => Type::U64,
+            8 => Type::I8,
+            9 => Type::I16,
+            10 => Type::I32,
+            11 => Type::I64,
+            13 => Type::F32,
+            14 => Type::F64,
+            3 => Type::String,
+            18 => Type::Bytes,
+            4 => Type::List(Box::new(self.type_(table, depth + 1)?)),
+            20 => {
+                let item = self.type_(table, depth + 1)?;
+                let len = self.var_int()?;
+                ensure_valid_array(&item, len)?;
+                Type::Array(Box::new(item), len)
+            }
+            16 => Type::Optional(Box::new(self.type_(table, depth + 1)?)),
+            17 => Type::Recursive(self.string()?),
+            15 => {
+                let key = self.type_(table, depth + 1)?;
+                if !key.is_map_key() {
+                    return Err(bad(format!("{} is not a valid map key", key)));
+                }
+                let value = self.type_(table, depth + 1)?;
+                Type::Map(Box::new(key), Box::new(value))
+            }
+            5 => Type::Unit,
+            6 => {
+                let index = self.var_int()?;
+                let def = table
+                    .get(index)
+                    .ok_or_else(|| bad(format!("struct index {} out of bounds", index)))?;
+                Type::Struct(Arc::clone(def))
+            }
+            7 => Type::Bool,
+            19 => Type::Enum(Arc::new(self.enum_def()?)),
+            tag => return Err(bad(format!("unknown type tag {}", tag))),
+        })
+    }
+
+    fn enum_def(&mut self) -> Result<EnumDef, Error> {
+        let type_name = self.string()?;
+        let count = self.var_int()?;
+
+        let mut variants = Vec::<EnumVariant>::new();
+        for _ in 0..count {
+            let name = self.string()?;
+            let value = u32::try_from(self.var_int()?).map_err(|_| bad("enum value too large"))?;
+            if variants.iter().any(|v| v.name == name) {
+                return Err(bad(format!("duplicate enum variant {}", name)));
+            }
+            variants.push(EnumVariant { name, value });
+        }
+        Ok(EnumDef {
+            type_name,
+            variants,
+        })
+    }
+
+    fn attributes(&mut self) -> Result<FieldAttributes, Error> {
+        let flags = self.byte()?;
+        if flags & !(IGNORE_IN_HASH | HAS_RANGE | HAS_MAX_LEN | VARINT) != 0 {
+            return Err(bad("unknown attribute flags"));
+        }
+
+        let mut attributes = FieldAttributes {
+            ignore_in_hash: flags & IGNORE_IN_HASH != 0,
+            varint: flags & VARINT != 0,
+            ..Default::default()
+        };
+        if flags & HAS_RANGE != 0 {
+            attributes.range = Some((self.i128()?, self.i128()?));
+        }
+        if flags & HAS_MAX_LEN != 0 {
+            attributes.max_len = Some(self.var_int()?);
+        }
+        Ok(attributes)
+    }
+
+    fn i128(&mut self) -> Result<i128, Error> {
+        let bytes = self.take(16)?;
+        Ok(i128::from_le_bytes(
+            bytes.try_into().expect("took exactly 16 bytes"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Parser;
+
+    const NESTED: &'static str = r#"
+    struct Inner {
+        flag :bool;
+        age :u8 @range(0, 150);
+    }
+
+    struct Outer {
+        inner :Inner;
+        inners :List<List<Inner>>;
+        name :string @maxlen(64) @ignore_in_hash;
+        big :u64 @varint;
+        small :u32;
+        lookup :Map<i16, Inner>;
+        status :Optional<Status>;
+        digest :Array<u8, 4>;
+    }
+
+    enum Status { Active; Closed; }
+    "#;
+
+    fn outer() -> Arc<StructDef> {
+        let mut parser = Parser::default();
+        parser.add_file_defs(NESTED).unwrap();
+        Arc::clone(parser.struct_def("Outer").unwrap())
+    }
+
+    #[test]
+    fn round_trips_nested() {
+        let outer = outer();
+
+        let blob = outer.to_binary();
+        assert_eq!(StructDef::from_binary(&blob).unwrap(), *outer);
+    }
+
+    #[test]
+    fn stores_nested_structs_once() {
+        let blob = outer().to_binary();
+        let table = decode(&blob).unwrap();
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[0].type_name(), "Inner");
+    }
+
+    #[test]
+    fn round_trips_parser() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(NESTED).unwrap();
+
+        let loaded = Parser::from_binary(&parser.to_binary()).unwrap();
+        assert_eq!(loaded.struct_def("Inner"), parser.struct_def("Inner"));
+        assert_eq!(loaded.struct_def("Outer"), parser.struct_def("Outer"));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut blob = outer().to_binary();
+        blob[0] = b'X';
+
+        assert_eq!(
+            StructDef::from_binary(&blob),
+            Err(Error::BadSchemaBlob("missing magic number".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut blob = outer().to_binary();
+        blob[4] = VERSION + 1;
+
+        assert_eq!(
+            StructDef::from_binary(&blob),
+            Err(Error::BadSchemaBlob("unsupported version 2".to_string()))
+        );
+    }
+
+    /// Unchecked, as only a hand-built definition can have one.
+    fn with_array(item: Type, len: usize) -> StructDef {
+        StructDef {
+            type_name: "Foo".to_string(),
+            fields: vec![FieldDef {
+                name: "a".to_string(),
+                type_: Type::Array(Box::new(item), len),
+                attributes: Default::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn rejects_bad_arrays() {
+        assert_eq!(
+            StructDef::from_binary(&with_array(Type::U8, usize::MAX).to_binary()),
+            Err(Error::ArrayTooLong {
+                len: usize::MAX,
+                max: crate::MAX_ARRAY_LEN,
+                span: None,
+            })
+        );
+        assert_eq!(
+            StructDef::from_binary(&with_array(Type::Unit, 2).to_binary()),
+            Err(Error::ZeroWidthArrayItem {
+                item: "()".to_string(),
+                span: None,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_deep_struct_chains() {
+        // Each struct only refers to the one before it, but they add up.
+        let chain = |len| {
+            let mut blob = Vec::from(MAGIC);
+            blob.push(VERSION);
+            blob.extend(encode_var_int(len));
+            encode_str("S0", &mut blob);
+            blob.push(0);
+            for i in 1..len {
+                encode_str(&format!("S{}", i), &mut blob);
+                blob.push(1);
+                encode_str("a", &mut blob);
+                blob.push(6);
+                blob.extend(encode_var_int(i - 1));
+                blob.push(0);
+            }
+            blob
+        };
+
+        assert!(Parser::from_binary(&chain(MAX_TYPE_DEPTH + 1)).is_ok());
+        assert_eq!(
+            Parser::from_binary(&chain(MAX_TYPE_DEPTH + 2)).err(),
+            Some(Error::TypeTooDeep {
+                max: MAX_TYPE_DEPTH,
+                span: None
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let blob = outer().to_binary();
+
+        assert!(matches!(
+            StructDef::from_binary(&blob[..blob.len() - 1]),
+            Err(Error::BadSchemaBlob(_))
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{FieldDef, Object, StructDef, Type, Value};
+use crate::{AssignError, FieldDef, Object, StructDef, Type, Value};
 
 use std::collections::HashMap;
 
@@ -34,36 +34,106 @@ impl<'s> Builder<'s> {
         })
     }
 
+    /// Optional fields that were never set are absent.
     fn value_for_field(&mut self, field: &FieldDef) -> Result<Value<'s>, BuildError> {
-        let value = self
-            .field_values
-            .remove(&field.name)
-            .ok_or_else(|| BuildError::MissingField(self.field_name(field)))?;
-
-        if let Err((expected, got)) = value.assignable(&field.type_) {
-            return Err(BuildError::IncorrectType {
-                field: self.field_name(field),
-                expected,
-                got,
-            });
-        }
+        let value = match self.field_values.remove(&field.name) {
+            Some(value) => value,
+            None => return unset_value(self.struct_def, field),
+        };
 
+        check_type(self.struct_def, field, &value)?;
         Ok(value)
     }
+}
+
+/// Like [`Builder`], but each field is checked as it is set rather than when
+/// building. From [`StructDef::new_value`].
+pub struct ValueBuilder<'s> {
+    struct_def: &'s StructDef,
+    values: Vec<Option<Value<'s>>>,
+}
+
+impl<'s> ValueBuilder<'s> {
+    pub(crate) fn new(struct_def: &'s StructDef) -> Self {
+        ValueBuilder {
+            struct_def,
+            values: struct_def.fields.iter().map(|_| None).collect(),
+        }
+    }
 
-    fn field_name(&self, field: &FieldDef) -> String {
-        format!("{}.{}", self.struct_def.type_name, field.name)
+    /// Fails if `field_name` isn't a field of the struct or `value` doesn't
+    /// match its type, leaving the field as it was.
+    pub fn set(
+        &mut self,
+        field_name: &str,
+        value: impl Into<Value<'s>>,
+    ) -> Result<&mut Self, BuildError> {
+        let index = self.struct_def.field_index(field_name).ok_or_else(|| {
+            BuildError::UnknownField(format!("{}.{}", self.struct_def.type_name, field_name))
+        })?;
+
+        let value = value.into();
+        check_type(self.struct_def, &self.struct_def.fields[index], &value)?;
+        self.values[index] = Some(value);
+        Ok(self)
+    }
+
+    /// Fails if a field that isn't `Optional` was never set.
+    pub fn finish(self) -> Result<Object<'s>, BuildError> {
+        let struct_def = self.struct_def;
+        let values = struct_def
+            .fields
+            .iter()
+            .zip(self.values)
+            .map(|(field, value)| match value {
+                Some(value) => Ok(value),
+                None => unset_value(struct_def, field),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Object {
+            schema: struct_def,
+            values,
+        })
+    }
+
+    /// [`finish`](Self::finish), then serialize.
+    pub fn encode(self) -> Result<Vec<u8>, BuildError> {
+        Ok(self.finish()?.serialize())
     }
 }
 
+fn unset_value<'s>(struct_def: &StructDef, field: &FieldDef) -> Result<Value<'s>, BuildError> {
+    match field.type_ {
+        Type::Optional(_) => Ok(Value::Optional(None)),
+        _ => Err(BuildError::MissingField(field_name(struct_def, field))),
+    }
+}
+
+fn check_type(struct_def: &StructDef, field: &FieldDef, value: &Value) -> Result<(), BuildError> {
+    value.assignable(&field.type_).map_err(|e| match e {
+        AssignError::IncorrectType { expected, got } => BuildError::IncorrectType {
+            field: field_name(struct_def, field),
+            expected,
+            got,
+        },
+        AssignError::DuplicateKey => BuildError::DuplicateMapKey(field_name(struct_def, field)),
+    })
+}
+
+fn field_name(struct_def: &StructDef, field: &FieldDef) -> String {
+    format!("{}.{}", struct_def.type_name, field.name)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum BuildError {
     MissingField(String),
+    UnknownField(String),
     IncorrectType {
         field: String,
         expected: Type,
         got: Type<String>,
     },
+    DuplicateMapKey(String),
 }
 
 #[cfg(test)]
@@ -89,6 +159,7 @@ mod tests {
             fields: vec![FieldDef {
                 name: "bar".to_string(),
                 type_: Type::U8,
+                attributes: Default::default(),
             }],
         };
 
@@ -107,6 +178,7 @@ mod tests {
             fields: vec![FieldDef {
                 name: "bar".to_string(),
                 type_: Type::U8,
+                attributes: Default::default(),
             }],
         };
 
@@ -125,6 +197,7 @@ mod tests {
             fields: vec![FieldDef {
                 name: "bar".to_string(),
                 type_: Type::U8,
+                attributes: Default::default(),
             }],
         };
 
@@ -147,6 +220,7 @@ mod tests {
             fields: vec![FieldDef {
                 name: "bar".to_string(),
                 type_: Type::U8,
+                attributes: Default::default(),
             }],
         };
 
@@ -165,6 +239,7 @@ mod tests {
             fields: vec![FieldDef {
                 name: "bar".to_string(),
                 type_: Type::List(Box::new(Type::U8)),
+                attributes: Default::default(),
             }],
         };
 
@@ -175,4 +250,89 @@ mod tests {
 
         assert_eq!(obj["bar"].as_list(), Some(&[Value::U8(42)][..]));
     }
+
+    fn reading() -> StructDef {
+        StructDef {
+            type_name: "Reading".to_string(),
+            fields: vec![
+                FieldDef {
+                    name: "sensor".to_string(),
+                    type_: Type::U8,
+                    attributes: Default::default(),
+                },
+                FieldDef {
+                    name: "note".to_string(),
+                    type_: Type::Optional(Box::new(Type::String)),
+                    attributes: Default::default(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn new_value_encodes() {
+        let def = reading();
+        let mut value = def.new_value();
+        value.set("sensor", 7u8).unwrap();
+
+        let encoded = value.encode().unwrap();
+        assert_eq!(&encoded[..8], &def.id());
+        assert_eq!(&encoded[8..], &[7, 0]);
+    }
+
+    #[test]
+    fn new_value_missing_field() {
+        let def = reading();
+        let mut value = def.new_value();
+        value.set("note", Some("late".to_string())).unwrap();
+
+        assert_eq!(
+            value.encode().err(),
+            Some(BuildError::MissingField("Reading.sensor".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_map_keys() {
+        let def = StructDef {
+            type_name: "Tally".to_string(),
+            fields: vec![FieldDef {
+                name: "counts".to_string(),
+                type_: Type::Map(Box::new(Type::String), Box::new(Type::U32)),
+                attributes: Default::default(),
+            }],
+        };
+        let counts = || {
+            Value::Map(vec![
+                (Value::String("a".to_string()), Value::U32(1)),
+                (Value::String("a".to_string()), Value::U32(2)),
+            ])
+        };
+        let error = || Some(BuildError::DuplicateMapKey("Tally.counts".to_string()));
+
+        let obj = def.builder().set("counts", counts()).try_build();
+        assert_eq!(obj.err(), error());
+
+        let mut value = def.new_value();
+        assert_eq!(value.set("counts", counts()).err(), error());
+    }
+
+    #[test]
+    fn new_value_type_mismatch() {
+        let def = reading();
+        let mut value = def.new_value();
+
+        assert_eq!(
+            value.set("sensor", 7u32).err(),
+            Some(BuildError::IncorrectType {
+                field: "Reading.sensor".to_string(),
+                expected: Type::U8,
+                got: Type::U32,
+            })
+        );
+        assert_eq!(
+            value.set("sensr", 7u8).err(),
+            Some(BuildError::UnknownField("Reading.sensr".to_string()))
+        );
+    }
 }
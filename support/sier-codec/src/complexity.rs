@@ -0,0 +1,164 @@
+/// Rough size of a schema source, from `Parser::estimate_complexity`.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct ComplexityEstimate {
+    pub structs: usize,
+    pub fields: usize,
+    /// Deepest nesting of generic brackets, e.g. 2 for `List<List<u8>>`.
+    pub max_generic_depth: usize,
+}
+
+/// Single pass over the characters of `input` without building any parse
+/// tree, so it takes no more stack however deeply the input nests. Fields
+/// are counted as `;`s inside struct braces, so the result is only as
+/// accurate as the input is well-formed. Inline structs count as structs,
+/// enum variants don't count at all; comments and string literals are
+/// skipped.
+pub(crate) fn estimate(input: &str) -> ComplexityEstimate {
+    let mut estimate = ComplexityEstimate::default();
+    let mut brace_depth = 0usize;
+    let mut generic_depth = 0usize;
+    let mut in_enum = false;
+    // The last keyword whose body hasn't been reached yet.
+    let mut pending = None;
+    let mut word = String::new();
+
+    // A trailing space ends a word at the very end of the input.
+    let mut chars = input.chars().chain(core::iter::once(' ')).peekable();
+    while let Some(mut c) = chars.next() {
+        if c == '/' && chars.peek() == Some(&'/') {
+            chars.find(|&c| c == '\n');
+            c = '\n';
+        } else if c == '/' && chars.peek() == Some(&'*') {
+            chars.next();
+            let mut previous = ' ';
+            chars.find(|&c| std::mem::replace(&mut previous, c) == '*' && c == '/');
+            c = ' ';
+        } else if c == '"' {
+            chars.find(|&c| c == '"' || c == '\n');
+            c = ' ';
+        }
+
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        match word.as_str() {
+            "struct" => {
+                estimate.structs += 1;
+                pending = Some("struct");
+            }
+            "enum" => pending = Some("enum"),
+            _ => {}
+        }
+        word.clear();
+
+        match c {
+            '}' if in_enum => in_enum = false,
+            _ if in_enum => {}
+            '{' => match pending.take() {
+                Some("enum") => in_enum = true,
+                keyword => {
+                    // Not the body of a `struct`, so an inline struct.
+                    if keyword.is_none() {
+                        estimate.structs += 1;
+                    }
+                    brace_depth += 1;
+                }
+            },
+            '}' => brace_depth = brace_depth.saturating_sub(1),
+            ';' if brace_depth > 0 => estimate.fields += 1,
+            '<' => {
+                generic_depth += 1;
+                estimate.max_generic_depth = estimate.max_generic_depth.max(generic_depth);
+            }
+            '>' => generic_depth = generic_depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    estimate
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Parser;
+
+    const SCHEMA: &'static str = r#"
+    // struct Commented { out :u8; }
+    struct Corge {
+        /* <<not; generic>> */
+        gz :u64;
+        op :bool;
+    }
+
+    struct Foo {
+        bar :u64;
+        baz :string;
+        qux :List<List<u64>>;
+        corge :Corge;
+    }
+
+    struct Empty {}
+    "#;
+
+    #[test]
+    fn matches_parsed_counts() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(SCHEMA).unwrap();
+        let parsed_fields = ["Corge", "Foo", "Empty"]
+            .iter()
+            .map(|name| parser.struct_def(name).unwrap().fields().len())
+            .sum::<usize>();
+
+        let estimate = Parser::estimate_complexity(SCHEMA);
+        assert_eq!(estimate.structs, 3);
+        assert_eq!(estimate.fields, parsed_fields);
+        assert_eq!(estimate.max_generic_depth, 2);
+    }
+
+    #[test]
+    fn enums_and_inline_structs() {
+        let source = r#"
+        enum Kind { A; B; C = 7; }
+        type Point = { x :i32; y :List<{ z :u8; }>; };
+        struct Foo { kind :Kind; home :{ zip :u32; }; at :Point; }"#;
+        let parser = Parser::from_schema(source).unwrap();
+        let structs = ["Foo", "Foo_home", "Point", "Point_y"];
+        let parsed_fields = structs
+            .iter()
+            .map(|name| parser.struct_def(name).unwrap().fields().len())
+            .sum::<usize>();
+
+        let estimate = Parser::estimate_complexity(source);
+        assert_eq!(estimate.structs, structs.len());
+        assert_eq!(estimate.fields, parsed_fields);
+        assert_eq!(estimate.max_generic_depth, 1);
+    }
+
+    #[test]
+    fn keywords_at_the_edges() {
+        let estimate = Parser::estimate_complexity("struct Foo { a :u8; }");
+        assert_eq!(estimate.structs, 1);
+        assert_eq!(estimate.fields, 1);
+
+        let estimate = Parser::estimate_complexity("struct Foo { a :u8; } struct");
+        assert_eq!(estimate.structs, 2);
+        assert_eq!(estimate.fields, 1);
+
+        let estimate = Parser::estimate_complexity(r#"include "struct.sier"; struct structs {}"#);
+        assert_eq!(estimate.structs, 1);
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let depth = 100_000;
+        let source = format!(
+            "struct Foo {{ a :{}u8{}; }}",
+            "List<".repeat(depth),
+            ">".repeat(depth)
+        );
+        let estimate = Parser::estimate_complexity(&source);
+        assert_eq!(estimate.max_generic_depth, depth);
+        assert_eq!(estimate.fields, 1);
+    }
+}
@@ -1,158 +1,1538 @@
-use crate::{
-    schema::{FieldDef, StructDef, Type},
-    Error, Parser,
+use core::cell::{Cell, RefCell};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
 };
 
-use std::collections::HashSet;
+use crate::{
+    schema::{
+        ensure_shallow, ensure_valid_array, ensure_valid_name, ensure_valid_type_name, EnumDef,
+        EnumVariant, FieldAttributes, FieldDef, StructDef, Type,
+    },
+    Error, Parser, Span, MAX_TYPE_DEPTH,
+};
 
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{alphanumeric1, multispace0, multispace1},
-    combinator::opt,
-    multi::many0,
+    bytes::complete::{tag, take_until, take_while, take_while1},
+    character::complete::{digit1, multispace0, multispace1},
+    combinator::{all_consuming, cut, map_res, opt, recognize, value},
+    multi::{many0, many1, separated_list0, separated_list1},
+    sequence::{pair, preceded, terminated, tuple},
     IResult,
 };
 
+/// Where a token is: how much input was left where it starts, and its
+/// length. The parsers only ever see the rest of the input, so it takes the
+/// whole source to turn this into a `Span`, see `Resolver::span`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Location {
+    remaining: usize,
+    len: usize,
+}
+
+impl Location {
+    /// The token that parsing `before` consumed, leaving `after`.
+    fn between(before: &str, after: &str) -> Location {
+        Location {
+            remaining: before.len(),
+            len: before.len() - after.len(),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct ParsedStruct<'i> {
     type_name: &'i str,
-    fields: Vec<ParsedField<'i>>,
+    location: Location,
+    includes: Vec<(&'i str, Location)>,
+    fields: Vec<ParsedField>,
+}
+
+/// Owned, like `TypeDef`, since inline structs keep their fields there.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct ParsedField {
+    name: String,
+    /// Of the name.
+    location: Location,
+    type_: TypeDef,
+    attributes: Vec<ParsedAttribute>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct ParsedAttribute {
+    name: String,
+    args: Vec<String>,
+    /// Of the whole attribute, `@` and arguments included.
+    location: Location,
+}
+
+#[derive(Debug)]
+struct ParsedEnum<'i> {
+    type_name: &'i str,
+    location: Location,
+    variants: Vec<ParsedVariant<'i>>,
+}
+
+#[derive(Debug)]
+struct ParsedVariant<'i> {
+    name: &'i str,
+    /// Of the name.
+    location: Location,
+    value: Option<&'i str>,
 }
 
 #[derive(Debug)]
-struct ParsedField<'i> {
+struct ParsedAlias<'i> {
     name: &'i str,
-    type_: TypeDef<'i>,
+    location: Location,
+    type_: TypeDef,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum TypeDef<'i> {
+/// An unresolved type as written in a definition. Owned so that aliases can be
+/// stored on the `Parser` and expanded after the input is gone.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TypeDef {
     Primitive(Type),
-    Generic(&'i str, Box<TypeDef<'i>>),
-    Struct(&'i str),
+    Generic(String, Vec<TypeDef>, Location),
+    Struct(String, Location),
+    /// A number among a generic's arguments, only valid as the length of an
+    /// `Array`.
+    Length(usize, Location),
+    /// An anonymous struct written in place, `{ street :string; }`. The name
+    /// is generated from where it appears, see `name_inline_structs`.
+    Inline(String, Vec<ParsedField>, Location),
+}
+
+impl core::fmt::Display for TypeDef {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TypeDef::Primitive(t) => write!(f, "{}", t),
+            TypeDef::Generic(name, args, _) => {
+                write!(f, "{}<", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ">")
+            }
+            TypeDef::Struct(name, _) => write!(f, "{}", name),
+            TypeDef::Length(len, _) => write!(f, "{}", len),
+            TypeDef::Inline(_, fields, _) => {
+                write!(f, "{{ ")?;
+                for field in fields {
+                    write!(f, "{} :{}", field.name, field.type_)?;
+                    for attribute in &field.attributes {
+                        write!(f, " @{}", attribute.name)?;
+                        if !attribute.args.is_empty() {
+                            write!(f, "({})", attribute.args.join(", "))?;
+                        }
+                    }
+                    write!(f, "; ")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Definition {
+    Struct(Arc<StructDef>),
+    Enum(Arc<EnumDef>),
+    Alias(String, TypeDef),
+}
+
+/// A definition as written, before any of its types are resolved.
+#[derive(Debug)]
+enum ParsedDefinition<'i> {
+    Struct(ParsedStruct<'i>),
+    Enum(ParsedEnum<'i>),
+    Alias(ParsedAlias<'i>),
+}
+
+// Aliases are expanded lazily, so a cycle like `type A = B; type B = A;` is
+// only noticed when something uses it.
+const MAX_ALIAS_DEPTH: usize = 32;
+
+/// What names refer to while a file is compiled: the file's own structs and
+/// aliases, whatever order they appear in, then those already in `parser`.
+struct Resolver<'r, 'i> {
+    parser: &'r Parser,
+    structs: HashMap<&'i str, &'r ParsedStruct<'i>>,
+    enums: HashMap<&'i str, Arc<EnumDef>>,
+    aliases: HashMap<&'i str, &'r TypeDef>,
+    /// Structs from the file compiled so far, except those whose compiled
+    /// form depends on where they were reached from (see `Frame`).
+    compiled: RefCell<HashMap<&'i str, Arc<StructDef>>>,
+    /// Inline structs reached since the last `take_inline`, by generated
+    /// name.
+    inline: RefCell<Vec<(String, Vec<ParsedField>, Location)>>,
+    /// Of the source the definitions came from, if any, for `span`.
+    source_len: Option<usize>,
+}
+
+/// A struct being compiled. Structs from the file are compiled when first
+/// referred to, so frames chain back through the structs whose fields led
+/// here.
+#[derive(Debug)]
+struct Frame<'a> {
+    name: &'a str,
+    parent: Option<&'a Frame<'a>>,
+    /// How many frames `parent` chains back through.
+    depth: usize,
+    /// Whether the field that led here from `parent` was under a `List`,
+    /// `Map` or `Optional`.
+    reached_indirectly: bool,
+    /// Set when something inside refers back to a struct enclosing this one.
+    /// The resulting `Type::Recursive` only resolves within that struct, so
+    /// the compiled form is not reused elsewhere.
+    depends_on_enclosing: Cell<bool>,
+}
+
+/// Where a type is being resolved: the innermost struct being compiled, if
+/// any, and whether the type sits under a `List`, `Map` or `Optional` within
+/// it, which is what makes a reference back to an enclosing struct finite.
+#[derive(Debug, Clone, Copy, Default)]
+struct Scope<'a> {
+    frame: Option<&'a Frame<'a>>,
+    indirect: bool,
+}
+
+impl<'r, 'i> Resolver<'r, 'i> {
+    /// Enums refer to nothing else, so they are compiled straight away.
+    fn new(
+        parser: &'r Parser,
+        defs: &'r [ParsedDefinition<'i>],
+        source: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut resolver = Resolver {
+            parser,
+            structs: HashMap::new(),
+            enums: HashMap::new(),
+            aliases: HashMap::new(),
+            compiled: RefCell::default(),
+            inline: RefCell::default(),
+            source_len: source.map(str::len),
+        };
+        for def in defs {
+            match def {
+                ParsedDefinition::Struct(s) => {
+                    resolver.ensure_type_name_free(s.type_name, s.location)?;
+                    resolver.structs.insert(s.type_name, s);
+                }
+                ParsedDefinition::Enum(e) => {
+                    resolver.ensure_type_name_free(e.type_name, e.location)?;
+                    let compiled = Arc::new(e.compile(&resolver)?);
+                    resolver.enums.insert(e.type_name, compiled);
+                }
+                ParsedDefinition::Alias(a) => {
+                    let span = resolver.span(a.location);
+                    if is_builtin_type(a.name) {
+                        return Err(Error::ShadowsBuiltinType {
+                            name: a.name.to_string(),
+                            span,
+                        });
+                    }
+                    ensure_valid_type_name(a.name).map_err(|e| e.or_at(span))?;
+                    let redefined = parser.alias(a.name).is_some();
+                    if resolver.aliases.insert(a.name, &a.type_).is_some() || redefined {
+                        return Err(Error::DuplicateAlias {
+                            name: a.name.to_string(),
+                            span,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(resolver)
+    }
+
+    /// Like `Parser::ensure_type_name_free`, also counting the file's own
+    /// structs and enums before `location`, which it points at.
+    fn ensure_type_name_free(&self, name: &str, location: Location) -> Result<(), Error> {
+        let span = self.span(location);
+        if self.structs.contains_key(name) || self.parser.struct_def(name).is_some() {
+            return Err(Error::DuplicateStruct {
+                name: name.to_string(),
+                span,
+            });
+        }
+        if self.enums.contains_key(name) || self.parser.enum_def(name).is_some() {
+            return Err(Error::DuplicateEnum {
+                name: name.to_string(),
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    /// `None` without a source, or for a location in another one, such as
+    /// that of an alias from an earlier file.
+    fn span(&self, location: Location) -> Option<Span> {
+        let start = self.source_len?.checked_sub(location.remaining)?;
+        Some(Span {
+            start,
+            end: start + location.len,
+        })
+    }
+
+    /// Compiles the inline structs reached since the last call as though
+    /// they had been written out under their generated names, so that they
+    /// can be registered like any other struct. `taken` holds those returned
+    /// before; each name is only returned once, and fails with
+    /// `DuplicateStruct`, pointing at the inline struct, if it names another
+    /// struct.
+    fn take_inline(
+        &self,
+        taken: &mut HashMap<String, Vec<ParsedField>>,
+    ) -> Result<Vec<Arc<StructDef>>, Error> {
+        let mut defs = Vec::new();
+        loop {
+            let pending = core::mem::take(&mut *self.inline.borrow_mut());
+            if pending.is_empty() {
+                return Ok(defs);
+            }
+            for (name, fields, location) in pending {
+                let span = self.span(location);
+                let duplicate = || Error::DuplicateStruct {
+                    name: name.clone(),
+                    span,
+                };
+                match taken.get(&name) {
+                    Some(seen) if *seen == fields => continue,
+                    Some(_) => return Err(duplicate()),
+                    None if self.structs.contains_key(name.as_str()) => return Err(duplicate()),
+                    None => {}
+                }
+                let (def, _) = compile_struct(&name, span, &[], &fields, self, Scope::default())?;
+                match self.parser.struct_def(def.type_name()) {
+                    // Registered by an earlier file using the same alias.
+                    Some(registered) if registered.id() == def.id() => {}
+                    Some(_) => return Err(duplicate()),
+                    None => defs.push(def),
+                }
+                taken.insert(name, fields);
+            }
+        }
+    }
+
+    fn alias(&self, name: &str) -> Option<&'r TypeDef> {
+        self.aliases
+            .get(name)
+            .copied()
+            .or_else(|| self.parser.alias(name))
+    }
+
+    fn compiled(&self, name: &str) -> Option<Arc<StructDef>> {
+        self.compiled.borrow().get(name).cloned()
+    }
+
+    /// The struct or enum `name` refers to from `scope`, compiling it first if
+    /// it is a struct defined in the file. Errors about the reference itself
+    /// are left without a span for the caller to fill in.
+    fn struct_type(&self, name: &str, scope: Scope) -> Result<Type, Error> {
+        let mut inner_frames = Vec::new();
+        let mut indirect = scope.indirect;
+        let mut frame = scope.frame;
+        while let Some(f) = frame {
+            if f.name == name {
+                if !indirect {
+                    let path = core::iter::once(name)
+                        .chain(inner_frames.iter().rev().map(|f: &&Frame| f.name))
+                        .chain(core::iter::once(name))
+                        .collect::<Vec<_>>();
+                    return Err(Error::RecursiveType {
+                        path: path.join(" -> "),
+                        span: None,
+                    });
+                }
+                for inner in inner_frames {
+                    inner.depends_on_enclosing.set(true);
+                }
+                return Ok(Type::Recursive(name.to_string()));
+            }
+            indirect |= f.reached_indirectly;
+            inner_frames.push(f);
+            frame = f.parent;
+        }
+
+        if let Some(def) = self.compiled(name) {
+            return Ok(Type::Struct(def));
+        }
+        if let Some(parsed) = self.structs.get(name) {
+            return parsed.compile(self, scope).map(Type::Struct);
+        }
+        if let Some(def) = self.enums.get(name) {
+            return Ok(Type::Enum(Arc::clone(def)));
+        }
+        if let Some(def) = self.parser.struct_def(name) {
+            return Ok(Type::Struct(Arc::clone(def)));
+        }
+        self.parser
+            .enum_def(name)
+            .cloned()
+            .map(Type::Enum)
+            .ok_or_else(|| Error::UnrecognizedType {
+                name: name.to_string(),
+                span: None,
+            })
+    }
 }
 
 impl<'i> ParsedStruct<'i> {
-    fn compile(self, parser: &Parser) -> Result<StructDef, Error<'i>> {
-        let mut seen = HashSet::with_capacity(self.fields.len());
-        for field in &self.fields {
-            if seen.contains(field.name) {
-                return Err(Error::DuplicateField(field.name.to_string()));
+    fn compile(&self, resolver: &Resolver<'_, 'i>, scope: Scope) -> Result<Arc<StructDef>, Error> {
+        let (def, depends_on_enclosing) = compile_struct(
+            self.type_name,
+            resolver.span(self.location),
+            &self.includes,
+            &self.fields,
+            resolver,
+            scope,
+        )?;
+        if !depends_on_enclosing {
+            resolver
+                .compiled
+                .borrow_mut()
+                .insert(self.type_name, Arc::clone(&def));
+        }
+        Ok(def)
+    }
+}
+
+/// Also returns whether the struct refers back to one enclosing it, in which
+/// case the result only holds where it was reached from.
+fn compile_struct(
+    type_name: &str,
+    name_span: Option<Span>,
+    includes: &[(&str, Location)],
+    parsed_fields: &[ParsedField],
+    resolver: &Resolver,
+    scope: Scope,
+) -> Result<(Arc<StructDef>, bool), Error> {
+    let depth = scope.frame.map_or(0, |f| f.depth + 1);
+    if depth > MAX_TYPE_DEPTH {
+        return Err(Error::TypeTooDeep {
+            max: MAX_TYPE_DEPTH,
+            span: None,
+        });
+    }
+    let frame = Frame {
+        name: type_name,
+        parent: scope.frame,
+        depth,
+        reached_indirectly: scope.indirect,
+        depends_on_enclosing: Cell::new(false),
+    };
+    let scope = Scope {
+        frame: Some(&frame),
+        indirect: false,
+    };
+
+    // Included structs are flattened: their fields come first, in order,
+    // exactly as if they had been written out here.
+    let mut fields = Vec::new();
+    // Where each field came from: its name, or the include that brought it.
+    let mut spans = Vec::new();
+    for (name, location) in includes {
+        let span = resolver.span(*location);
+        match resolver
+            .struct_type(name, scope)
+            .map_err(|e| e.or_at(span))?
+        {
+            Type::Struct(included) => {
+                fields.extend(included.fields().iter().cloned());
+                spans.resize(fields.len(), span);
+            }
+            // An enum, or a struct that is still being compiled because it
+            // refers to this one.
+            _ => {
+                return Err(Error::InvalidInclude {
+                    name: name.to_string(),
+                    span,
+                })
+            }
+        }
+    }
+
+    for f in parsed_fields {
+        let type_ = f.type_.resolve_within(resolver, 0, scope)?;
+        ensure_shallow(&type_).map_err(|e| e.or_at(resolver.span(f.location)))?;
+        let attributes = compile_attributes(&f.attributes, &type_, resolver)?;
+        fields.push(FieldDef {
+            name: f.name.clone(),
+            type_,
+            attributes,
+        });
+        spans.push(resolver.span(f.location));
+    }
+
+    ensure_valid_type_name(type_name).map_err(|e| e.or_at(name_span))?;
+    for f in parsed_fields {
+        ensure_valid_name(&f.name).map_err(|e| e.or_at(resolver.span(f.location)))?;
+    }
+    let mut seen = HashSet::new();
+    for (field, span) in fields.iter().zip(spans) {
+        if !seen.insert(field.name()) {
+            return Err(Error::DuplicateField {
+                name: field.name().to_string(),
+                span,
+            });
+        }
+    }
+
+    let def = Arc::new(StructDef {
+        type_name: type_name.to_string(),
+        fields,
+    });
+    Ok((def, frame.depends_on_enclosing.get()))
+}
+
+impl<'i> ParsedEnum<'i> {
+    /// A variant without an explicit value gets one more than the variant
+    /// before it, or 0 if it is the first.
+    fn compile(&self, resolver: &Resolver) -> Result<EnumDef, Error> {
+        ensure_valid_type_name(self.type_name)
+            .map_err(|e| e.or_at(resolver.span(self.location)))?;
+        let mut variants: Vec<EnumVariant> = Vec::new();
+        for parsed in &self.variants {
+            let span = resolver.span(parsed.location);
+            ensure_valid_name(parsed.name).map_err(|e| e.or_at(span))?;
+            let name = parsed.name.to_string();
+            if variants.iter().any(|v| v.name == name) {
+                return Err(Error::DuplicateVariant { name, span });
+            }
+
+            let value = match (parsed.value, variants.last()) {
+                (Some(value), _) => value.parse().ok(),
+                (None, Some(previous)) => previous.value.checked_add(1),
+                (None, None) => Some(0),
+            };
+            let value = value.ok_or_else(|| Error::InvalidDiscriminant {
+                variant: name.clone(),
+                span,
+            })?;
+            if variants.iter().any(|v| v.value == value) {
+                return Err(Error::DuplicateDiscriminant {
+                    variant: name,
+                    value,
+                    span,
+                });
             }
-            seen.insert(field.name);
+
+            variants.push(EnumVariant { name, value });
         }
 
-        Ok(StructDef {
+        Ok(EnumDef {
             type_name: self.type_name.to_string(),
-            fields: self
-                .fields
-                .into_iter()
-                .map(|f| {
-                    Ok(FieldDef {
-                        name: f.name.to_string(),
-                        type_: f.type_.resolve(parser)?,
-                    })
-                })
-                .collect::<Result<_, Error<'i>>>()?,
+            variants,
         })
     }
 }
 
-impl<'i> TypeDef<'i> {
-    fn resolve(self, parser: &Parser) -> Result<Type, Error<'i>> {
+fn compile_attributes(
+    attributes: &[ParsedAttribute],
+    type_: &Type,
+    resolver: &Resolver,
+) -> Result<FieldAttributes, Error> {
+    let mut compiled = FieldAttributes::default();
+    for attribute in attributes {
+        let invalid = || Error::InvalidAttribute {
+            detail: format!("@{} on {}", attribute.name, type_),
+            span: resolver.span(attribute.location),
+        };
+
+        match (attribute.name.as_str(), attribute.args.as_slice()) {
+            ("ignore_in_hash", []) => compiled.ignore_in_hash = true,
+            ("range", [min, max]) if type_.is_integer() => {
+                let min = min.parse().map_err(|_| invalid())?;
+                let max = max.parse().map_err(|_| invalid())?;
+                if min > max {
+                    return Err(invalid());
+                }
+                compiled.range = Some((min, max));
+            }
+            ("maxlen", [max])
+                if matches!(
+                    type_,
+                    Type::String | Type::Bytes | Type::List(_) | Type::Map(..)
+                ) =>
+            {
+                compiled.max_len = Some(max.parse().map_err(|_| invalid())?);
+            }
+            ("varint", []) if type_.has_varint_form() => compiled.varint = true,
+            ("ignore_in_hash", _) | ("range", _) | ("maxlen", _) | ("varint", _) => {
+                return Err(invalid())
+            }
+            (name, _) => {
+                return Err(Error::UnknownAttribute {
+                    name: name.to_string(),
+                    span: resolver.span(attribute.location),
+                })
+            }
+        }
+    }
+    Ok(compiled)
+}
+
+impl TypeDef {
+    /// `source` is what the type was parsed from, for the spans of errors.
+    pub(crate) fn resolve(&self, parser: &Parser, source: Option<&str>) -> Result<Type, Error> {
+        self.resolve_within(&Resolver::new(parser, &[], source)?, 0, Scope::default())
+    }
+
+    fn resolve_within<'i>(
+        &self,
+        resolver: &Resolver<'_, 'i>,
+        alias_depth: usize,
+        scope: Scope,
+    ) -> Result<Type, Error> {
         match self {
-            TypeDef::Primitive(t) => Ok(t),
-            TypeDef::Generic("List", t) => Ok(Type::List(Box::new(t.resolve(parser)?))),
-            TypeDef::Struct(name) => parser
-                .struct_def(name)
-                .cloned()
-                .map(Type::Struct)
-                .ok_or_else(|| Error::UnrecognizedType(name.to_string())),
-            TypeDef::Generic(name, _) => Err(Error::UnresolvedType(name.to_string())),
+            TypeDef::Primitive(t) => Ok(t.clone()),
+            TypeDef::Generic(name, args, location) => {
+                let arity = match name.as_str() {
+                    "List" | "Optional" => 1,
+                    "Map" | "Array" => 2,
+                    _ => {
+                        return Err(Error::UnknownGeneric {
+                            name: name.to_string(),
+                            span: resolver.span(*location),
+                        })
+                    }
+                };
+                if args.len() != arity {
+                    return Err(Error::WrongGenericArity {
+                        generic: name.to_string(),
+                        expected: arity,
+                        found: args.len(),
+                        span: resolver.span(*location),
+                    });
+                }
+
+                // Every element of an array is always there, so it is no more
+                // indirect than the array itself.
+                if let ("Array", [item, TypeDef::Length(len, _)]) = (name.as_str(), args.as_slice())
+                {
+                    let item = item.resolve_within(resolver, alias_depth, scope)?;
+                    ensure_valid_array(&item, *len)
+                        .map_err(|e| e.or_at(resolver.span(*location)))?;
+                    return Ok(Type::Array(Box::new(item), *len));
+                }
+
+                let inner = Scope {
+                    indirect: true,
+                    ..scope
+                };
+                let args = args
+                    .iter()
+                    .map(|arg| arg.resolve_within(resolver, alias_depth, inner))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                match (name.as_str(), args.as_slice()) {
+                    ("List", [t]) => Ok(Type::List(Box::new(t.clone()))),
+                    ("Optional", [t]) => Ok(Type::Optional(Box::new(t.clone()))),
+                    ("Map", [k, _]) if !k.is_map_key() => Err(Error::InvalidMapKey {
+                        key: k.to_string(),
+                        span: resolver.span(*location),
+                    }),
+                    ("Map", [k, v]) => Ok(Type::Map(Box::new(k.clone()), Box::new(v.clone()))),
+                    _ => Err(Error::UnresolvedType {
+                        name: self.to_string(),
+                        span: resolver.span(*location),
+                    }),
+                }
+            }
+            TypeDef::Struct(name, location) => {
+                let span = resolver.span(*location);
+                if let Some(aliased) = resolver.alias(name) {
+                    if alias_depth >= MAX_ALIAS_DEPTH {
+                        return Err(Error::RecursiveAlias {
+                            name: name.to_string(),
+                            span,
+                        });
+                    }
+                    let type_ = aliased.resolve_within(resolver, alias_depth + 1, scope);
+                    // An alias from an earlier file has locations in that
+                    // file's source, so errors point at its use instead.
+                    if resolver.aliases.contains_key(name.as_str()) {
+                        return type_;
+                    }
+                    return type_.map_err(|e| e.at(span));
+                }
+
+                resolver.struct_type(name, scope).map_err(|e| e.or_at(span))
+            }
+            TypeDef::Length(len, location) => Err(Error::UnrecognizedType {
+                name: len.to_string(),
+                span: resolver.span(*location),
+            }),
+            // Compiled afresh wherever it is used, and registered separately
+            // (see `Resolver::take_inline`).
+            TypeDef::Inline(name, fields, location) => {
+                let span = resolver.span(*location);
+                let (def, _) = compile_struct(name, span, &[], fields, resolver, scope)?;
+                resolver
+                    .inline
+                    .borrow_mut()
+                    .push((name.clone(), fields.clone(), *location));
+                Ok(Type::Struct(def))
+            }
+        }
+    }
+}
+
+/// Parses and compiles every definition in `source`, in source order. Types
+/// may refer to structs and aliases defined anywhere in `source`, before or
+/// after the reference, as well as to those already known to `parser`.
+///
+/// Leading `include` statements are skipped; the caller is expected to have
+/// added the included files to `parser` already (see `file_includes`).
+pub fn parse_file(source: &str, parser: &Parser) -> Result<Vec<Definition>, Error> {
+    let mut parsed = Vec::new();
+    let (mut remaining, _) = include_statements(source).map_err(|e| definition_error(e, source))?;
+    while let (rest, Some(def)) = next_def(remaining, source)? {
+        parsed.push(def);
+        remaining = rest;
+    }
+
+    let resolver = Resolver::new(parser, &parsed, Some(source))?;
+    let mut defs = Vec::new();
+    let mut inline = HashMap::new();
+    for def in &parsed {
+        let def = match def {
+            ParsedDefinition::Struct(s) => match resolver.compiled(s.type_name) {
+                Some(def) => Definition::Struct(def),
+                None => Definition::Struct(s.compile(&resolver, Scope::default())?),
+            },
+            ParsedDefinition::Enum(e) => Definition::Enum(Arc::clone(&resolver.enums[e.type_name])),
+            ParsedDefinition::Alias(a) => Definition::Alias(a.name.to_string(), a.type_.clone()),
+        };
+        // Inline structs come just before the first definition that used
+        // them.
+        for inline_def in resolver.take_inline(&mut inline)? {
+            defs.push(Definition::Struct(inline_def));
         }
+        defs.push(def);
     }
+    Ok(defs)
 }
 
-pub fn next_def<'a>(
+/// Parses the definition at the start of `s`, which is the unparsed tail of
+/// `source`. Returns `None` once only whitespace is left.
+fn next_def<'a>(
     s: &'a str,
-    parser: &Parser,
-) -> Result<(&'a str, Option<StructDef>), Error<'a>> {
-    let (s, _) = multispace0(s).map_err(Error::DefinitionParsing)?;
-    let (s, struct_) = opt(struct_def)(s).map_err(Error::DefinitionParsing)?;
+    source: &'a str,
+) -> Result<(&'a str, Option<ParsedDefinition<'a>>), Error> {
+    let definition_error = |e| definition_error(e, source);
+
+    let (s, _) = ws0(s).map_err(definition_error)?;
+    let (s, struct_) = opt(struct_def)(s).map_err(definition_error)?;
+    if let Some(st) = struct_ {
+        return Ok((s, Some(ParsedDefinition::Struct(st))));
+    }
+
+    let (s, enum_) = opt(enum_def)(s).map_err(definition_error)?;
+    if let Some(e) = enum_ {
+        return Ok((s, Some(ParsedDefinition::Enum(e))));
+    }
 
-    let compiled = struct_.map(|st| st.compile(parser)).transpose()?;
-    Ok((s, compiled))
+    let (s, alias) = opt(alias_def)(s).map_err(definition_error)?;
+    match alias {
+        Some(a) => Ok((s, Some(ParsedDefinition::Alias(a)))),
+        None if s.is_empty() => Ok((s, None)),
+        None => Err(syntax_error(s, source)),
+    }
+}
+
+/// Paths named by the `include "path";` statements at the top of `source`, in
+/// source order.
+pub fn file_includes(source: &str) -> Result<Vec<&str>, Error> {
+    let (_, paths) = include_statements(source).map_err(|e| definition_error(e, source))?;
+    Ok(paths)
+}
+
+fn include_statements(s: &str) -> IResult<&str, Vec<&str>> {
+    let path = preceded(tag("\""), take_while(|c| c != '"' && c != '\n'));
+    let include = tuple((
+        ws0,
+        tag("include"),
+        ws1,
+        cut(path),
+        cut(tag("\"")),
+        ws0,
+        cut(tag(";")),
+    ));
+    let (s, includes) = many0(include)(s)?;
+    Ok((
+        s,
+        includes
+            .into_iter()
+            .map(|(_, _, _, p, _, _, _)| p)
+            .collect(),
+    ))
+}
+
+/// Parses a lone type such as `List<u64>`, resolving struct names and
+/// aliases against `parser`. Spans in errors are within `s`.
+pub fn parse_type(s: &str, parser: &Parser) -> Result<Type, Error> {
+    let trimmed = s.trim_start();
+    let (_, type_) =
+        all_consuming(terminated(|s| type_(s, 0), multispace0))(trimmed).map_err(|e| match e {
+            nom::Err::Failure(e) if e.code == nom::error::ErrorKind::TooLarge => {
+                definition_error(nom::Err::Failure(e), s)
+            }
+            _ => {
+                let start = s.len() - trimmed.len();
+                Error::UnrecognizedType {
+                    name: trimmed.trim_end().to_string(),
+                    span: Some(Span {
+                        start,
+                        end: start + trimmed.trim_end().len(),
+                    }),
+                }
+            }
+        })?;
+    type_.resolve(parser, Some(s))
 }
 
 fn struct_def(s: &str) -> IResult<&str, ParsedStruct> {
     let (s, _) = tag("struct")(s)?;
-    let (s, _) = multispace1(s)?;
-    let (s, ident) = ident(s)?;
-    let (s, _) = multispace1(s)?;
+    cut(struct_body)(s)
+}
+
+fn struct_body(s: &str) -> IResult<&str, ParsedStruct> {
+    let (s, _) = ws1(s)?;
+    let (s, (ident, location)) = located(ident)(s)?;
+    let (s, _) = ws1(s)?;
+    let (s, includes) = opt(includes)(s)?;
     let (s, _) = tag("{")(s)?;
-    let (s, fields) = many0(field)(s)?;
+    let (s, mut fields) = many0(|s| field(s, 0))(s)?;
+    let (s, _) = ws0(s)?;
     let (s, _) = tag("}")(s)?;
 
+    for f in &mut fields {
+        name_inline_structs(&mut f.type_, &format!("{}_{}", ident, f.name));
+    }
     Ok((
         s,
         ParsedStruct {
             type_name: ident,
+            location,
+            includes: includes.unwrap_or_default(),
             fields,
         },
     ))
 }
 
+fn includes(s: &str) -> IResult<&str, Vec<(&str, Location)>> {
+    let comma = tuple((ws0, tag(","), ws0));
+
+    let (s, _) = tag("includes")(s)?;
+    let (s, _) = ws1(s)?;
+    let (s, names) = separated_list1(comma, located(ident))(s)?;
+    let (s, _) = ws1(s)?;
+    Ok((s, names))
+}
+
+fn enum_def(s: &str) -> IResult<&str, ParsedEnum> {
+    let (s, _) = tag("enum")(s)?;
+    cut(enum_body)(s)
+}
+
+fn enum_body(s: &str) -> IResult<&str, ParsedEnum> {
+    let value = preceded(tuple((tag("="), ws0)), digit1);
+    let variant = tuple((ws0, located(ident), ws0, opt(value), ws0, tag(";")));
+
+    let (s, _) = ws1(s)?;
+    let (s, (type_name, location)) = located(ident)(s)?;
+    let (s, _) = ws0(s)?;
+    let (s, _) = tag("{")(s)?;
+    let (s, variants) = many0(variant)(s)?;
+    let (s, _) = ws0(s)?;
+    let (s, _) = tag("}")(s)?;
+
+    Ok((
+        s,
+        ParsedEnum {
+            type_name,
+            location,
+            variants: variants
+                .into_iter()
+                .map(|(_, (name, location), _, value, _, _)| ParsedVariant {
+                    name,
+                    location,
+                    value,
+                })
+                .collect(),
+        },
+    ))
+}
+
+/// `type Name = ...;`, or equivalently `alias Name = ...;`.
+fn alias_def(s: &str) -> IResult<&str, ParsedAlias> {
+    let (s, _) = alt((tag("type"), tag("alias")))(s)?;
+    cut(alias_body)(s)
+}
+
+fn alias_body(s: &str) -> IResult<&str, ParsedAlias> {
+    let (s, _) = ws1(s)?;
+    let (s, (name, location)) = located(ident)(s)?;
+    let (s, _) = ws0(s)?;
+    let (s, _) = tag("=")(s)?;
+    let (s, _) = ws0(s)?;
+    let (s, mut type_) = type_(s, 0)?;
+    let (s, _) = ws0(s)?;
+    let (s, _) = tag(";")(s)?;
+
+    name_inline_structs(&mut type_, name);
+    Ok((
+        s,
+        ParsedAlias {
+            name,
+            location,
+            type_,
+        },
+    ))
+}
+
+fn definition_error(e: nom::Err<nom::error::Error<&str>>, source: &str) -> Error {
+    // Only streaming parsers ask for more input; here it has simply run out.
+    let e = match e {
+        nom::Err::Incomplete(_) => return syntax_error("", source),
+        nom::Err::Error(e) | nom::Err::Failure(e) => e,
+    };
+    match e.code {
+        nom::error::ErrorKind::Alpha => {
+            let (_, name) =
+                take_while::<_, _, ()>(is_ident_char)(e.input).expect("take_while never fails");
+            Error::InvalidIdentifier {
+                name: name.to_string(),
+                span: span(e.input, name, source),
+            }
+        }
+        nom::error::ErrorKind::TooLarge => Error::TypeTooDeep {
+            max: MAX_TYPE_DEPTH,
+            span: Some(span(e.input, token(e.input), source)),
+        },
+        nom::error::ErrorKind::TakeUntil => {
+            let (line, column) = location(e.input, source);
+            Error::UnterminatedComment {
+                line,
+                column,
+                span: span(e.input, e.input, source),
+            }
+        }
+        _ => syntax_error(e.input, source),
+    }
+}
+
+fn syntax_error(remaining: &str, source: &str) -> Error {
+    let (line, column) = location(remaining, source);
+    let token = token(remaining);
+    Error::Syntax {
+        line,
+        column,
+        span: span(remaining, token, source),
+        token: Some(token)
+            .filter(|t| !t.is_empty())
+            .unwrap_or("end of input")
+            .to_string(),
+    }
+}
+
+/// The token `remaining` starts with: a run of identifier characters, or else
+/// a single character. Empty at the end of the input.
+fn token(remaining: &str) -> &str {
+    let len = match remaining.find(|c| !is_ident_char(c)) {
+        Some(0) => remaining.chars().next().map_or(0, char::len_utf8),
+        Some(len) => len,
+        None => remaining.len(),
+    };
+    &remaining[..len]
+}
+
+/// Where `token`, which starts `remaining`, sits in `source`. `remaining` is
+/// a suffix of `source`.
+fn span(remaining: &str, token: &str, source: &str) -> Span {
+    let start = source.len() - remaining.len();
+    Span {
+        start,
+        end: start + token.len(),
+    }
+}
+
+/// Locates `remaining`, a suffix of `source`, as a 1-based line and column.
+fn location(remaining: &str, source: &str) -> (usize, usize) {
+    let consumed = &source[..source.len() - remaining.len()];
+    let line_start = consumed.rfind('\n').map_or(0, |i| i + 1);
+    (
+        consumed.matches('\n').count() + 1,
+        consumed[line_start..].chars().count() + 1,
+    )
+}
+
+/// Whitespace, `// line` comments and `/* block */` comments, which may
+/// appear anywhere whitespace does. Block comments don't nest.
+fn ws0(s: &str) -> IResult<&str, ()> {
+    value((), many0(alt((value((), multispace1), comment))))(s)
+}
+
+fn ws1(s: &str) -> IResult<&str, ()> {
+    value((), many1(alt((value((), multispace1), comment))))(s)
+}
+
+fn comment(s: &str) -> IResult<&str, ()> {
+    if let Ok((s, _)) = tag::<_, _, ()>("//")(s) {
+        let (s, _) = take_while(|c| c != '\n')(s)?;
+        return Ok((s, ()));
+    }
+
+    let (rest, _) = tag("/*")(s)?;
+    match take_until::<_, _, ()>("*/")(rest) {
+        Ok((rest, _)) => Ok((&rest[2..], ())),
+        Err(_) => Err(nom::Err::Failure(nom::error::make_error(
+            s,
+            nom::error::ErrorKind::TakeUntil,
+        ))),
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// An ASCII letter or underscore followed by letters, digits and underscores.
+/// Starting with a digit is a hard failure rather than a non-match, so that
+/// `9foo` is reported instead of silently ending the definitions.
 fn ident(s: &str) -> IResult<&str, &str> {
-    alphanumeric1(s)
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(nom::Err::Failure(nom::error::make_error(
+            s,
+            nom::error::ErrorKind::Alpha,
+        )));
+    }
+
+    recognize(pair(
+        take_while1(|c: char| c.is_ascii_alphabetic() || c == '_'),
+        take_while(is_ident_char),
+    ))(s)
 }
 
-fn field(s: &str) -> IResult<&str, ParsedField> {
-    let (s, _) = multispace0(s)?;
-    let (s, name) = ident(s)?;
-    let (s, _) = multispace1(s)?;
+/// `parser`'s output along with the location of what it consumed.
+fn located<'i, O>(
+    mut parser: impl FnMut(&'i str) -> IResult<&'i str, O>,
+) -> impl FnMut(&'i str) -> IResult<&'i str, (O, Location)> {
+    move |s| {
+        let (rest, output) = parser(s)?;
+        Ok((rest, (output, Location::between(s, rest))))
+    }
+}
+
+/// A field of a struct nested `depth` types deep, see `type_`.
+fn field(s: &str, depth: usize) -> IResult<&str, ParsedField> {
+    let (s, _) = ws0(s)?;
+    let (s, (name, location)) = located(ident)(s)?;
+    // Nothing but a field starts with an identifier inside a struct, so from
+    // here on mistakes are reported rather than ending the field list.
+    cut(move |s| field_body(s, name, location, depth))(s)
+}
+
+fn field_body<'i>(
+    s: &'i str,
+    name: &'i str,
+    location: Location,
+    depth: usize,
+) -> IResult<&'i str, ParsedField> {
+    let (s, _) = ws0(s)?;
     let (s, _) = tag(":")(s)?;
-    let (s, type_) = type_(s)?;
+    let (s, _) = ws0(s)?;
+    let (s, type_) = type_(s, depth)?;
+    let (s, attributes) = many0(preceded(ws1, attribute))(s)?;
+    let (s, _) = ws0(s)?;
     let (s, _) = tag(";")(s)?;
-    let (s, _) = multispace0(s)?;
-    Ok((s, ParsedField { name, type_ }))
+    let (s, _) = ws0(s)?;
+    Ok((
+        s,
+        ParsedField {
+            name: name.to_string(),
+            location,
+            type_,
+            attributes,
+        },
+    ))
+}
+
+fn attribute(input: &str) -> IResult<&str, ParsedAttribute> {
+    let (s, _) = tag("@")(input)?;
+    let (s, name) = ident(s)?;
+    let (s, args) = opt(attribute_args)(s)?;
+
+    Ok((
+        s,
+        ParsedAttribute {
+            name: name.to_string(),
+            args: args
+                .unwrap_or_default()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            location: Location::between(input, s),
+        },
+    ))
+}
+
+fn attribute_args(s: &str) -> IResult<&str, Vec<&str>> {
+    let comma = tuple((ws0, tag(","), ws0));
+    let arg = recognize(pair(opt(tag("-")), digit1));
+
+    let (s, _) = tag("(")(s)?;
+    let (s, _) = ws0(s)?;
+    let (s, args) = separated_list0(comma, arg)(s)?;
+    let (s, _) = ws0(s)?;
+    let (s, _) = tag(")")(s)?;
+    Ok((s, args))
+}
+
+/// A type inside `depth` generics and inline structs. Past `MAX_TYPE_DEPTH`
+/// that is a hard failure, as the parsers would otherwise recurse until the
+/// stack overflows on untrusted input.
+fn type_(s: &str, depth: usize) -> IResult<&str, TypeDef> {
+    if depth > MAX_TYPE_DEPTH {
+        return Err(nom::Err::Failure(nom::error::make_error(
+            s,
+            nom::error::ErrorKind::TooLarge,
+        )));
+    }
+    alt((
+        unit_type,
+        |s| inline_struct(s, depth),
+        |s| generic_type(s, depth),
+        leaf_type,
+    ))(s)
+}
+
+/// Left unnamed; the enclosing definition names it once it is parsed.
+fn inline_struct(input: &str, depth: usize) -> IResult<&str, TypeDef> {
+    let (s, _) = tag("{")(input)?;
+    let (s, fields) = cut(many0(|s| field(s, depth + 1)))(s)?;
+    let (s, _) = ws0(s)?;
+    let (s, _) = cut(tag("}"))(s)?;
+    let location = Location::between(input, s);
+    Ok((s, TypeDef::Inline(String::new(), fields, location)))
+}
+
+/// Gives the inline structs in `type_` a name built from where they appear,
+/// `Struct_field`, `Struct_field_inner` for one inside that, and so on.
+fn name_inline_structs(type_: &mut TypeDef, name: &str) {
+    match type_ {
+        TypeDef::Inline(inline_name, fields, _) => {
+            *inline_name = name.to_string();
+            for f in fields {
+                name_inline_structs(&mut f.type_, &format!("{}_{}", name, f.name));
+            }
+        }
+        TypeDef::Generic(_, args, _) => {
+            for arg in args {
+                name_inline_structs(arg, name);
+            }
+        }
+        TypeDef::Primitive(_) | TypeDef::Struct(..) | TypeDef::Length(..) => {}
+    }
 }
 
-fn type_(s: &str) -> IResult<&str, TypeDef> {
-    alt((generic_type, leaf_type))(s)
+fn unit_type(s: &str) -> IResult<&str, TypeDef> {
+    value(TypeDef::Primitive(Type::Unit), tag("()"))(s)
 }
 
-fn generic_type(s: &str) -> IResult<&str, TypeDef> {
-    let (s, outer_type) = ident(s)?;
+fn generic_type(input: &str, depth: usize) -> IResult<&str, TypeDef> {
+    let (s, outer_type) = ident(input)?;
+    let comma = tuple((ws0, tag(","), ws0));
+
+    let (s, _) = ws0(s)?;
     let (s, _) = tag("<")(s)?;
-    let (s, inner_type) = type_(s)?;
-    let (s, _) = tag(">")(s)?;
-    Ok((s, TypeDef::Generic(outer_type, Box::new(inner_type))))
+    // Only a generic has a `<` after its name, so a mistake in the arguments
+    // is reported where it is rather than where the field ends.
+    let (s, (_, args, _, _)) = cut(tuple((
+        ws0,
+        separated_list1(comma, alt((length, |s| type_(s, depth + 1)))),
+        ws0,
+        tag(">"),
+    )))(s)?;
+    let location = Location::between(input, s);
+    Ok((s, TypeDef::Generic(outer_type.to_string(), args, location)))
+}
+
+fn length(s: &str) -> IResult<&str, TypeDef> {
+    let (s, (len, location)) = located(map_res(digit1, str::parse))(s)?;
+    Ok((s, TypeDef::Length(len, location)))
 }
 
 fn leaf_type(s: &str) -> IResult<&str, TypeDef> {
-    let (s, type_str) = ident(s)?;
-    let as_type = match type_str {
-        "bool" => TypeDef::Primitive(Type::Bool),
-        "u8" => TypeDef::Primitive(Type::U8),
-        "u32" => TypeDef::Primitive(Type::U32),
-        "u64" => TypeDef::Primitive(Type::U64),
-        "string" => TypeDef::Primitive(Type::String),
-        v => TypeDef::Struct(v),
+    let (s, (type_str, location)) = located(ident)(s)?;
+    let as_type = match primitive(type_str) {
+        Some(type_) => TypeDef::Primitive(type_),
+        None => TypeDef::Struct(type_str.to_string(), location),
     };
     Ok((s, as_type))
 }
 
+fn primitive(name: &str) -> Option<Type> {
+    Some(match name {
+        "bool" => Type::Bool,
+        "u8" => Type::U8,
+        "u16" => Type::U16,
+        "u32" => Type::U32,
+        "u64" => Type::U64,
+        "i8" => Type::I8,
+        "i16" => Type::I16,
+        "i32" => Type::I32,
+        "i64" => Type::I64,
+        "f32" => Type::F32,
+        "f64" => Type::F64,
+        "string" => Type::String,
+        "bytes" => Type::Bytes,
+        _ => return None,
+    })
+}
+
+/// Names an alias can't take because the grammar already gives them a
+/// meaning.
+fn is_builtin_type(name: &str) -> bool {
+    primitive(name).is_some() || ["List", "Array", "Map", "Optional"].contains(&name)
+}
+
+/// Names nothing can take: built-in types, `struct` and `enum`.
+pub(crate) fn is_reserved_name(name: &str) -> bool {
+    is_builtin_type(name) || ["struct", "enum"].contains(&name)
+}
+
+/// Keywords that only start a definition, so they can't name a struct, enum
+/// or alias but can name a field or variant.
+pub(crate) fn is_definition_keyword(name: &str) -> bool {
+    ["type", "alias", "include"].contains(&name)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn empty_struct() {
-        let (_, struct_) = struct_def("struct Foo {}").unwrap();
+    fn location(remaining: usize, len: usize) -> Location {
+        Location { remaining, len }
+    }
 
-        assert_eq!(struct_.type_name, "Foo");
-        assert_eq!(struct_.fields.len(), 0);
+    #[test]
+    fn empty_input() {
+        assert!(matches!(next_def("", ""), Ok(("", None))));
+    }
+
+    #[test]
+    fn whitespace_only_input() {
+        assert!(matches!(
+            next_def(" \n\t\r\n  ", " \n\t\r\n  "),
+            Ok(("", None))
+        ));
+    }
+
+    #[test]
+    fn syntax_error_location() {
+        let source = "struct Foo {\n    bar :u8;\n    baz u32;\n}\n";
+        assert_eq!(
+            next_def(source, source).unwrap_err(),
+            Error::Syntax {
+                line: 3,
+                column: 9,
+                token: "u32".to_string(),
+                span: Span { start: 34, end: 37 },
+            }
+        );
+    }
+
+    #[test]
+    fn trailing_garbage() {
+        let source = "struct Foo {}\n  oops";
+        let (rest, _) = next_def(source, source).unwrap();
+        assert_eq!(
+            next_def(rest, source).unwrap_err(),
+            Error::Syntax {
+                line: 2,
+                column: 3,
+                token: "oops".to_string(),
+                span: Span { start: 16, end: 20 },
+            }
+        );
+    }
+
+    #[test]
+    fn error_outlives_source() {
+        fn parse(source: String) -> Error {
+            next_def(&source, &source).unwrap_err()
+        }
+
+        assert_eq!(
+            parse("struct Foo { bar u8; }".to_string()),
+            Error::Syntax {
+                line: 1,
+                column: 18,
+                token: "u8".to_string(),
+                span: Span { start: 17, end: 19 },
+            }
+        );
+    }
+
+    #[test]
+    fn incomplete_is_end_of_input() {
+        let source = "struct Foo {\n";
+        assert_eq!(
+            definition_error(nom::Err::Incomplete(nom::Needed::Unknown), source),
+            Error::Syntax {
+                line: 2,
+                column: 1,
+                token: "end of input".to_string(),
+                span: Span { start: 13, end: 13 },
+            }
+        );
+    }
+
+    #[test]
+    fn comment_only_input() {
+        let source = "// nothing here\n/* or\n here */\n";
+        assert!(matches!(next_def(source, source), Ok(("", None))));
+    }
+
+    #[test]
+    fn comments_between_tokens() {
+        let parser = Parser::default();
+        let source = r#"
+        // A reading from one sensor.
+        struct /* name */ Reading {
+            // Which sensor.
+            sensor :u8; // trailing
+            value /* raw */ : /* still raw */ u32 /* before attr */ @range(0, /* max */ 100);
+            /* last */ label :List<u8>;
+        }
+        /* between */ type Blob = /* bytes */ List<u8>; // done
+        "#;
+
+        let defs = parse_file(source, &parser).unwrap();
+        let def = match defs.as_slice() {
+            [Definition::Struct(def), Definition::Alias(name, _)] if name == "Blob" => def,
+            d => panic!("expected a struct and an alias, got {:?}", d),
+        };
+        assert_eq!(def.type_name(), "Reading");
+        let names = def.fields().iter().map(|f| f.name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["sensor", "value", "label"]);
+        assert_eq!(def.fields()[1].attributes().range, Some((0, 100)));
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        let source = "struct Foo {\n  bar :u8; /* never closed\n}";
+        assert_eq!(
+            next_def(source, source).unwrap_err(),
+            Error::UnterminatedComment {
+                line: 2,
+                column: 12,
+                span: Span { start: 24, end: 41 },
+            }
+        );
+    }
+
+    #[test]
+    fn nested_block_comment() {
+        let source = "/* outer /* inner */ still outer */ struct Foo {}";
+        assert!(matches!(
+            next_def(source, source),
+            Err(Error::Syntax { .. })
+        ));
+    }
+
+    #[test]
+    fn empty_struct() {
+        let (_, struct_) = struct_def("struct Foo {}").unwrap();
+
+        assert_eq!(struct_.type_name, "Foo");
+        assert_eq!(struct_.fields.len(), 0);
+    }
+
+    #[test]
+    fn include_statements() {
+        let source = r#"
+            // Shared types.
+            include "common.sier";
+            include   "nested/other.sier" ;
+
+            struct Foo { foo :u8; }
+        "#;
+        assert_eq!(
+            file_includes(source).unwrap(),
+            vec!["common.sier", "nested/other.sier"]
+        );
+
+        assert!(matches!(
+            file_includes(r#"include "common.sier""#),
+            Err(Error::Syntax { .. })
+        ));
+    }
+
+    #[test]
+    fn includes_clause() {
+        let (_, struct_) =
+            struct_def("struct Message includes Header, Trace { body :string; }").unwrap();
+
+        assert_eq!(struct_.type_name, "Message");
+        assert_eq!(
+            struct_.includes,
+            vec![("Header", location(31, 6)), ("Trace", location(23, 5))]
+        );
+        assert_eq!(struct_.fields.len(), 1);
+    }
+
+    #[test]
+    fn snake_case_ident() {
+        let (_, struct_) = struct_def("struct user_profile { first_name : string; }").unwrap();
+
+        assert_eq!(struct_.type_name, "user_profile");
+        assert_eq!(struct_.fields[0].name, "first_name");
+    }
+
+    #[test]
+    fn camel_case_ident() {
+        let (_, struct_) = struct_def("struct UserProfile { _createdAt2 :u64; }").unwrap();
+
+        assert_eq!(struct_.type_name, "UserProfile");
+        assert_eq!(struct_.fields[0].name, "_createdAt2");
+    }
+
+    #[test]
+    fn reserved_struct_name() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_file("struct enum { a :u8; }", &parser).unwrap_err(),
+            Error::ReservedName {
+                name: "enum".to_string(),
+                span: Some(Span { start: 7, end: 11 }),
+            }
+        );
+        assert_eq!(
+            parse_file("struct u64 {}", &parser).unwrap_err(),
+            Error::ReservedName {
+                name: "u64".to_string(),
+                span: Some(Span { start: 7, end: 10 }),
+            }
+        );
+    }
+
+    #[test]
+    fn reserved_field_name() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_file("struct Foo { string :string; }", &parser).unwrap_err(),
+            Error::ReservedName {
+                name: "string".to_string(),
+                span: Some(Span { start: 13, end: 19 }),
+            }
+        );
+        assert_eq!(
+            parse_file("struct Foo { struct :u8; }", &parser).unwrap_err(),
+            Error::ReservedName {
+                name: "struct".to_string(),
+                span: Some(Span { start: 13, end: 19 }),
+            }
+        );
+    }
+
+    #[test]
+    fn definition_keywords() {
+        let parser = Parser::default();
+        let defs = parse_file(
+            "struct Foo { type :u8; alias :u8; include :u8; } enum E { type; include; }",
+            &parser,
+        )
+        .unwrap();
+        let names = match defs.as_slice() {
+            [Definition::Struct(foo), Definition::Enum(_)] => {
+                foo.fields().iter().map(|f| f.name()).collect::<Vec<_>>()
+            }
+            d => panic!("expected a struct and an enum, got {:?}", d),
+        };
+        assert_eq!(names, ["type", "alias", "include"]);
+
+        let reserved = |source, name: &str, start| {
+            assert_eq!(
+                parse_file(source, &parser).unwrap_err(),
+                Error::ReservedName {
+                    name: name.to_string(),
+                    span: Some(Span {
+                        start,
+                        end: start + name.len(),
+                    }),
+                }
+            );
+        };
+        reserved("struct type { a :u8; }", "type", 7);
+        reserved("enum include { A; }", "include", 5);
+        reserved("type alias = u8;", "alias", 5);
+    }
+
+    #[test]
+    fn reserved_enum_names() {
+        let parser = Parser::default();
+        let reserved = |source, name: &str, start| {
+            assert_eq!(
+                parse_file(source, &parser).unwrap_err(),
+                Error::ReservedName {
+                    name: name.to_string(),
+                    span: Some(Span {
+                        start,
+                        end: start + name.len(),
+                    }),
+                }
+            );
+        };
+        reserved("enum u8 { A; }", "u8", 5);
+        reserved("enum struct { A; }", "struct", 5);
+        reserved("enum Optional { A; }", "Optional", 5);
+        reserved("enum E { A; u8; }", "u8", 12);
+    }
+
+    #[test]
+    fn ident_starting_with_digit() {
+        assert_eq!(
+            next_def("struct 9foo {}", "struct 9foo {}").unwrap_err(),
+            Error::InvalidIdentifier {
+                name: "9foo".to_string(),
+                span: Span { start: 7, end: 11 },
+            }
+        );
+        assert_eq!(
+            next_def("struct Foo { 9foo :u8; }", "struct Foo { 9foo :u8; }").unwrap_err(),
+            Error::InvalidIdentifier {
+                name: "9foo".to_string(),
+                span: Span { start: 13, end: 17 },
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_type_span() {
+        let source = "struct Foo {\n    bar :u8;\n    baz :List<u8;\n}\n";
+        let error = next_def(source, source).unwrap_err();
+        let span = match &error {
+            Error::Syntax { span, .. } => *span,
+            e => panic!("expected a syntax error, got {:?}", e),
+        };
+        assert_eq!(&source[span.start..span.end], ";");
+        assert_eq!(
+            error,
+            Error::Syntax {
+                line: 3,
+                column: 17,
+                token: ";".to_string(),
+                span: Span { start: 42, end: 43 },
+            }
+        );
     }
 
     #[test]
@@ -165,10 +1545,809 @@ mod tests {
         assert_eq!(fields[0].type_, TypeDef::Primitive(Type::U64));
     }
 
+    #[test]
+    fn field_attribute() {
+        let (_, struct_) = struct_def("struct Foo { at :u64 @ignore_in_hash; }").unwrap();
+
+        let fields = struct_.fields;
+        assert_eq!(fields[0].type_, TypeDef::Primitive(Type::U64));
+        assert_eq!(
+            fields[0].attributes,
+            vec![ParsedAttribute {
+                name: "ignore_in_hash".to_string(),
+                args: vec![],
+                location: location(18, 15),
+            }]
+        );
+    }
+
+    #[test]
+    fn field_attribute_args() {
+        let (_, struct_) =
+            struct_def("struct Foo { age :u8 @range(0, 150); name :string @maxlen(64); }").unwrap();
+
+        let fields = struct_.fields;
+        assert_eq!(fields[0].attributes[0].name, "range");
+        assert_eq!(fields[0].attributes[0].args, vec!["0", "150"]);
+        assert_eq!(fields[1].attributes[0].name, "maxlen");
+        assert_eq!(fields[1].attributes[0].args, vec!["64"]);
+    }
+
+    #[test]
+    fn compiles_constraints() {
+        let parser = Parser::default();
+        let source = "struct Foo { age :u8 @range(0, 150); name :string @maxlen(64); }";
+        let def = match parse_file(source, &parser).unwrap().as_slice() {
+            [Definition::Struct(def)] => Arc::clone(def),
+            d => panic!("expected a struct, got {:?}", d),
+        };
+
+        assert_eq!(def.fields()[0].attributes().range, Some((0, 150)));
+        assert_eq!(def.fields()[1].attributes().max_len, Some(64));
+    }
+
+    #[test]
+    fn constraint_on_wrong_type() {
+        let parser = Parser::default();
+        let result = parse_file("struct Foo { name :string @range(0, 1); }", &parser);
+        assert_eq!(
+            result.unwrap_err(),
+            Error::InvalidAttribute {
+                detail: "@range on string".to_string(),
+                span: Some(Span { start: 26, end: 38 }),
+            }
+        );
+    }
+
+    #[test]
+    fn varint_only_on_wider_integers() {
+        let parser = Parser::default();
+        let def = only_struct("struct Foo { n :i64 @varint; }").unwrap();
+        assert!(def.fields()[0].attributes().varint);
+
+        for type_ in &["u8", "u16", "string", "List<u32>"] {
+            let source = format!("struct Foo {{ n :{} @varint; }}", type_);
+            assert!(matches!(
+                parse_file(&source, &parser),
+                Err(Error::InvalidAttribute { detail, .. }) if detail == format!("@varint on {}", type_)
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_field_attribute() {
+        let parser = Parser::default();
+        let result = parse_file("struct Foo { at :u64 @bogus; }", &parser);
+        assert_eq!(
+            result.unwrap_err(),
+            Error::UnknownAttribute {
+                name: "bogus".to_string(),
+                span: Some(Span { start: 21, end: 27 }),
+            }
+        );
+    }
+
+    #[test]
+    fn alias() {
+        let (_, alias) = alias_def("type Hash = List<u8>;").unwrap();
+
+        assert_eq!(alias.name, "Hash");
+        assert_eq!(
+            alias.type_,
+            TypeDef::Generic(
+                "List".to_string(),
+                vec![TypeDef::Primitive(Type::U8)],
+                location(9, 8)
+            )
+        );
+    }
+
+    #[test]
+    fn whitespace_in_generics() {
+        let list = |t, at| TypeDef::Generic("List".to_string(), vec![t], at);
+        let u8_ = || TypeDef::Primitive(Type::U8);
+
+        assert_eq!(
+            type_("List< u64 >", 0),
+            Ok(("", list(TypeDef::Primitive(Type::U64), location(11, 11))))
+        );
+        assert_eq!(type_("List <u8>", 0), Ok(("", list(u8_(), location(9, 9)))));
+        assert_eq!(
+            type_("List< List<u8> >", 0),
+            Ok(("", list(list(u8_(), location(10, 8)), location(16, 16))))
+        );
+        assert_eq!(
+            type_("Foo ;", 0),
+            Ok((" ;", TypeDef::Struct("Foo".to_string(), location(5, 3))))
+        );
+    }
+
+    #[test]
+    fn map_type() {
+        let (_, type_) = type_("Map<string, List<u8>>", 0).unwrap();
+
+        assert_eq!(
+            type_,
+            TypeDef::Generic(
+                "Map".to_string(),
+                vec![
+                    TypeDef::Primitive(Type::String),
+                    TypeDef::Generic(
+                        "List".to_string(),
+                        vec![TypeDef::Primitive(Type::U8)],
+                        location(9, 8)
+                    ),
+                ],
+                location(21, 21)
+            )
+        );
+    }
+
+    #[test]
+    fn map_key_must_be_primitive() {
+        let mut parser = Parser::default();
+        parser.add_file_defs("struct Key { id :u8; }").unwrap();
+
+        let source = "struct Foo { bad :Map<Key, u64>; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::InvalidMapKey {
+                key: "Key".to_string(),
+                span: Some(Span { start: 18, end: 31 }),
+            }
+        );
+        let source = "struct Foo { bad :Map<f64, u64>; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::InvalidMapKey {
+                key: "f64".to_string(),
+                span: Some(Span { start: 18, end: 31 }),
+            }
+        );
+    }
+
+    #[test]
+    fn direct_recursion() {
+        let parser = Parser::default();
+        let source = "struct Node { value :u8; next :Node; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::RecursiveType {
+                path: "Node -> Node".to_string(),
+                span: Some(Span { start: 31, end: 35 }),
+            }
+        );
+    }
+
+    #[test]
+    fn recursion_through_list() {
+        let parser = Parser::default();
+        let source = "struct Node { children :List<Node>; next :Optional<Node>; }";
+        let def = match parse_file(source, &parser).unwrap().as_slice() {
+            [Definition::Struct(def)] => Arc::clone(def),
+            d => panic!("expected a struct, got {:?}", d),
+        };
+
+        let node = Type::Recursive("Node".to_string());
+        assert_eq!(def.fields()[0].type_(), &Type::List(Box::new(node.clone())));
+        assert_eq!(def.fields()[1].type_(), &Type::Optional(Box::new(node)));
+    }
+
+    #[test]
+    fn forward_reference() {
+        let parser = Parser::default();
+        let source = r#"
+        struct Outer { inner :Inner; items :List<Item>; }
+        type Item = Inner;
+        struct Inner { x :u8; }
+        "#;
+        let defs = parse_file(source, &parser).unwrap();
+        let (outer, inner) = match defs.as_slice() {
+            [Definition::Struct(outer), Definition::Alias(..), Definition::Struct(inner)] => {
+                (outer, inner)
+            }
+            d => panic!("expected two structs and an alias, got {:?}", d),
+        };
+
+        assert_eq!(outer.fields()[0].type_(), &Type::Struct(Arc::clone(inner)));
+        assert_eq!(
+            outer.fields()[1].type_(),
+            &Type::List(Box::new(Type::Struct(Arc::clone(inner))))
+        );
+    }
+
+    #[test]
+    fn unknown_type_after_both_passes() {
+        let parser = Parser::default();
+        let source = "struct Outer { inner :Inner; } struct Other {}";
+        let error = parse_file(source, &parser).unwrap_err();
+        assert_eq!(
+            error,
+            Error::UnrecognizedType {
+                name: "Inner".to_string(),
+                span: Some(Span { start: 22, end: 27 }),
+            }
+        );
+        let span = error.span().unwrap();
+        assert_eq!(&source[span.start..span.end], "Inner");
+    }
+
+    #[test]
+    fn duplicate_field_span() {
+        let parser = Parser::default();
+        let source = "struct Foo { a :u8; b :u8; a :u16; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::DuplicateField {
+                name: "a".to_string(),
+                span: Some(Span { start: 27, end: 28 }),
+            }
+        );
+
+        let source = "struct Header { seq :u32; } struct Message includes Header { seq :u64; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::DuplicateField {
+                name: "seq".to_string(),
+                span: Some(Span { start: 61, end: 64 }),
+            }
+        );
+        let source = "struct Message includes Header { seq :u64; } struct Header { seq :u32; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::DuplicateField {
+                name: "seq".to_string(),
+                span: Some(Span { start: 33, end: 36 }),
+            }
+        );
+        let source = "struct A { a :u8; } struct B { a :u8; } struct C includes A, B {}";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::DuplicateField {
+                name: "a".to_string(),
+                span: Some(Span { start: 61, end: 62 }),
+            }
+        );
+    }
+
+    #[test]
+    fn include_target_is_not_a_struct() {
+        let parser = Parser::default();
+        let source = "enum E { A; } struct M includes E { x :u8; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::InvalidInclude {
+                name: "E".to_string(),
+                span: Some(Span { start: 32, end: 33 }),
+            }
+        );
+
+        let source = "struct A { b :List<B>; } struct B includes A { x :u8; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::InvalidInclude {
+                name: "A".to_string(),
+                span: Some(Span { start: 43, end: 44 }),
+            }
+        );
+    }
+
+    #[test]
+    fn span_of_alias_from_earlier_file() {
+        let mut parser = Parser::default();
+        parser.add_file_defs("type Items = List<Item>;").unwrap();
+        assert_eq!(
+            parse_file("struct Foo { items :Items; }", &parser).unwrap_err(),
+            Error::UnrecognizedType {
+                name: "Item".to_string(),
+                span: Some(Span { start: 20, end: 25 }),
+            }
+        );
+    }
+
+    #[test]
+    fn indirect_recursion() {
+        let parser = Parser::default();
+        let source = "struct A { b :B; } struct B { c :C; } struct C { a :A; }";
+        assert_eq!(
+            parse_file(source, &parser).unwrap_err(),
+            Error::RecursiveType {
+                path: "A -> B -> C -> A".to_string(),
+                span: Some(Span { start: 52, end: 53 }),
+            }
+        );
+    }
+
+    #[test]
+    fn mutual_recursion_through_optional() {
+        let parser = Parser::default();
+        let source = "struct A { b :B; } struct B { a :Optional<A>; }";
+        let defs = parse_file(source, &parser).unwrap();
+        let (a, b) = match defs.as_slice() {
+            [Definition::Struct(a), Definition::Struct(b)] => (a, b),
+            d => panic!("expected two structs, got {:?}", d),
+        };
+
+        // Each struct is complete on its own: `B` holds a full `A`, and the
+        // `B` inside `A` refers back to the enclosing `A`.
+        let recursive = Type::Optional(Box::new(Type::Recursive("A".to_string())));
+        match a.fields()[0].type_() {
+            Type::Struct(inner) => assert_eq!(inner.fields()[0].type_(), &recursive),
+            t => panic!("expected a struct, got {}", t),
+        }
+        assert_eq!(
+            b.fields()[0].type_(),
+            &Type::Optional(Box::new(Type::Struct(Arc::clone(a))))
+        );
+    }
+
+    #[test]
+    fn enum_definition() {
+        let parser = Parser::default();
+        let source = "struct Account { status :Status; } enum Status { Active; Closed; Pending; }";
+        let defs = parse_file(source, &parser).unwrap();
+        let (account, status) = match defs.as_slice() {
+            [Definition::Struct(account), Definition::Enum(status)] => (account, status),
+            d => panic!("expected a struct and an enum, got {:?}", d),
+        };
+
+        let variants = status
+            .variants()
+            .iter()
+            .map(|v| (v.name.as_str(), v.value))
+            .collect::<Vec<_>>();
+        assert_eq!(variants, vec![("Active", 0), ("Closed", 1), ("Pending", 2)]);
+        assert_eq!(account.fields()[0].type_(), &Type::Enum(Arc::clone(status)));
+    }
+
+    fn enum_values(source: &str) -> Result<Vec<(String, u32)>, Error> {
+        let parser = Parser::default();
+        match parse_file(source, &parser)?.as_slice() {
+            [Definition::Enum(def)] => Ok(def
+                .variants()
+                .iter()
+                .map(|v| (v.name.clone(), v.value))
+                .collect()),
+            d => panic!("expected an enum, got {:?}", d),
+        }
+    }
+
+    #[test]
+    fn explicit_discriminants() {
+        assert_eq!(
+            enum_values("enum Status { Active = 1; Closed = 5; }").unwrap(),
+            vec![("Active".to_string(), 1), ("Closed".to_string(), 5)]
+        );
+    }
+
+    #[test]
+    fn mixed_discriminants() {
+        assert_eq!(
+            enum_values("enum Status { Active; Closed = 5; Pending; Gone=2; }").unwrap(),
+            vec![
+                ("Active".to_string(), 0),
+                ("Closed".to_string(), 5),
+                ("Pending".to_string(), 6),
+                ("Gone".to_string(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_discriminant() {
+        assert_eq!(
+            enum_values("enum Status { Active = 1; Closed = 0; Pending; }").unwrap_err(),
+            Error::DuplicateDiscriminant {
+                variant: "Pending".to_string(),
+                value: 1,
+                span: Some(Span { start: 38, end: 45 }),
+            }
+        );
+        assert_eq!(
+            enum_values("enum Status { Active = 4294967295; Closed; }").unwrap_err(),
+            Error::InvalidDiscriminant {
+                variant: "Closed".to_string(),
+                span: Some(Span { start: 35, end: 41 }),
+            }
+        );
+    }
+
+    #[test]
+    fn duplicate_enum_variant() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_file("enum Status { Active; Active; }", &parser).unwrap_err(),
+            Error::DuplicateVariant {
+                name: "Active".to_string(),
+                span: Some(Span { start: 22, end: 28 }),
+            }
+        );
+    }
+
+    fn first_field_type(source: &str) -> Result<Type, Error> {
+        let mut parser = Parser::default();
+        parser.add_file_defs(source)?;
+        let def = parser.struct_def("Foo").unwrap();
+        Ok(def.fields()[0].type_().clone())
+    }
+
+    #[test]
+    fn alias_keyword() {
+        assert_eq!(
+            first_field_type("alias Id = u64; struct Foo { id :Id; }"),
+            Ok(Type::U64)
+        );
+        assert_eq!(
+            first_field_type("alias Blob = List<u8>; struct Foo { data :Blob; }"),
+            Ok(Type::List(Box::new(Type::U8)))
+        );
+        assert_eq!(
+            first_field_type(
+                "alias Blob = List<u8>; alias Blobs = Map<string, Blob>; \
+                 alias Index = Blobs; struct Foo { index :Optional<Index>; }"
+            ),
+            Ok(Type::Optional(Box::new(Type::Map(
+                Box::new(Type::String),
+                Box::new(Type::List(Box::new(Type::U8)))
+            ))))
+        );
+    }
+
+    #[test]
+    fn redefined_alias() {
+        assert_eq!(
+            first_field_type("alias Id = u64; type Id = u32; struct Foo { id :Id; }"),
+            Err(Error::DuplicateAlias {
+                name: "Id".to_string(),
+                span: Some(Span { start: 21, end: 23 }),
+            })
+        );
+
+        let mut parser = Parser::default();
+        parser.add_file_defs("alias Id = u64;").unwrap();
+        assert_eq!(
+            parser.add_file_defs("alias Id = u64;"),
+            Err(Error::DuplicateAlias {
+                name: "Id".to_string(),
+                span: Some(Span { start: 6, end: 8 }),
+            })
+        );
+    }
+
+    #[test]
+    fn alias_shadowing_builtin_type() {
+        assert_eq!(
+            first_field_type("alias u8 = u64; struct Foo { id :u8; }"),
+            Err(Error::ShadowsBuiltinType {
+                name: "u8".to_string(),
+                span: Some(Span { start: 6, end: 8 }),
+            })
+        );
+        assert_eq!(
+            first_field_type("type List = u8; struct Foo { id :List; }"),
+            Err(Error::ShadowsBuiltinType {
+                name: "List".to_string(),
+                span: Some(Span { start: 5, end: 9 }),
+            })
+        );
+    }
+
+    #[test]
+    fn alias_of_alias() {
+        let (_, alias) = alias_def("type Digest = Hash;").unwrap();
+
+        assert_eq!(alias.name, "Digest");
+        assert_eq!(
+            alias.type_,
+            TypeDef::Struct("Hash".to_string(), location(5, 4))
+        );
+    }
+
+    #[test]
+    fn unknown_generic() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_type("Bogus<u8>", &parser),
+            Err(Error::UnknownGeneric {
+                name: "Bogus".to_string(),
+                span: Some(Span { start: 0, end: 9 }),
+            })
+        );
+        assert_eq!(
+            parse_type("List<Bogus<Missing>>", &parser),
+            Err(Error::UnknownGeneric {
+                name: "Bogus".to_string(),
+                span: Some(Span { start: 5, end: 19 }),
+            })
+        );
+    }
+
+    #[test]
+    fn wrong_generic_arity() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_type("Map<u8>", &parser),
+            Err(Error::WrongGenericArity {
+                generic: "Map".to_string(),
+                expected: 2,
+                found: 1,
+                span: Some(Span { start: 0, end: 7 }),
+            })
+        );
+        assert_eq!(
+            parse_type("Optional<u8, u16>", &parser),
+            Err(Error::WrongGenericArity {
+                generic: "Optional".to_string(),
+                expected: 1,
+                found: 2,
+                span: Some(Span { start: 0, end: 17 }),
+            })
+        );
+    }
+
+    #[test]
+    fn array_type() {
+        let parser = Parser::default();
+        let array = parse_type("Array<List<u8>, 32>", &parser).unwrap();
+        assert_eq!(
+            array,
+            Type::Array(Box::new(Type::List(Box::new(Type::U8))), 32)
+        );
+        assert_eq!(array.to_string(), "Array<List<u8>, 32>");
+
+        assert_eq!(
+            parse_type("Array<u8>", &parser),
+            Err(Error::WrongGenericArity {
+                generic: "Array".to_string(),
+                expected: 2,
+                found: 1,
+                span: Some(Span { start: 0, end: 9 }),
+            })
+        );
+        assert_eq!(
+            parse_type("List<32>", &parser),
+            Err(Error::UnrecognizedType {
+                name: "32".to_string(),
+                span: Some(Span { start: 5, end: 7 }),
+            })
+        );
+    }
+
+    #[test]
+    fn array_limits() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_type("Array<(), 1099511627776>", &parser),
+            Err(Error::ArrayTooLong {
+                len: 1099511627776,
+                max: crate::MAX_ARRAY_LEN,
+                span: Some(Span { start: 0, end: 24 }),
+            })
+        );
+        assert_eq!(
+            parse_type("Array<u64, 18446744073709551615>", &parser),
+            Err(Error::ArrayTooLong {
+                len: usize::MAX,
+                max: crate::MAX_ARRAY_LEN,
+                span: Some(Span { start: 0, end: 32 }),
+            })
+        );
+        assert_eq!(
+            parse_type("Array<(), 4>", &parser),
+            Err(Error::ZeroWidthArrayItem {
+                item: "()".to_string(),
+                span: Some(Span { start: 0, end: 12 }),
+            })
+        );
+        assert_eq!(
+            parse_file("struct E {} struct Foo { a :Array<E, 4>; }", &parser).unwrap_err(),
+            Error::ZeroWidthArrayItem {
+                item: "E".to_string(),
+                span: Some(Span { start: 28, end: 39 }),
+            }
+        );
+        assert!(parse_type("Array<Array<u8, 1024>, 1024>", &parser).is_ok());
+    }
+
+    #[test]
+    fn lone_type_spans() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_type("Array<u8, u8>", &parser),
+            Err(Error::UnresolvedType {
+                name: "Array<u8, u8>".to_string(),
+                span: Some(Span { start: 0, end: 13 }),
+            })
+        );
+        // Nothing names an inline struct outside a definition.
+        assert_eq!(
+            parse_type("{ x :u8; }", &parser),
+            Err(Error::EmptyName {
+                span: Some(Span { start: 0, end: 10 }),
+            })
+        );
+    }
+
+    #[test]
+    fn array_of_self_is_infinite() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_file("struct Node { children :Array<Node, 2>; }", &parser).unwrap_err(),
+            Error::RecursiveType {
+                path: "Node -> Node".to_string(),
+                span: Some(Span { start: 30, end: 34 }),
+            }
+        );
+    }
+
+    #[test]
+    fn lone_type() {
+        let parser = Parser::default();
+        assert_eq!(
+            parse_type(" List<u8> ", &parser),
+            Ok(Type::List(Box::new(Type::U8)))
+        );
+        assert_eq!(
+            parse_type("List<u8", &parser),
+            Err(Error::UnrecognizedType {
+                name: "List<u8".to_string(),
+                span: Some(Span { start: 0, end: 7 }),
+            })
+        );
+        assert_eq!(
+            parse_type(" u8 u8 ", &parser),
+            Err(Error::UnrecognizedType {
+                name: "u8 u8".to_string(),
+                span: Some(Span { start: 1, end: 6 }),
+            })
+        );
+    }
+
+    #[test]
+    fn deeply_nested_lists() {
+        let parser = Parser::default();
+        let expected = (0..6).fold(Type::U8, |t, _| Type::List(Box::new(t)));
+        assert_eq!(
+            parse_type("List<List<List< List<List<List<u8>>> >>>", &parser),
+            Ok(expected)
+        );
+    }
+
+    #[test]
+    fn type_depth_limit() {
+        let parser = Parser::default();
+        let lists = |n| format!("{}u8{}", "List<".repeat(n), ">".repeat(n));
+        assert!(parse_type(&lists(MAX_TYPE_DEPTH), &parser).is_ok());
+        assert_eq!(
+            parse_type(&format!(" {}", lists(MAX_TYPE_DEPTH + 1)), &parser),
+            Err(Error::TypeTooDeep {
+                max: MAX_TYPE_DEPTH,
+                span: Some(Span {
+                    start: 1 + 5 * (MAX_TYPE_DEPTH + 1),
+                    end: 3 + 5 * (MAX_TYPE_DEPTH + 1),
+                }),
+            })
+        );
+
+        let inline = |n| format!("{}u8{}", "{ a :".repeat(n), "; }".repeat(n));
+        let source = |n| format!("struct Foo {{ a :{}; }}", inline(n));
+        assert!(parse_file(&source(MAX_TYPE_DEPTH), &parser).is_ok());
+        assert!(matches!(
+            parse_file(&source(MAX_TYPE_DEPTH + 1), &parser),
+            Err(Error::TypeTooDeep { .. })
+        ));
+
+        // Each struct is compiled while compiling the one before it.
+        let chain = |n| {
+            let mut source = (0..n)
+                .map(|i| format!("struct S{} {{ a :S{}; }}\n", i, i + 1))
+                .collect::<String>();
+            source += &format!("struct S{} {{}}", n);
+            source
+        };
+        assert!(parse_file(&chain(MAX_TYPE_DEPTH), &parser).is_ok());
+        let source = chain(1000);
+        let error = parse_file(&source, &parser).unwrap_err();
+        assert_eq!(
+            error,
+            Error::TypeTooDeep {
+                max: MAX_TYPE_DEPTH,
+                span: error.span(),
+            }
+        );
+        let span = error.span().unwrap();
+        let at = format!(":S{};", MAX_TYPE_DEPTH + 1);
+        assert_eq!(&source[span.start - 1..span.end + 1], at);
+
+        // Compiled one at a time, but each adds a level to the next.
+        let reversed = |n| {
+            let mut source = format!("struct S{} {{}}\n", n);
+            for i in (0..n).rev() {
+                source += &format!("struct S{} {{ a :S{}; }}\n", i, i + 1);
+            }
+            source
+        };
+        assert!(parse_file(&reversed(MAX_TYPE_DEPTH - 1), &parser).is_ok());
+        assert!(matches!(
+            parse_file(&reversed(1000), &parser),
+            Err(Error::TypeTooDeep { .. })
+        ));
+    }
+
     #[test]
     fn duplicate_fields() {
         let parser = Parser::default();
-        let result = next_def("struct Foo { bar :u64; bar :u64; }", &parser);
+        let result = parse_file("struct Foo { bar :u64; bar :u64; }", &parser);
         assert!(result.is_err());
     }
+    fn only_struct(source: &str) -> Result<Arc<StructDef>, Error> {
+        match parse_file(source, &Parser::default())?.as_slice() {
+            // Inline structs come first.
+            [.., Definition::Struct(def)] => Ok(Arc::clone(def)),
+            d => panic!("expected a struct, got {:?}", d),
+        }
+    }
+
+    fn struct_field(def: &StructDef, index: usize) -> Arc<StructDef> {
+        match def.field(index).unwrap().type_() {
+            Type::Struct(def) => Arc::clone(def),
+            t => panic!("expected a struct, got {}", t),
+        }
+    }
+
+    #[test]
+    fn inline_struct() {
+        let person = only_struct(
+            "struct Person { name :string; address : { street : string; geo :{ lat :f64; }; }; }",
+        )
+        .unwrap();
+
+        let address = struct_field(&person, 1);
+        assert_eq!(address.type_name(), "Person_address");
+        assert_eq!(address.field(0).unwrap().type_(), &Type::String);
+        let geo = struct_field(&address, 1);
+        assert_eq!(geo.type_name(), "Person_address_geo");
+        assert_eq!(geo.field(0).unwrap().type_(), &Type::F64);
+    }
+
+    #[test]
+    fn inline_struct_display() {
+        let (_, type_) = type_("{ x :u8 @range(0, 9) @ignore_in_hash; n :List<u8>; }", 0).unwrap();
+        assert_eq!(
+            type_.to_string(),
+            "{ x :u8 @range(0, 9) @ignore_in_hash; n :List<u8>; }"
+        );
+    }
+
+    #[test]
+    fn inline_struct_in_generic() {
+        let route = only_struct("struct Route { stops :List<{ at :u64; }>; }").unwrap();
+        match route.field(0).unwrap().type_() {
+            Type::List(t) => assert_eq!(t.to_string(), "Route_stops"),
+            t => panic!("expected a list, got {}", t),
+        }
+    }
+
+    #[test]
+    fn inline_struct_duplicate_fields() {
+        assert_eq!(
+            only_struct("struct Person { address :{ zip :u32; zip :u32; }; }").unwrap_err(),
+            Error::DuplicateField {
+                name: "zip".to_string(),
+                span: Some(Span { start: 37, end: 40 }),
+            }
+        );
+    }
+
+    #[test]
+    fn inline_struct_refers_to_enclosing() {
+        assert!(only_struct("struct Node { meta :{ parent :Optional<Node>; }; }").is_ok());
+        assert_eq!(
+            only_struct("struct Node { meta :{ again :Node; }; }").unwrap_err(),
+            Error::RecursiveType {
+                path: "Node -> Node_meta -> Node".to_string(),
+                span: Some(Span { start: 29, end: 33 }),
+            }
+        );
+    }
 }
@@ -1,10 +1,27 @@
+use core::convert::TryFrom;
+
 use crate::{Error, FieldDef, Object, StructDef, Type, Value};
 use serde_json::{json, Map as SerdeMap, Number as SerdeNumber, Value as SerdeValue};
 use std::sync::Arc;
 
-fn parse_serde_value<'a>(v: &SerdeValue, type_: &'a Type) -> Result<Value<'a>, Error<'a>> {
+/// `enclosing` holds the structs being converted, innermost last, for
+/// resolving `Type::Recursive`.
+fn parse_serde_value<'a>(
+    v: &SerdeValue,
+    type_: &'a Type,
+    enclosing: &[&'a StructDef],
+) -> Result<Value<'a>, Error> {
+    if let Type::Optional(inner) = type_ {
+        return match v {
+            SerdeValue::Null => Ok(Value::Optional(None)),
+            v => Ok(Value::Optional(Some(Box::new(parse_serde_value(
+                v, inner, enclosing,
+            )?)))),
+        };
+    }
+
     match v {
-        SerdeValue::Null => unimplemented!(),
+        SerdeValue::Null => Err(Error::InvalidJson),
         SerdeValue::Bool(b) => {
             if *type_ != Type::Bool {
                 return Err(Error::InvalidJson);
@@ -12,81 +29,210 @@ fn parse_serde_value<'a>(v: &SerdeValue, type_: &'a Type) -> Result<Value<'a>, E
 
             Ok(Value::Bool(*b))
         }
-        SerdeValue::Number(number) => {
-            if *type_ != Type::U64 {
-                return Err(Error::InvalidJson);
-            }
-
-            let n = number.as_u64().unwrap_or_else(|| unimplemented!());
-            Ok(Value::U64(n))
-        }
-        SerdeValue::String(s) => {
-            if *type_ != Type::String {
-                return Err(Error::InvalidJson);
-            }
-
-            Ok(Value::String(s.to_string()))
-        }
-        SerdeValue::Array(vec) => {
-            if let Type::List(arr_type) = type_ {
+        SerdeValue::Number(number) => parse_number(number, type_).ok_or(Error::InvalidJson),
+        SerdeValue::String(s) => match type_ {
+            Type::String => Ok(Value::String(s.to_string())),
+            Type::Enum(def) => def.value(s).ok_or(Error::InvalidJson),
+            _ => Err(Error::InvalidJson),
+        },
+        SerdeValue::Array(vec) if *type_ == Type::Bytes => vec
+            .iter()
+            .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect::<Option<_>>()
+            .map(Value::Bytes)
+            .ok_or(Error::InvalidJson),
+        SerdeValue::Array(vec) => match type_ {
+            Type::List(arr_type) => {
                 let list = vec
                     .iter()
-                    .map(|val| parse_serde_value(val, arr_type))
+                    .map(|val| parse_serde_value(val, arr_type, enclosing))
                     .collect::<Result<_, _>>()?;
                 Ok(Value::List(list))
-            } else {
-                Err(Error::InvalidJson)
             }
+            Type::Array(item_type, len) if vec.len() == *len => {
+                let items = vec
+                    .iter()
+                    .map(|val| parse_serde_value(val, item_type, enclosing))
+                    .collect::<Result<_, _>>()?;
+                Ok(Value::Array(items))
+            }
+            _ => Err(Error::InvalidJson),
+        },
+        SerdeValue::Object(entries) if matches!(type_, Type::Map(..)) => {
+            let (key_type, value_type) = match type_ {
+                Type::Map(k, v) => (k, v),
+                _ => unreachable!("matched above"),
+            };
+            let entries = entries
+                .iter()
+                .map(|(k, v)| {
+                    let key = match **key_type {
+                        Type::String => Value::String(k.clone()),
+                        // Written with `to_string` by `transform_sier_value`.
+                        _ => {
+                            let key: SerdeValue =
+                                serde_json::from_str(k).map_err(|_| Error::InvalidJson)?;
+                            parse_serde_value(&key, key_type, enclosing)?
+                        }
+                    };
+                    Ok((key, parse_serde_value(v, value_type, enclosing)?))
+                })
+                .collect::<Result<_, Error>>()?;
+            Ok(Value::Map(entries))
         }
-        SerdeValue::Object(inner_json_obj) => {
-            if let Type::Struct(struct_type) = type_ {
-                Ok(Value::Struct(transform_serde_obj(
-                    inner_json_obj,
-                    struct_type,
-                )?))
-            } else {
-                Err(Error::InvalidJson)
+        SerdeValue::Object(inner_json_obj) if *type_ == Type::Unit => {
+            if !inner_json_obj.is_empty() {
+                return Err(Error::InvalidJson);
             }
+            Ok(Value::Unit)
+        }
+        SerdeValue::Object(inner_json_obj) => {
+            let def = match type_ {
+                Type::Struct(struct_type) => struct_type.as_ref(),
+                Type::Recursive(name) => enclosing
+                    .iter()
+                    .rev()
+                    .find(|d| d.type_name() == name)
+                    .copied()
+                    .ok_or(Error::InvalidJson)?,
+                _ => return Err(Error::InvalidJson),
+            };
+            Ok(Value::Struct(object_from_serde(
+                inner_json_obj,
+                def,
+                enclosing,
+            )?))
         }
     }
 }
 
+/// `None` if `number` doesn't fit in `type_`.
+fn parse_number<'a>(number: &SerdeNumber, type_: &Type) -> Option<Value<'a>> {
+    let unsigned = || number.as_u64();
+    let signed = || number.as_i64();
+    Some(match type_ {
+        Type::U8 => Value::U8(u8::try_from(unsigned()?).ok()?),
+        Type::U16 => Value::U16(u16::try_from(unsigned()?).ok()?),
+        Type::U32 => Value::U32(u32::try_from(unsigned()?).ok()?),
+        Type::U64 => Value::U64(unsigned()?),
+        Type::I8 => Value::I8(i8::try_from(signed()?).ok()?),
+        Type::I16 => Value::I16(i16::try_from(signed()?).ok()?),
+        Type::I32 => Value::I32(i32::try_from(signed()?).ok()?),
+        Type::I64 => Value::I64(signed()?),
+        Type::F32 => Value::F32(number.as_f64()? as f32),
+        Type::F64 => Value::F64(number.as_f64()?),
+        _ => return None,
+    })
+}
+
 pub fn transform_serde_obj<'a>(
     json_obj: &SerdeMap<String, SerdeValue>,
     def: &'a Arc<StructDef>,
-) -> Result<Object<'a>, Error<'a>> {
+) -> Result<Object<'a>, Error> {
+    object_from_serde(json_obj, def, &[])
+}
+
+pub(crate) fn object_from_json<'a>(
+    json: &SerdeValue,
+    def: &'a StructDef,
+) -> Result<Object<'a>, Error> {
+    object_from_serde(json.as_object().ok_or(Error::InvalidJson)?, def, &[])
+}
+
+fn object_from_serde<'a>(
+    json_obj: &SerdeMap<String, SerdeValue>,
+    def: &'a StructDef,
+    enclosing: &[&'a StructDef],
+) -> Result<Object<'a>, Error> {
+    let enclosing = enclosing
+        .iter()
+        .copied()
+        .chain(core::iter::once(def))
+        .collect::<Vec<_>>();
+
+    if let Some(extra) = json_obj.keys().find(|k| def.field_index(k).is_none()) {
+        return Err(Error::UnknownField(format!(
+            "{}.{}",
+            def.type_name(),
+            extra
+        )));
+    }
+
     let mut values = Vec::with_capacity(json_obj.len());
     for field_def in def.fields().iter() {
-        let sier_value = parse_serde_value(
-            json_obj.get(field_def.name()).ok_or(Error::InvalidJson)?,
+        let json_value = json_obj.get(field_def.name()).ok_or_else(|| {
+            Error::MissingField(format!("{}.{}", def.type_name(), field_def.name()))
+        })?;
+        values.push(parse_serde_value(
+            json_value,
             field_def.type_(),
-        )?;
-        values.push(sier_value);
+            &enclosing,
+        )?);
     }
 
-    Ok(Object::new(def.as_ref(), values))
+    Ok(Object::new(def, values))
 }
 
-fn transform_sier_value<'a>(sier_value: &'a Value) -> Result<SerdeValue, Error<'a>> {
+fn transform_sier_value(sier_value: &Value) -> Result<SerdeValue, Error> {
     Ok(match sier_value {
         Value::Bool(b) => SerdeValue::Bool(*b),
-        Value::List(list) => SerdeValue::Array(
+        Value::List(list) | Value::Array(list) => SerdeValue::Array(
             list.iter()
                 .map(transform_sier_value)
                 .collect::<Result<Vec<_>, _>>()?,
         ),
         Value::String(str) => SerdeValue::String(str.clone()),
+        Value::Bytes(bytes) => SerdeValue::Array(
+            bytes
+                .iter()
+                .map(|b| SerdeValue::Number(SerdeNumber::from(*b)))
+                .collect(),
+        ),
+        // JSON object keys are always strings.
+        Value::Map(entries) => SerdeValue::Object(
+            entries
+                .iter()
+                .map(|(k, v)| {
+                    let key = match transform_sier_value(k)? {
+                        SerdeValue::String(s) => s,
+                        other => other.to_string(),
+                    };
+                    Ok((key, transform_sier_value(v)?))
+                })
+                .collect::<Result<_, Error>>()?,
+        ),
+        Value::Optional(None) => SerdeValue::Null,
+        Value::Optional(Some(v)) => transform_sier_value(v)?,
         Value::Struct(obj) => transform_sier_obj(obj)?,
+        Value::Enum(..) => {
+            SerdeValue::String(sier_value.as_enum().ok_or(Error::InvalidJson)?.to_string())
+        }
         Value::U32(n) => SerdeValue::Number(SerdeNumber::from(*n)),
         Value::U64(n) => SerdeValue::Number(SerdeNumber::from(*n)),
+        Value::I8(n) => SerdeValue::Number(SerdeNumber::from(*n)),
+        Value::I16(n) => SerdeValue::Number(SerdeNumber::from(*n)),
+        Value::I32(n) => SerdeValue::Number(SerdeNumber::from(*n)),
+        Value::I64(n) => SerdeValue::Number(SerdeNumber::from(*n)),
+        // JSON has no NaN or infinities.
+        Value::F32(n) => {
+            SerdeNumber::from_f64((*n).into()).map_or(SerdeValue::Null, SerdeValue::Number)
+        }
+        Value::F64(n) => SerdeNumber::from_f64(*n).map_or(SerdeValue::Null, SerdeValue::Number),
         Value::U8(n) => SerdeValue::Number(SerdeNumber::from(*n)),
+        Value::U16(n) => SerdeValue::Number(SerdeNumber::from(*n)),
         Value::Unit => json!({}),
     })
 }
-pub fn transform_sier_obj<'a>(sier_obj: &'a Object) -> Result<SerdeValue, Error<'a>> {
+pub fn transform_sier_obj(sier_obj: &Object) -> Result<SerdeValue, Error> {
+    transform_fields(sier_obj.schema().fields().iter().zip(&sier_obj.values))
+}
+
+pub(crate) fn transform_fields<'a, 's: 'a>(
+    fields: impl Iterator<Item = (&'a FieldDef, &'a Value<'s>)>,
+) -> Result<SerdeValue, Error> {
     let mut result = SerdeMap::new();
-    for FieldDef { name, .. } in sier_obj.schema().fields() {
-        result.insert(name.clone(), transform_sier_value(&sier_obj[name])?);
+    for (FieldDef { name, .. }, value) in fields {
+        result.insert(name.clone(), transform_sier_value(value)?);
     }
 
     Ok(SerdeValue::Object(result))
@@ -1,64 +1,365 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+mod annotate;
+pub use annotate::ByteSpan;
+
+mod binary;
 
 mod builder;
-use builder::Builder;
+pub use builder::{BuildError, Builder, ValueBuilder};
+
+mod complexity;
+pub use complexity::ComplexityEstimate;
 
 mod definition_parser;
+use definition_parser::{Definition, TypeDef};
+
+mod native;
+pub use native::{SierDecode, SierEncode, SierValue};
+#[cfg(feature = "derive")]
+pub use sier_codec_derive::{SierDecode, SierEncode};
+
+/// Used by the code `sier-codec-derive` generates, not part of the API.
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::native::{check_fields, field};
+}
 
 mod object;
-use object::{Object, Value};
+pub use object::{AssignError, Object, PartialObject, Value};
+
+mod options;
+pub use options::{DecodeOptions, EncodeOptions, Endianness, UnknownEnum};
+
+mod stream;
+pub use stream::{read_framed, read_framed_with_limit, write_framed};
 
 mod schema;
-use schema::{FieldDef, Id, StructDef, Type};
+pub use schema::{
+    EnumDef, EnumVariant, FieldAttributes, FieldDef, Id, SizeHint, StructDef, StructDefBuilder,
+    Type,
+};
 
 pub mod json;
 
 use serde_json::Value as SerdeValue;
 use thiserror::Error as ThisError;
 
+/// Largest schema source accepted by [`Parser::parse_self_describing`].
+pub const MAX_EMBEDDED_SCHEMA_LEN: usize = 64 * 1024;
+
+/// Largest frame accepted by [`read_framed`].
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Longest `Array` a schema may declare, whether parsed or loaded from a
+/// blob.
+pub const MAX_ARRAY_LEN: usize = 1024 * 1024;
+
+/// Deepest a schema may nest generics and inline structs, whether parsed or
+/// loaded from a blob, and how long a chain of structs defined later in the
+/// same source a parsed struct may refer through. Untrusted schemas could
+/// otherwise nest them deep enough to overflow the stack.
+pub const MAX_TYPE_DEPTH: usize = 64;
+
 #[derive(Debug, Default)]
 pub struct Parser {
     structs: HashMap<Id, Arc<StructDef>>,
+    aliases: HashMap<String, TypeDef>,
+    enums: HashMap<String, Arc<EnumDef>>,
 }
 
 impl Parser {
-    pub fn add_file_defs<'i>(&mut self, file_contents: &'i str) -> Result<(), Error<'i>> {
-        let mut remaining_contents = file_contents;
+    /// A parser with every definition in `schema` registered. Like
+    /// [`Parser::add_file_defs`], `schema` can't include other files.
+    pub fn from_schema(schema: &str) -> Result<Parser, Error> {
+        let mut parser = Parser::default();
+        parser.add_file_defs(schema)?;
+        Ok(parser)
+    }
+
+    /// Fails with `Error::UnresolvedInclude` if `file_contents` includes other
+    /// files; use [`Parser::add_file_with_includes`] for those.
+    pub fn add_file_defs(&mut self, file_contents: &str) -> Result<(), Error> {
+        self.parse_all(file_contents).map(|_| ())
+    }
+
+    /// Like [`Parser::add_file_defs`], also returning the structs defined in
+    /// `file_contents` in source order. All of `file_contents` must be valid
+    /// definitions; anything left over is a syntax error.
+    pub fn parse_all(&mut self, file_contents: &str) -> Result<Vec<Arc<StructDef>>, Error> {
+        if let Some(path) = definition_parser::file_includes(file_contents)?.first() {
+            return Err(Error::UnresolvedInclude(path.to_string()));
+        }
 
-        while let (c, Some(def)) = definition_parser::next_def(remaining_contents, self)? {
-            let existing = self.structs.insert(def.id(), Arc::new(def));
-            if let Some(s) = existing {
-                return Err(Error::DuplicateStructDef(s.type_name().to_string()));
+        let mut structs = Vec::new();
+        for def in definition_parser::parse_file(file_contents, self)? {
+            if let Definition::Struct(def) = &def {
+                structs.push(Arc::clone(def));
+            }
+            self.add_def(def)?;
+        }
+
+        Ok(structs)
+    }
+
+    /// Adds the file at `path` along with everything it includes, directly or
+    /// not. `resolve` returns the source of the file at a path, exactly as
+    /// written in the `include` statement, so where files live is up to the
+    /// caller. A file included more than once is only added the first time.
+    pub fn add_file_with_includes<'i, F>(&mut self, path: &str, mut resolve: F) -> Result<(), Error>
+    where
+        F: FnMut(&str) -> Option<&'i str>,
+    {
+        let mut added = HashSet::new();
+        self.add_included(path, &mut resolve, &mut Vec::new(), &mut added)
+    }
+
+    /// `including` holds the files whose includes are being added, outermost
+    /// first.
+    fn add_included<'i>(
+        &mut self,
+        path: &str,
+        resolve: &mut dyn FnMut(&str) -> Option<&'i str>,
+        including: &mut Vec<String>,
+        added: &mut HashSet<String>,
+    ) -> Result<(), Error> {
+        if let Some(start) = including.iter().position(|p| p == path) {
+            let mut chain = including[start..].to_vec();
+            chain.push(path.to_string());
+            return Err(Error::CircularInclude(chain.join(" -> ")));
+        }
+        if added.contains(path) {
+            return Ok(());
+        }
+
+        let source = resolve(path).ok_or_else(|| Error::UnresolvedInclude(path.to_string()))?;
+        including.push(path.to_string());
+        for include in definition_parser::file_includes(source)? {
+            self.add_included(include, resolve, including, added)?;
+        }
+        including.pop();
+
+        for def in definition_parser::parse_file(source, self)? {
+            self.add_def(def)?;
+        }
+        added.insert(path.to_string());
+        Ok(())
+    }
+
+    fn add_def(&mut self, def: Definition) -> Result<(), Error> {
+        match def {
+            Definition::Struct(def) => {
+                self.ensure_type_name_free(def.type_name())?;
+                self.structs.insert(def.id(), def);
+            }
+            Definition::Alias(name, type_) => {
+                if self.aliases.contains_key(&name) {
+                    return Err(Error::DuplicateAlias { name, span: None });
+                }
+                self.aliases.insert(name, type_);
+            }
+            Definition::Enum(def) => {
+                self.ensure_type_name_free(def.type_name())?;
+                self.enums.insert(def.type_name().to_string(), def);
             }
-            remaining_contents = c;
         }
+        Ok(())
+    }
 
+    /// Structs and enums share one namespace, so a field's type can only
+    /// ever mean one of them.
+    fn ensure_type_name_free(&self, name: &str) -> Result<(), Error> {
+        if self.struct_def(name).is_some() {
+            return Err(Error::DuplicateStruct {
+                name: name.to_string(),
+                span: None,
+            });
+        }
+        if self.enum_def(name).is_some() {
+            return Err(Error::DuplicateEnum {
+                name: name.to_string(),
+                span: None,
+            });
+        }
         Ok(())
     }
 
-    pub fn parse<'i>(&self, bytes: &'i [u8]) -> Result<Object, Error<'i>> {
+    /// Canonical text for the definitions in `file_contents`, suitable for
+    /// hashing. Semantically equal schemas produce identical text:
+    ///
+    /// * Structs are rendered in source order with their fields in source
+    ///   order (order is part of the wire format), one field per line as
+    ///   `name :type;` indented by four spaces.
+    /// * Aliases are expanded into the fields that use them and the alias
+    ///   declarations themselves are dropped.
+    /// * Inline structs become structs of their own, under their generated
+    ///   names, just before the struct that first uses them.
+    /// * All other whitespace between tokens is discarded.
+    /// * Identifiers are kept exactly as written, including case.
+    ///
+    /// A compiled `StructDef` is already in canonical form; its `Display`
+    /// output is the same text.
+    pub fn canonicalize(file_contents: &str) -> Result<String, Error> {
+        let mut parser = Parser::default();
+        let mut canonical = Vec::new();
+
+        for def in definition_parser::parse_file(file_contents, &parser)? {
+            match &def {
+                Definition::Struct(def) => canonical.push(def.to_string()),
+                Definition::Enum(def) => canonical.push(def.to_string()),
+                Definition::Alias(..) => {}
+            }
+            parser.add_def(def)?;
+        }
+
+        Ok(canonical
+            .into_iter()
+            .map(|def| def + "\n")
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    pub fn parse(&self, bytes: &[u8]) -> Result<Object, Error> {
+        self.parse_with(bytes, &DecodeOptions::default())
+    }
+
+    pub fn parse_with(&self, bytes: &[u8], options: &DecodeOptions) -> Result<Object, Error> {
         use core::convert::TryInto;
 
-        let id = bytes[0..8].try_into().map_err(|_| Error::TooFewBytes)?;
+        let id = bytes
+            .get(0..8)
+            .ok_or(Error::TooFewBytes)?
+            .try_into()
+            .map_err(|_| Error::TooFewBytes)?;
         let schema = self.structs.get(&id).ok_or(Error::MissingId(id))?;
 
         let bytes = &bytes[8..];
-        let (bytes, obj) = schema.parse(bytes)?;
+        let (bytes, obj) = schema.parse_with(bytes, options)?;
         if !bytes.is_empty() {
-            return Err(Error::TooManyBytes);
+            return Err(Error::TrailingBytes(bytes.len()));
         }
         Ok(obj)
     }
 
+    /// Compiles the schema embedded in a message produced by
+    /// `Object::serialize_self_describing` and finds the message's struct in
+    /// it. The schema is untrusted, so it is compiled with the same limits as
+    /// any other, and nothing of it is registered anywhere. The message
+    /// itself is decoded by [`SelfDescribing::object`].
+    pub fn parse_self_describing(bytes: &[u8]) -> Result<SelfDescribing, Error> {
+        use core::convert::TryInto;
+
+        let (bytes, len) = schema::var_int(bytes).map_err(Error::from)?;
+        if len > MAX_EMBEDDED_SCHEMA_LEN {
+            return Err(Error::EmbeddedSchemaTooLarge(len));
+        }
+        if bytes.len() < len {
+            return Err(Error::TooFewBytes);
+        }
+        let (source, message) = bytes.split_at(len);
+
+        let embedded = Parser::from_schema(std::str::from_utf8(source)?)?;
+        let id = message
+            .get(0..8)
+            .ok_or(Error::TooFewBytes)?
+            .try_into()
+            .expect("took 8 bytes");
+        let def = Arc::clone(embedded.structs.get(&id).ok_or(Error::MissingId(id))?);
+        Ok(SelfDescribing {
+            def,
+            message: message.to_vec(),
+        })
+    }
+
+    /// The registered struct called `name`. Struct names are unique within a
+    /// parser.
+    pub fn get(&self, name: &str) -> Option<&StructDef> {
+        self.struct_def(name).map(|def| def.as_ref())
+    }
+
     pub fn struct_def(&self, name: &str) -> Option<&Arc<StructDef>> {
         self.structs.values().find(|s| s.type_name() == name)
     }
 
+    pub fn enum_def(&self, name: &str) -> Option<&Arc<EnumDef>> {
+        self.enums.get(name)
+    }
+
+    pub(crate) fn alias(&self, name: &str) -> Option<&TypeDef> {
+        self.aliases.get(name)
+    }
+
+    /// Compact binary form of every registered struct, see
+    /// `StructDef::to_binary`. Aliases are already expanded into the structs
+    /// using them, so they aren't included.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut structs = self.structs.values().collect::<Vec<_>>();
+        structs.sort_by(|a, b| a.type_name().cmp(b.type_name()));
+        binary::encode(structs.into_iter().map(AsRef::as_ref))
+    }
+
+    /// Registry containing every struct in a blob written by
+    /// [`Parser::to_binary`] or `StructDef::to_binary`.
+    pub fn from_binary(bytes: &[u8]) -> Result<Parser, Error> {
+        let mut parser = Parser::default();
+        for def in binary::decode(bytes)? {
+            parser.structs.insert(def.id(), def);
+        }
+        Ok(parser)
+    }
+
+    /// Cheap estimate of how much work parsing `input` will be, without
+    /// parsing it.
+    pub fn estimate_complexity(input: &str) -> ComplexityEstimate {
+        complexity::estimate(input)
+    }
+
+    /// Human-readable listing of everything registered, for debugging schemas
+    /// that fail to resolve. Unlike `Display` on a `StructDef` this isn't valid
+    /// schema text; it includes IDs, what each alias resolves to, and flags
+    /// aliases whose target can't be resolved.
+    pub fn debug_dump(&self) -> String {
+        use core::fmt::Write;
+
+        let mut structs = self.structs.values().collect::<Vec<_>>();
+        structs.sort_by(|a, b| a.type_name().cmp(b.type_name()));
+        let mut aliases = self.aliases.iter().collect::<Vec<_>>();
+        aliases.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut dump = String::new();
+        writeln!(dump, "structs ({}):", structs.len()).unwrap();
+        for def in structs {
+            let id = def
+                .id()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            writeln!(dump, "  {} [id {}]", def.type_name(), id).unwrap();
+            for field in def.fields() {
+                writeln!(dump, "    {}", field).unwrap();
+            }
+        }
+
+        writeln!(dump, "aliases ({}):", aliases.len()).unwrap();
+        for (name, type_) in aliases {
+            match type_.resolve(self, None) {
+                Ok(t) => writeln!(dump, "  {} = {} => {}", name, type_, t),
+                Err(e) => writeln!(dump, "  {} = {} => UNRESOLVED ({})", name, type_, e),
+            }
+            .unwrap();
+        }
+
+        dump
+    }
+
     pub fn json_str<'a>(
         &'a self,
         file_json_contents: &str,
         def: &'a Arc<StructDef>,
-    ) -> Result<Object, Error<'a>> {
+    ) -> Result<Object, Error> {
         let json: SerdeValue =
             serde_json::from_str(file_json_contents).map_err(|_| Error::InvalidJson)?;
 
@@ -67,32 +368,288 @@ impl Parser {
     }
 }
 
+/// A message read by [`Parser::parse_self_describing`], along with the
+/// struct definition that came with it.
+#[derive(Debug, Clone)]
+pub struct SelfDescribing {
+    def: Arc<StructDef>,
+    /// Starts with the ID of `def`, but is otherwise unchecked.
+    message: Vec<u8>,
+}
+
+impl SelfDescribing {
+    pub fn def(&self) -> &Arc<StructDef> {
+        &self.def
+    }
+
+    /// Decodes the message against [`def`](Self::def).
+    pub fn object(&self) -> Result<Object, Error> {
+        self.def.decode(&self.message)
+    }
+}
+
+/// Byte range `start..end` of a schema source that an error points at, for
+/// underlining it in an editor. Errors about a definition have an
+/// `Option<Span>`, `None` when the definition didn't come from a source, as
+/// with [`StructDefBuilder`] or a decoded schema blob.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(ThisError, Debug, PartialEq)]
-pub enum Error<'i> {
+pub enum Error {
     #[error("ID not found: {:?}", [..])]
     MissingId(Id),
-    #[error("Could not parse definition: {0}")]
-    DefinitionParsing(nom::Err<nom::error::Error<&'i str>>),
     #[error("Could not parse value: {0}")]
-    ValueParsing(nom::Err<nom::error::Error<&'i [u8]>>),
-    #[error("Could not find type: {0}")]
-    UnresolvedType(String),
-    #[error("Duplicate field: {0}")]
-    DuplicateField(String),
-    #[error("Struct already defined: {0}")]
-    DuplicateStructDef(String),
-    #[error("Unknown type: {0}")]
-    UnrecognizedType(String),
+    ValueParsing(#[source] nom::Err<nom::error::Error<Vec<u8>>>),
+    #[error("Syntax error at line {line}, column {column} near `{token}`")]
+    Syntax {
+        line: usize,
+        column: usize,
+        token: String,
+        span: Span,
+    },
+    /// The span runs from the opening `/*` to the end of the source.
+    #[error("Unterminated block comment starting at line {line}, column {column}")]
+    UnterminatedComment {
+        line: usize,
+        column: usize,
+        span: Span,
+    },
+    #[error("Map keys must be bool, integer or string, not {key}")]
+    InvalidMapKey { key: String, span: Option<Span> },
+    /// Map entries are written in increasing order of their encoded keys,
+    /// each key once, so that equal maps have the same bytes.
+    #[error("Map entry {index} has a key out of order or repeated")]
+    UnorderedMapKey { index: usize },
+    #[error("Array of {len} items is longer than the maximum of {max}")]
+    ArrayTooLong {
+        len: usize,
+        max: usize,
+        span: Option<Span>,
+    },
+    /// Every item of an `Array` must take at least a byte, or the array
+    /// carries nothing but its length.
+    #[error("Array items take no bytes: {item}")]
+    ZeroWidthArrayItem { item: String, span: Option<Span> },
+    /// The span is the reference that closes the cycle.
+    #[error("Struct contains itself without a List, Map or Optional in between: {path}")]
+    RecursiveType { path: String, span: Option<Span> },
+    #[error("Could not find type: {name}")]
+    UnresolvedType { name: String, span: Option<Span> },
+    #[error("Unknown generic type: {name}")]
+    UnknownGeneric { name: String, span: Option<Span> },
+    #[error("{generic} takes {expected} type arguments, found {found}")]
+    WrongGenericArity {
+        generic: String,
+        expected: usize,
+        found: usize,
+        span: Option<Span>,
+    },
+    /// The span is the second field of that name, or the include it came
+    /// from.
+    #[error("Duplicate field: {name}")]
+    DuplicateField { name: String, span: Option<Span> },
+    /// An enum, or a struct that refers back to the one including it.
+    #[error("Include target {name} is not a struct")]
+    InvalidInclude { name: String, span: Option<Span> },
+    /// The span is the second definition of the name.
+    #[error("Struct already defined: {name}")]
+    DuplicateStruct { name: String, span: Option<Span> },
+    #[error("Enum already defined: {name}")]
+    DuplicateEnum { name: String, span: Option<Span> },
+    #[error("Unknown type: {name}")]
+    UnrecognizedType { name: String, span: Option<Span> },
+    #[error("No source for included file {0}")]
+    UnresolvedInclude(String),
+    #[error("Circular include: {0}")]
+    CircularInclude(String),
+    /// Counting generics, inline structs and structs whose fields refer to
+    /// further structs still being compiled.
+    #[error("Types nest more than {max} deep")]
+    TypeTooDeep { max: usize, span: Option<Span> },
+    #[error("Identifiers must start with a letter or underscore: {name}")]
+    InvalidIdentifier { name: String, span: Span },
+    #[error("Input ended while reading {field}")]
+    UnexpectedEof { field: String },
+    #[error("I/O error: {0:?}")]
+    Io(std::io::ErrorKind),
+    #[error("Varint is overlong or doesn't fit in {bits} bits")]
+    InvalidVarint { bits: u32 },
+    #[error("Frame of {len} bytes is larger than the maximum of {max}")]
+    FrameTooLarge { len: usize, max: usize },
+    /// Only an inline struct that no definition names has a span, that of
+    /// the struct.
+    #[error("Names must not be empty")]
+    EmptyName { span: Option<Span> },
+    #[error("{name} is reserved and can't be used as a name")]
+    ReservedName { name: String, span: Option<Span> },
+    #[error("Unknown field attribute: {name}")]
+    UnknownAttribute { name: String, span: Option<Span> },
+    #[error("Invalid field attribute: {detail}")]
+    InvalidAttribute { detail: String, span: Option<Span> },
+    #[error("Constraint violated on {field}: {detail}")]
+    ConstraintViolation { field: String, detail: String },
+    /// The span is the use of the alias that expanded too deeply.
+    #[error("Alias expands too deeply, likely a cycle: {name}")]
+    RecursiveAlias { name: String, span: Option<Span> },
+    #[error("Alias defined more than once: {name}")]
+    DuplicateAlias { name: String, span: Option<Span> },
+    #[error("Alias would shadow the built-in type {name}")]
+    ShadowsBuiltinType { name: String, span: Option<Span> },
+    #[error("Embedded schema is too large: {0} bytes")]
+    EmbeddedSchemaTooLarge(usize),
+    #[error("Expected {expected} fields, found {found}")]
+    FieldCountMismatch { expected: usize, found: usize },
+    #[error("Bad schema blob: {0}")]
+    BadSchemaBlob(String),
+    #[error("Could not decode {field}: {error}")]
+    FieldDecoding {
+        field: String,
+        #[source]
+        error: Box<Error>,
+    },
+    #[error("Nesting exceeds the maximum decode depth")]
+    MaxDepthExceeded,
+    #[error("{enum_name} has no variant with value {value}")]
+    UnknownEnumVariant { enum_name: String, value: u32 },
+    #[error("Duplicate enum variant: {name}")]
+    DuplicateVariant { name: String, span: Option<Span> },
+    #[error("{variant} reuses discriminant {value}")]
+    DuplicateDiscriminant {
+        variant: String,
+        value: u32,
+        span: Option<Span>,
+    },
+    #[error("Discriminant of {variant} does not fit in a u32")]
+    InvalidDiscriminant { variant: String, span: Option<Span> },
+    #[error("No field named {0}")]
+    UnknownField(String),
+    #[error("Missing field: {0}")]
+    MissingField(String),
+    /// A Rust struct doesn't match the schema it is encoded against, see
+    /// [`SierEncode`].
+    #[error("Rust struct does not match schema: {0}")]
+    SchemaMismatch(String),
+    #[error("{field} is not a {requested}")]
+    WrongFieldType {
+        field: String,
+        requested: &'static str,
+    },
+    /// A length prefix claimed more bytes than the buffer has left.
+    #[error("Length prefix of {claimed} bytes, but only {remaining} remain")]
+    LengthExceedsBuffer { claimed: usize, remaining: usize },
     #[error("Too few bytes")]
     TooFewBytes,
-    #[error("Too many bytes")]
-    TooManyBytes,
-    #[error("Invalid UTF8")]
-    InvalidUtf8(#[from] std::str::Utf8Error),
+    #[error("{0} bytes left over after a complete message")]
+    TrailingBytes(usize),
+    /// `offset` is where the first invalid sequence starts, counted from
+    /// the start of the string.
+    #[error("Invalid UTF-8 at byte {offset} of a string")]
+    InvalidUtf8 { offset: usize },
     #[error("Invalid JSON")]
     InvalidJson,
 }
 
+impl Error {
+    /// Where in the schema source the error is, if it is about a definition
+    /// that came from one.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Syntax { span, .. }
+            | Error::UnterminatedComment { span, .. }
+            | Error::InvalidIdentifier { span, .. } => Some(*span),
+            Error::RecursiveType { span, .. }
+            | Error::UnknownGeneric { span, .. }
+            | Error::WrongGenericArity { span, .. }
+            | Error::DuplicateField { span, .. }
+            | Error::InvalidInclude { span, .. }
+            | Error::UnrecognizedType { span, .. }
+            | Error::ReservedName { span, .. }
+            | Error::InvalidAttribute { span, .. }
+            | Error::TypeTooDeep { span, .. }
+            | Error::InvalidMapKey { span, .. }
+            | Error::ArrayTooLong { span, .. }
+            | Error::ZeroWidthArrayItem { span, .. }
+            | Error::UnresolvedType { span, .. }
+            | Error::DuplicateStruct { span, .. }
+            | Error::DuplicateEnum { span, .. }
+            | Error::EmptyName { span }
+            | Error::UnknownAttribute { span, .. }
+            | Error::RecursiveAlias { span, .. }
+            | Error::DuplicateAlias { span, .. }
+            | Error::ShadowsBuiltinType { span, .. }
+            | Error::DuplicateVariant { span, .. }
+            | Error::DuplicateDiscriminant { span, .. }
+            | Error::InvalidDiscriminant { span, .. } => *span,
+            _ => None,
+        }
+    }
+
+    /// Points the error at `span` unless it already points somewhere.
+    pub(crate) fn or_at(mut self, span: Option<Span>) -> Error {
+        if let Some(s) = self.optional_span_mut() {
+            *s = s.or(span);
+        }
+        self
+    }
+
+    /// Points the error at `span` wherever it pointed before.
+    pub(crate) fn at(mut self, span: Option<Span>) -> Error {
+        if let Some(s) = self.optional_span_mut() {
+            *s = span;
+        }
+        self
+    }
+
+    fn optional_span_mut(&mut self) -> Option<&mut Option<Span>> {
+        match self {
+            Error::RecursiveType { span, .. }
+            | Error::UnknownGeneric { span, .. }
+            | Error::WrongGenericArity { span, .. }
+            | Error::DuplicateField { span, .. }
+            | Error::InvalidInclude { span, .. }
+            | Error::UnrecognizedType { span, .. }
+            | Error::ReservedName { span, .. }
+            | Error::InvalidAttribute { span, .. }
+            | Error::TypeTooDeep { span, .. }
+            | Error::InvalidMapKey { span, .. }
+            | Error::ArrayTooLong { span, .. }
+            | Error::ZeroWidthArrayItem { span, .. }
+            | Error::UnresolvedType { span, .. }
+            | Error::DuplicateStruct { span, .. }
+            | Error::DuplicateEnum { span, .. }
+            | Error::EmptyName { span }
+            | Error::UnknownAttribute { span, .. }
+            | Error::RecursiveAlias { span, .. }
+            | Error::DuplicateAlias { span, .. }
+            | Error::ShadowsBuiltinType { span, .. }
+            | Error::DuplicateVariant { span, .. }
+            | Error::DuplicateDiscriminant { span, .. }
+            | Error::InvalidDiscriminant { span, .. } => Some(span),
+            _ => None,
+        }
+    }
+}
+
+// nom errors keep a copy of the input from where parsing failed, so that the
+// error can outlive the input.
+impl From<nom::Err<nom::error::Error<&[u8]>>> for Error {
+    fn from(e: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+        Error::ValueParsing(e.to_owned())
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Error::InvalidUtf8 {
+            offset: e.valid_up_to(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -111,10 +668,92 @@ mod tests {
         let result = parser.add_file_defs(DUPLICATE_STRUCT);
         assert_eq!(
             result.unwrap_err(),
-            Error::DuplicateStructDef("Foo".to_string())
+            Error::DuplicateStruct {
+                name: "Foo".to_string(),
+                span: Some(Span { start: 53, end: 56 }),
+            }
+        );
+    }
+
+    #[test]
+    fn duplicate_struct_name() {
+        let mut parser = Parser::from_schema("struct Foo { foo :u8; }").unwrap();
+        assert_eq!(
+            parser.add_file_defs("struct Foo { foo :u16; }"),
+            Err(Error::DuplicateStruct {
+                name: "Foo".to_string(),
+                span: Some(Span { start: 7, end: 10 }),
+            })
+        );
+        assert_eq!(
+            parser.get("Foo").unwrap().field(0).unwrap().type_(),
+            &Type::U8
+        );
+    }
+
+    #[test]
+    fn duplicate_enum_name() {
+        let mut parser = Parser::from_schema("enum Kind { A; }").unwrap();
+        assert_eq!(
+            parser.add_file_defs("enum Kind { B; }"),
+            Err(Error::DuplicateEnum {
+                name: "Kind".to_string(),
+                span: Some(Span { start: 5, end: 9 }),
+            })
+        );
+        assert!(parser.enum_def("Kind").unwrap().value("A").is_some());
+
+        assert_eq!(
+            parser.add_file_defs("struct Kind { a :u8; }"),
+            Err(Error::DuplicateEnum {
+                name: "Kind".to_string(),
+                span: Some(Span { start: 7, end: 11 }),
+            })
+        );
+        assert!(parser.struct_def("Kind").is_none());
+        assert_eq!(
+            Parser::from_schema("struct Kind { a :u8; } enum Kind { A; }").unwrap_err(),
+            Error::DuplicateStruct {
+                name: "Kind".to_string(),
+                span: Some(Span { start: 28, end: 32 }),
+            }
+        );
+        assert_eq!(
+            Parser::from_schema("enum Kind { A; } enum Kind { B; }").unwrap_err(),
+            Error::DuplicateEnum {
+                name: "Kind".to_string(),
+                span: Some(Span { start: 22, end: 26 }),
+            }
         );
     }
 
+    #[test]
+    fn from_schema() {
+        let parser = Parser::from_schema(
+            r#"
+            struct Header { seq :u32; }
+            struct Packet { header :Header; body :bytes; }
+            "#,
+        )
+        .unwrap();
+
+        let header = parser.get("Header").unwrap();
+        let packet = parser.get("Packet").unwrap();
+        assert_eq!(header.type_name(), "Header");
+        assert_eq!(packet.type_name(), "Packet");
+        assert_eq!(packet.fields().len(), 2);
+        assert!(parser.get("Missing").is_none());
+    }
+
+    #[test]
+    fn no_definitions() {
+        for input in &["", "   ", "\n\n\t\n"] {
+            let mut parser = Parser::default();
+            assert_eq!(parser.add_file_defs(input), Ok(()));
+            assert!(parser.structs.is_empty());
+        }
+    }
+
     const UNDECLARED_STRUCT: &'static str = r#"
     struct Foo {
         foo :Bar;
@@ -126,7 +765,392 @@ mod tests {
         let result = parser.add_file_defs(UNDECLARED_STRUCT);
         assert_eq!(
             result.unwrap_err(),
-            Error::UnrecognizedType("Bar".to_string())
+            Error::UnrecognizedType {
+                name: "Bar".to_string(),
+                span: Some(Span { start: 31, end: 34 }),
+            }
+        );
+    }
+
+    const ALIASED_FIELD: &'static str = r#"
+    type Blob = List<u8>;
+    type Hash = Blob;
+
+    struct Foo {
+        foo :Hash;
+    }
+    "#;
+    #[test]
+    fn aliased_field() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(ALIASED_FIELD).unwrap();
+
+        let def = parser.struct_def("Foo").unwrap();
+        assert_eq!(def.fields()[0].type_(), &Type::List(Box::new(Type::U8)));
+    }
+
+    #[test]
+    fn canonicalize() {
+        let a = "struct Foo {}   struct   Bar  { bar :List<u8>;baz :Foo ; }";
+        let b = r#"
+        type Bytes = List<u8>;
+
+        struct Foo {}
+
+        struct Bar {
+            bar :Bytes;
+            baz :Foo;
+        }
+        "#;
+
+        let canonical = Parser::canonicalize(a).unwrap();
+        assert_eq!(canonical, Parser::canonicalize(b).unwrap());
+        assert_eq!(
+            canonical,
+            "struct Foo {}\n\nstruct Bar {\n    bar :List<u8>;\n    baz :Foo;\n}\n"
+        );
+    }
+
+    #[test]
+    fn canonicalize_inline_structs() {
+        let source = "type Point = { x :i32; }; struct M { a :{ x :u8 @range(0, 9); }; p :Point; }";
+        let canonical = Parser::canonicalize(source).unwrap();
+        assert_eq!(
+            canonical,
+            "struct M_a {\n    x :u8 @range(0, 9);\n}\n\n\
+             struct Point {\n    x :i32;\n}\n\n\
+             struct M {\n    a :M_a;\n    p :Point;\n}\n"
+        );
+
+        let original = Parser::from_schema(source).unwrap();
+        let reparsed = Parser::from_schema(&canonical).unwrap();
+        assert_eq!(reparsed.get("M"), original.get("M"));
+        assert_eq!(reparsed.get("M_a"), original.get("M_a"));
+    }
+
+    #[test]
+    fn inline_struct_name_collision() {
+        assert_eq!(
+            Parser::from_schema("struct M { a :{ x :u8; }; } struct M_a { y :u8; }").err(),
+            Some(Error::DuplicateStruct {
+                name: "M_a".to_string(),
+                span: Some(Span { start: 14, end: 24 }),
+            })
+        );
+        assert_eq!(
+            Parser::from_schema("type M_a = { y :u8; }; struct M { a :{ x :u8; }; b :M_a; }").err(),
+            Some(Error::DuplicateStruct {
+                name: "M_a".to_string(),
+                span: Some(Span { start: 11, end: 21 }),
+            })
+        );
+
+        let mut parser = Parser::from_schema("struct M_a { y :u8; }").unwrap();
+        assert_eq!(
+            parser.add_file_defs("struct M { a :{ x :u8; }; }"),
+            Err(Error::DuplicateStruct {
+                name: "M_a".to_string(),
+                span: Some(Span { start: 14, end: 24 }),
+            })
+        );
+    }
+
+    #[test]
+    fn inline_struct_in_alias_registered_once() {
+        let mut parser = Parser::from_schema("type Point = { x :i32; };").unwrap();
+        parser
+            .add_file_defs("struct A { p :Point; q :List<Point>; }")
+            .unwrap();
+        parser.add_file_defs("struct B { p :Point; }").unwrap();
+        assert!(parser.get("Point").is_some());
+    }
+
+    const RENDERED: &'static str = r#"
+    struct Tree {
+        label :string @maxlen(32);
+        children :List<Tree>;
+        parent :Optional<Tree> @ignore_in_hash;
+    }
+
+    struct Everything {
+        nothing :();
+        offset :i16 @range(-100, 100);
+        grid :List<List<u8>>;
+        index :Map<u32, Optional<Tree>>;
+        raw :bytes;
+        root :Tree;
+    }
+    "#;
+    #[test]
+    fn display_round_trips() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(RENDERED).unwrap();
+
+        let rendered = ["Tree", "Everything"]
+            .iter()
+            .map(|name| parser.struct_def(name).unwrap().to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(rendered.contains("grid :List<List<u8>>;"));
+        assert!(rendered.contains("index :Map<u32, Optional<Tree>>;"));
+
+        let mut reparsed = Parser::default();
+        reparsed.add_file_defs(&rendered).unwrap();
+        for name in &["Tree", "Everything"] {
+            assert_eq!(reparsed.struct_def(name), parser.struct_def(name));
+        }
+    }
+
+    #[test]
+    fn enum_display_round_trips() {
+        let mut parser = Parser::default();
+        parser
+            .add_file_defs("enum Status { Active; Closed = 5; Pending; Gone = 2; }")
+            .unwrap();
+        let rendered = parser.enum_def("Status").unwrap().to_string();
+        assert_eq!(
+            rendered,
+            "enum Status {\n    Active;\n    Closed = 5;\n    Pending;\n    Gone = 2;\n}"
+        );
+
+        let mut reparsed = Parser::default();
+        reparsed.add_file_defs(&rendered).unwrap();
+        assert_eq!(reparsed.enum_def("Status"), parser.enum_def("Status"));
+    }
+
+    const DUMPED: &'static str = r#"
+    type Bytes = List<u8>;
+    type Dangling = List<Missing>;
+
+    struct Foo {
+        foo :u8;
+    }
+
+    struct Bar {
+        bar :Foo;
+        baz :Bytes;
+    }
+    "#;
+    #[test]
+    fn debug_dump() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(DUMPED).unwrap();
+
+        let dump = parser.debug_dump();
+        assert!(dump.contains("structs (2):"));
+        assert!(dump.contains("  Foo [id "));
+        assert!(dump.contains("    foo :u8;"));
+        assert!(dump.contains("  Bar [id "));
+        assert!(dump.contains("    bar :Foo;"));
+        assert!(dump.contains("    baz :List<u8>;"));
+        assert!(dump.contains("  Bytes = List<u8> => List<u8>"));
+        assert!(dump.contains("  Dangling = List<Missing> => UNRESOLVED (Unknown type: Missing)"));
+    }
+
+    const INCLUDES: &'static str = r#"
+    struct Header {
+        seq :u32;
+        time :u64 @ignore_in_hash;
+    }
+
+    struct Message includes Header {
+        body :string;
+    }
+    "#;
+    #[test]
+    fn includes() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(INCLUDES).unwrap();
+
+        let header = parser.struct_def("Header").unwrap();
+        let message = parser.struct_def("Message").unwrap();
+        assert_eq!(message.fields()[..2], header.fields()[..]);
+        assert_eq!(message.fields()[2].name(), "body");
+        assert_eq!(message.fields().len(), 3);
+    }
+
+    #[test]
+    fn includes_collision() {
+        let mut parser = Parser::default();
+        let result = parser.add_file_defs(
+            "struct Header { seq :u32; } struct Message includes Header { seq :u64; }",
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            Error::DuplicateField {
+                name: "seq".to_string(),
+                span: Some(Span { start: 61, end: 64 }),
+            }
+        );
+    }
+
+    const CYCLIC_ALIAS: &'static str = r#"
+    type A = B;
+    type B = A;
+
+    struct Foo {
+        foo :A;
+    }
+    "#;
+    #[test]
+    fn cyclic_alias() {
+        let mut parser = Parser::default();
+        let result = parser.add_file_defs(CYCLIC_ALIAS);
+        let error = result.unwrap_err();
+        assert!(matches!(error, Error::RecursiveAlias { .. }));
+        // The use that went too deep, in `B`.
+        let start = CYCLIC_ALIAS.find("= A;").unwrap() + 2;
+        assert_eq!(
+            error.span(),
+            Some(Span {
+                start,
+                end: start + 1
+            })
+        );
+    }
+
+    #[test]
+    fn parse_all() {
+        let mut parser = Parser::default();
+        let structs = parser
+            .parse_all(
+                "struct A { a :u8; } struct B { a :A; } type C = B; struct D { c :List<C>; }",
+            )
+            .unwrap();
+
+        let names = structs.iter().map(|s| s.type_name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["A", "B", "D"]);
+        for def in &structs {
+            assert_eq!(parser.struct_def(def.type_name()), Some(def));
+        }
+    }
+
+    #[test]
+    fn parse_all_rejects_trailing_input() {
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.parse_all("struct A { a :u8; }\n}"),
+            Err(Error::Syntax {
+                line: 2,
+                column: 1,
+                token: "}".to_string(),
+                span: Span { start: 20, end: 21 },
+            })
+        );
+    }
+
+    fn files(files: &[(&'static str, &'static str)]) -> HashMap<&'static str, &'static str> {
+        files.iter().copied().collect()
+    }
+
+    #[test]
+    fn include_resolves_types() {
+        let files = files(&[
+            (
+                "main.sier",
+                r#"
+                include "common.sier";
+
+                struct Message {
+                    header :Header;
+                    body :string;
+                }
+                "#,
+            ),
+            ("common.sier", "struct Header { id :u64; }"),
+        ]);
+
+        let mut parser = Parser::default();
+        parser
+            .add_file_with_includes("main.sier", |path| files.get(path).copied())
+            .unwrap();
+
+        let header = parser.struct_def("Header").unwrap();
+        let message = parser.struct_def("Message").unwrap();
+        assert_eq!(
+            message.fields()[0].type_(),
+            &Type::Struct(Arc::clone(header))
+        );
+    }
+
+    #[test]
+    fn shared_include_is_added_once() {
+        let files = files(&[
+            ("main.sier", r#"include "a.sier"; include "b.sier";"#),
+            (
+                "a.sier",
+                r#"include "common.sier"; struct A { h :Header; }"#,
+            ),
+            (
+                "b.sier",
+                r#"include "common.sier"; struct B { h :Header; }"#,
+            ),
+            ("common.sier", "struct Header { id :u64; }"),
+        ]);
+
+        let mut parser = Parser::default();
+        parser
+            .add_file_with_includes("main.sier", |path| files.get(path).copied())
+            .unwrap();
+        assert!(parser.struct_def("A").is_some());
+        assert!(parser.struct_def("B").is_some());
+    }
+
+    #[test]
+    fn circular_include() {
+        let files = files(&[
+            ("main.sier", r#"include "a.sier";"#),
+            ("a.sier", r#"include "b.sier"; struct A { id :u8; }"#),
+            ("b.sier", r#"include "a.sier"; struct B { id :u8; }"#),
+        ]);
+
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.add_file_with_includes("main.sier", |path| files.get(path).copied()),
+            Err(Error::CircularInclude(
+                "a.sier -> b.sier -> a.sier".to_string()
+            ))
         );
+
+        let files = self::files(&[("self.sier", r#"include "self.sier";"#)]);
+        assert_eq!(
+            parser.add_file_with_includes("self.sier", |path| files.get(path).copied()),
+            Err(Error::CircularInclude("self.sier -> self.sier".to_string()))
+        );
+    }
+
+    #[test]
+    fn unresolved_include() {
+        let files = files(&[("main.sier", r#"include "missing.sier";"#)]);
+
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.add_file_with_includes("main.sier", |path| files.get(path).copied()),
+            Err(Error::UnresolvedInclude("missing.sier".to_string()))
+        );
+        assert_eq!(
+            parser.add_file_defs(files["main.sier"]),
+            Err(Error::UnresolvedInclude("missing.sier".to_string()))
+        );
+    }
+    #[test]
+    fn errors_outlive_their_input() {
+        fn load(parser: &mut Parser) -> Result<(), Box<dyn std::error::Error>> {
+            let source = String::from("struct Foo { a :u32 }");
+            parser.add_file_defs(&source)?;
+            Ok(())
+        }
+
+        let error = load(&mut Parser::default()).unwrap_err();
+        assert!(error.downcast_ref::<Error>().is_some());
+        assert!(!error.to_string().is_empty());
+
+        let mut parser = Parser::default();
+        parser.add_file_defs("struct Foo { a :u32; }").unwrap();
+        let mut bytes = parser.struct_def("Foo").unwrap().id().to_vec();
+        bytes.extend_from_slice(&[0, 0]);
+        let error = parser.parse(&bytes).unwrap_err();
+        drop(bytes);
+        assert!(std::error::Error::source(&error).is_some());
     }
 }
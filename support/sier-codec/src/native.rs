@@ -0,0 +1,287 @@
+//! Encoding native Rust structs, usually through `#[derive(SierEncode,
+//! SierDecode)]` with the `derive` feature.
+//!
+//! A struct is encoded against a [`StructDef`] that has the same fields, in
+//! the same order, with the types [`SierValue::sier_type`] gives for the
+//! Rust field types.
+
+use core::convert::TryInto;
+use std::collections::BTreeMap;
+
+use crate::{Error, Object, StructDef, Type, Value};
+
+/// A Rust type with a fixed sier [`Type`], usable as a field of a derived
+/// struct.
+pub trait SierValue: Sized {
+    fn sier_type() -> Type;
+
+    fn to_value<'s>(&self) -> Value<'s>;
+
+    /// `None` if `value` isn't of [`SierValue::sier_type`].
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+pub trait SierEncode {
+    /// The message for `self` as an object of `def`, ID included, as
+    /// [`Object::serialize`] would produce it.
+    fn encode(&self, def: &StructDef) -> Result<Vec<u8>, Error>;
+}
+
+pub trait SierDecode: Sized {
+    /// Decodes a whole message of `def`, see [`StructDef::decode`].
+    fn decode(def: &StructDef, bytes: &[u8]) -> Result<Self, Error>;
+}
+
+/// Fails with [`Error::SchemaMismatch`] unless `def` has exactly `fields`, in
+/// order.
+pub fn check_fields(def: &StructDef, fields: &[(&str, Type)]) -> Result<(), Error> {
+    let mismatch =
+        |detail: String| Error::SchemaMismatch(format!("{}: {}", def.type_name(), detail));
+
+    if def.fields().len() != fields.len() {
+        return Err(mismatch(format!(
+            "schema has {} fields, Rust struct has {}",
+            def.fields().len(),
+            fields.len()
+        )));
+    }
+    for (field, (name, type_)) in def.fields().iter().zip(fields) {
+        if field.name() != *name {
+            return Err(mismatch(format!(
+                "schema has field {}, Rust struct has {}",
+                field.name(),
+                name
+            )));
+        }
+        if field.type_() != type_ {
+            return Err(mismatch(format!(
+                "{} is {} in the schema, {} in the Rust struct",
+                name,
+                field.type_(),
+                type_
+            )));
+        }
+    }
+    Ok(())
+}
+
+pub fn field<T: SierValue>(obj: &Object, name: &str) -> Result<T, Error> {
+    let value = obj
+        .get(name)
+        .ok_or_else(|| Error::UnknownField(name.to_string()))?;
+    T::from_value(value).ok_or_else(|| {
+        Error::SchemaMismatch(format!(
+            "{}: {} is not a {}",
+            obj.schema().type_name(),
+            name,
+            T::sier_type()
+        ))
+    })
+}
+
+macro_rules! primitive {
+    ($($rust:ty => $variant:ident, $as:ident;)*) => {
+        $(
+            impl SierValue for $rust {
+                fn sier_type() -> Type {
+                    Type::$variant
+                }
+
+                fn to_value<'s>(&self) -> Value<'s> {
+                    Value::$variant(*self)
+                }
+
+                fn from_value(value: &Value) -> Option<Self> {
+                    value.$as()
+                }
+            }
+        )*
+    };
+}
+
+primitive! {
+    bool => Bool, as_bool;
+    u8 => U8, as_u8;
+    u16 => U16, as_u16;
+    u32 => U32, as_u32;
+    u64 => U64, as_u64;
+    i8 => I8, as_i8;
+    i16 => I16, as_i16;
+    i32 => I32, as_i32;
+    i64 => I64, as_i64;
+    f32 => F32, as_f32;
+    f64 => F64, as_f64;
+}
+
+impl SierValue for String {
+    fn sier_type() -> Type {
+        Type::String
+    }
+
+    fn to_value<'s>(&self) -> Value<'s> {
+        Value::String(self.clone())
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        value.as_string().map(String::from)
+    }
+}
+
+impl SierValue for () {
+    fn sier_type() -> Type {
+        Type::Unit
+    }
+
+    fn to_value<'s>(&self) -> Value<'s> {
+        Value::Unit
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        value.as_unit()
+    }
+}
+
+/// Vectors are lists, including `Vec<u8>`; there is no native type for
+/// `bytes`.
+impl<T: SierValue> SierValue for Vec<T> {
+    fn sier_type() -> Type {
+        Type::List(Box::new(T::sier_type()))
+    }
+
+    fn to_value<'s>(&self) -> Value<'s> {
+        Value::List(self.iter().map(T::to_value).collect())
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::List(items) => items.iter().map(T::from_value).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: SierValue, const N: usize> SierValue for [T; N] {
+    fn sier_type() -> Type {
+        Type::Array(Box::new(T::sier_type()), N)
+    }
+
+    fn to_value<'s>(&self) -> Value<'s> {
+        Value::Array(self.iter().map(T::to_value).collect())
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Array(items) => items
+                .iter()
+                .map(T::from_value)
+                .collect::<Option<Vec<_>>>()?
+                .try_into()
+                .ok(),
+            _ => None,
+        }
+    }
+}
+
+impl<K: SierValue + Ord, V: SierValue> SierValue for BTreeMap<K, V> {
+    fn sier_type() -> Type {
+        Type::Map(Box::new(K::sier_type()), Box::new(V::sier_type()))
+    }
+
+    fn to_value<'s>(&self) -> Value<'s> {
+        Value::Map(
+            self.iter()
+                .map(|(k, v)| (k.to_value(), v.to_value()))
+                .collect(),
+        )
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        value
+            .as_map()?
+            .iter()
+            .map(|(k, v)| Some((K::from_value(k)?, V::from_value(v)?)))
+            .collect()
+    }
+}
+
+impl<T: SierValue> SierValue for Option<T> {
+    fn sier_type() -> Type {
+        Type::Optional(Box::new(T::sier_type()))
+    }
+
+    fn to_value<'s>(&self) -> Value<'s> {
+        Value::Optional(self.as_ref().map(|v| Box::new(v.to_value())))
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value.as_optional()? {
+            Some(v) => T::from_value(v).map(Some),
+            None => Some(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Parser;
+
+    fn roundtrip<T: SierValue + PartialEq + core::fmt::Debug>(value: T) {
+        let v = value.to_value();
+        assert!(v.assignable(&T::sier_type()).is_ok());
+        assert_eq!(T::from_value(&v), Some(value));
+    }
+
+    #[test]
+    fn values_roundtrip() {
+        roundtrip(true);
+        roundtrip(-7i64);
+        roundtrip(String::from("seven"));
+        roundtrip(vec![Some(1u8), None]);
+        roundtrip([1u16, 2, 3]);
+        roundtrip(
+            vec![(1u32, String::from("one"))]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>(),
+        );
+    }
+
+    #[test]
+    fn from_value_checks_type() {
+        assert_eq!(u8::from_value(&Value::U16(1)), None);
+        assert_eq!(
+            <[u8; 2]>::from_value(&Value::Array(vec![Value::U8(1)])),
+            None
+        );
+    }
+
+    #[test]
+    fn check_fields_mismatch() {
+        let parser = Parser::from_schema("struct Foo { a :u8; b :string; }").unwrap();
+        let def = parser.get("Foo").unwrap();
+
+        assert_eq!(
+            check_fields(def, &[("a", Type::U8), ("b", Type::String)]),
+            Ok(())
+        );
+        assert_eq!(
+            check_fields(def, &[("a", Type::U8)]),
+            Err(Error::SchemaMismatch(
+                "Foo: schema has 2 fields, Rust struct has 1".to_string()
+            ))
+        );
+        assert_eq!(
+            check_fields(def, &[("a", Type::U8), ("c", Type::String)]),
+            Err(Error::SchemaMismatch(
+                "Foo: schema has field b, Rust struct has c".to_string()
+            ))
+        );
+        assert_eq!(
+            check_fields(def, &[("a", Type::U16), ("b", Type::String)]),
+            Err(Error::SchemaMismatch(
+                "Foo: a is u8 in the schema, u16 in the Rust struct".to_string()
+            ))
+        );
+    }
+}
@@ -1,6 +1,14 @@
-use core::ops::Index;
+use blake2::{Blake2b512, Digest};
+use core::{convert::TryInto, ops::Index};
+use std::collections::{BTreeMap, HashSet};
 
-use crate::schema::{StructDef, Type};
+use std::sync::Arc;
+
+use crate::{
+    json,
+    schema::{EnumDef, FieldDef, StructDef, Type},
+    EncodeOptions, Error,
+};
 
 #[derive(Debug, PartialEq)]
 pub struct Object<'s> {
@@ -17,19 +25,232 @@ impl<'s> Object<'s> {
         self.schema
     }
 
+    /// The value of `field_name`, or `None` if there is no such field.
+    pub fn get(&self, field_name: &str) -> Option<&Value<'s>> {
+        let index = self.schema.field_index(field_name)?;
+        self.values.get(index)
+    }
+
+    /// Typed access to a field. These fail with [`Error::UnknownField`] if
+    /// there is no such field and [`Error::WrongFieldType`] if it holds some
+    /// other type.
+    fn get_as<'a, T>(
+        &'a self,
+        field_name: &str,
+        requested: &'static str,
+        as_type: impl FnOnce(&'a Value<'s>) -> Option<T>,
+    ) -> Result<T, Error> {
+        let value = self
+            .get(field_name)
+            .ok_or_else(|| Error::UnknownField(field_name.to_string()))?;
+        as_type(value).ok_or_else(|| Error::WrongFieldType {
+            field: format!("{}.{}", self.schema.type_name(), field_name),
+            requested,
+        })
+    }
+
+    pub fn get_bool(&self, field_name: &str) -> Result<bool, Error> {
+        self.get_as(field_name, "bool", Value::as_bool)
+    }
+
+    pub fn get_u8(&self, field_name: &str) -> Result<u8, Error> {
+        self.get_as(field_name, "u8", Value::as_u8)
+    }
+
+    pub fn get_u16(&self, field_name: &str) -> Result<u16, Error> {
+        self.get_as(field_name, "u16", Value::as_u16)
+    }
+
+    pub fn get_u32(&self, field_name: &str) -> Result<u32, Error> {
+        self.get_as(field_name, "u32", Value::as_u32)
+    }
+
+    pub fn get_u64(&self, field_name: &str) -> Result<u64, Error> {
+        self.get_as(field_name, "u64", Value::as_u64)
+    }
+
+    pub fn get_i8(&self, field_name: &str) -> Result<i8, Error> {
+        self.get_as(field_name, "i8", Value::as_i8)
+    }
+
+    pub fn get_i16(&self, field_name: &str) -> Result<i16, Error> {
+        self.get_as(field_name, "i16", Value::as_i16)
+    }
+
+    pub fn get_i32(&self, field_name: &str) -> Result<i32, Error> {
+        self.get_as(field_name, "i32", Value::as_i32)
+    }
+
+    pub fn get_i64(&self, field_name: &str) -> Result<i64, Error> {
+        self.get_as(field_name, "i64", Value::as_i64)
+    }
+
+    pub fn get_f32(&self, field_name: &str) -> Result<f32, Error> {
+        self.get_as(field_name, "f32", Value::as_f32)
+    }
+
+    pub fn get_f64(&self, field_name: &str) -> Result<f64, Error> {
+        self.get_as(field_name, "f64", Value::as_f64)
+    }
+
+    pub fn get_string(&self, field_name: &str) -> Result<&str, Error> {
+        self.get_as(field_name, "string", Value::as_string)
+    }
+
+    pub fn get_bytes(&self, field_name: &str) -> Result<&[u8], Error> {
+        self.get_as(field_name, "bytes", Value::as_bytes)
+    }
+
+    pub fn get_list(&self, field_name: &str) -> Result<&[Value], Error> {
+        self.get_as(field_name, "List", Value::as_list)
+    }
+
+    pub fn get_map(&self, field_name: &str) -> Result<&[(Value, Value)], Error> {
+        self.get_as(field_name, "Map", Value::as_map)
+    }
+
+    pub fn get_optional(&self, field_name: &str) -> Result<Option<&Value>, Error> {
+        self.get_as(field_name, "Optional", Value::as_optional)
+    }
+
+    pub fn get_struct(&self, field_name: &str) -> Result<&Object, Error> {
+        self.get_as(field_name, "struct", Value::as_object)
+    }
+
+    /// The name of the variant.
+    pub fn get_enum(&self, field_name: &str) -> Result<&str, Error> {
+        self.get_as(field_name, "enum", Value::as_enum)
+    }
+
+    /// The object as a JSON object keyed by field name. Lists and bytes become
+    /// arrays, enums their variant name, `Optional` null or the value, and
+    /// map keys are stringified. Integers are written as exact JSON numbers,
+    /// but many consumers (JavaScript in particular) read numbers as f64, so
+    /// 64 bit values beyond 2^53 may lose precision on the other end.
+    pub fn to_json(&self) -> Result<serde_json::Value, Error> {
+        json::transform_sier_obj(self)
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(&EncodeOptions::default())
+    }
+
+    pub fn serialize_with(&self, options: &EncodeOptions) -> Vec<u8> {
         let mut result = Vec::new();
         result.extend(self.schema.id());
-        result.extend(self.serialize_as_child());
+        result.extend(self.serialize_as_child(options));
         result
     }
-    fn serialize_as_child(&self) -> Vec<u8> {
+    /// Serializes with the schema source embedded in front, so the message can
+    /// be decoded with [`Parser::parse_self_describing`](crate::Parser::parse_self_describing)
+    /// without knowing the schema ahead of time.
+    pub fn serialize_self_describing(&self) -> Vec<u8> {
+        let source = self.schema.source_with_dependencies();
+
+        let mut result = var_int(source.len());
+        result.extend(source.bytes());
+        result.extend(self.serialize());
+        result
+    }
+
+    fn serialize_as_child(&self, options: &EncodeOptions) -> Vec<u8> {
         let mut result = Vec::new();
-        for value in &self.values {
-            result.extend(value.serialize());
+        if options.field_count_header {
+            result.extend(var_int(self.values.len()));
+        }
+        for (field, value) in self.schema.fields().iter().zip(&self.values) {
+            match value.varint().filter(|_| field.attributes().varint) {
+                Some(bytes) => result.extend(bytes),
+                None => result.extend(value.serialize_with(options)),
+            }
         }
         result
     }
+
+    /// Hash of the object's contents, skipping fields marked `@ignore_in_hash`
+    /// (including in nested structs). Useful for detecting messages that only
+    /// differ in things like timestamps or nonces.
+    pub fn content_hash(&self) -> [u8; 32] {
+        let mut hasher = Blake2b512::new();
+        self.hash_content(&mut hasher);
+
+        let digest = hasher.finalize();
+        digest[0..32]
+            .try_into()
+            .expect("hash should always be > 32 bytes")
+    }
+
+    fn hash_content(&self, hasher: &mut Blake2b512) {
+        hasher.update(self.schema.id());
+        for (field, value) in self.hashed_fields() {
+            hasher.update(field);
+            value.hash_content(hasher);
+        }
+    }
+
+    /// Equality over the same fields as [`Object::content_hash`].
+    pub fn content_eq(&self, other: &Object) -> bool {
+        self.schema == other.schema
+            && self
+                .hashed_fields()
+                .zip(other.hashed_fields())
+                .all(|((_, a), (_, b))| a.content_eq(b))
+    }
+
+    fn hashed_fields(&self) -> impl Iterator<Item = (&str, &Value<'s>)> {
+        self.schema
+            .fields()
+            .iter()
+            .zip(&self.values)
+            .filter(|(field, _)| !field.attributes().ignore_in_hash)
+            .map(|(field, value)| (field.name(), value))
+    }
+}
+
+/// The leading fields of a message that [`StructDef::decode_partial`] got
+/// through before failing. Unlike an [`Object`] it may be missing fields, so
+/// it can't be serialized until [`PartialObject::into_object`] finds them
+/// all there.
+#[derive(Debug, PartialEq)]
+pub struct PartialObject<'s> {
+    pub(crate) schema: &'s StructDef,
+    /// The first `values.len()` fields of `schema`.
+    pub(crate) values: Vec<Value<'s>>,
+}
+
+impl<'s> PartialObject<'s> {
+    pub fn schema(&self) -> &StructDef {
+        self.schema
+    }
+
+    /// The value of `field_name`, or `None` if there is no such field or it
+    /// was not decoded.
+    pub fn get(&self, field_name: &str) -> Option<&Value<'s>> {
+        let index = self.schema.field_index(field_name)?;
+        self.values.get(index)
+    }
+
+    /// The fields that were decoded, in order, along with their values.
+    pub fn decoded(&self) -> impl Iterator<Item = (&FieldDef, &Value<'s>)> {
+        self.schema.fields().iter().zip(&self.values)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.values.len() == self.schema.fields().len()
+    }
+
+    /// The object, if every field was decoded, or else `self` back.
+    pub fn into_object(self) -> Result<Object<'s>, Self> {
+        match self.is_complete() {
+            true => Ok(Object::new(self.schema, self.values)),
+            false => Err(self),
+        }
+    }
+
+    /// Like [`Object::to_json`], with only the fields that were decoded.
+    pub fn to_json(&self) -> Result<serde_json::Value, Error> {
+        json::transform_fields(self.decoded())
+    }
 }
 
 impl<'s> Index<&'_ str> for Object<'s> {
@@ -38,9 +259,7 @@ impl<'s> Index<&'_ str> for Object<'s> {
     fn index(&self, field_name: &str) -> &Self::Output {
         let index = self
             .schema
-            .fields()
-            .iter()
-            .position(|f| f.name() == field_name)
+            .field_index(field_name)
             .unwrap_or_else(|| panic!("no field with name '{}'", field_name));
 
         &self.values[index]
@@ -52,11 +271,25 @@ pub enum Value<'s> {
     Unit,
     Bool(bool),
     U8(u8),
+    U16(u16),
     U32(u32),
     U64(u64),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
     String(String),
+    Bytes(Vec<u8>),
     List(Vec<Value<'s>>),
+    /// Elements of a `Type::Array`, which must have exactly its length.
+    Array(Vec<Value<'s>>),
+    Map(Vec<(Value<'s>, Value<'s>)>),
+    Optional(Option<Box<Value<'s>>>),
     Struct(Object<'s>),
+    /// A variant of the enum, by value. See [`EnumDef::value`].
+    Enum(Arc<EnumDef>, u32),
 }
 
 impl<'s> From<()> for Value<'s> {
@@ -77,6 +310,12 @@ impl<'s> From<u8> for Value<'s> {
     }
 }
 
+impl<'s> From<u16> for Value<'s> {
+    fn from(v: u16) -> Value<'s> {
+        Value::U16(v)
+    }
+}
+
 impl<'s> From<u32> for Value<'s> {
     fn from(v: u32) -> Value<'s> {
         Value::U32(v)
@@ -89,12 +328,54 @@ impl<'s> From<u64> for Value<'s> {
     }
 }
 
+impl<'s> From<i8> for Value<'s> {
+    fn from(v: i8) -> Value<'s> {
+        Value::I8(v)
+    }
+}
+
+impl<'s> From<i16> for Value<'s> {
+    fn from(v: i16) -> Value<'s> {
+        Value::I16(v)
+    }
+}
+
+impl<'s> From<i32> for Value<'s> {
+    fn from(v: i32) -> Value<'s> {
+        Value::I32(v)
+    }
+}
+
+impl<'s> From<i64> for Value<'s> {
+    fn from(v: i64) -> Value<'s> {
+        Value::I64(v)
+    }
+}
+
+impl<'s> From<f32> for Value<'s> {
+    fn from(v: f32) -> Value<'s> {
+        Value::F32(v)
+    }
+}
+
+impl<'s> From<f64> for Value<'s> {
+    fn from(v: f64) -> Value<'s> {
+        Value::F64(v)
+    }
+}
+
 impl<'s> From<String> for Value<'s> {
     fn from(v: String) -> Value<'s> {
         Value::String(v)
     }
 }
 
+impl<'s> From<&[u8]> for Value<'s> {
+    fn from(v: &[u8]) -> Value<'s> {
+        Value::Bytes(v.to_vec())
+    }
+}
+
 impl<'s, T> From<Vec<T>> for Value<'s>
 where
     T: Into<Value<'s>>,
@@ -104,6 +385,39 @@ where
     }
 }
 
+impl<'s, T, const N: usize> From<[T; N]> for Value<'s>
+where
+    T: Into<Value<'s>>,
+{
+    fn from(items: [T; N]) -> Value<'s> {
+        Value::Array(IntoIterator::into_iter(items).map(Into::into).collect())
+    }
+}
+
+impl<'s, K, V> From<BTreeMap<K, V>> for Value<'s>
+where
+    K: Into<Value<'s>>,
+    V: Into<Value<'s>>,
+{
+    fn from(entries: BTreeMap<K, V>) -> Value<'s> {
+        Value::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+impl<'s, T> From<Option<T>> for Value<'s>
+where
+    T: Into<Value<'s>>,
+{
+    fn from(v: Option<T>) -> Value<'s> {
+        Value::Optional(v.map(|v| Box::new(v.into())))
+    }
+}
+
 impl<'s> From<Object<'s>> for Value<'s> {
     fn from(v: Object<'s>) -> Value<'s> {
         Value::Struct(v)
@@ -132,6 +446,13 @@ impl<'s> Value<'s> {
         }
     }
 
+    pub fn as_u16(&self) -> Option<u16> {
+        match self {
+            Value::U16(v) => Some(*v),
+            _ => None,
+        }
+    }
+
     pub fn as_u32(&self) -> Option<u32> {
         match self {
             Value::U32(v) => Some(*v),
@@ -146,6 +467,48 @@ impl<'s> Value<'s> {
         }
     }
 
+    pub fn as_i8(&self) -> Option<i8> {
+        match self {
+            Value::I8(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_i16(&self) -> Option<i16> {
+        match self {
+            Value::I16(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_i32(&self) -> Option<i32> {
+        match self {
+            Value::I32(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::I64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            Value::F32(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::F64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
     pub fn as_string(&self) -> Option<&str> {
         match self {
             Value::String(s) => Some(s),
@@ -153,9 +516,40 @@ impl<'s> Value<'s> {
         }
     }
 
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Items of a list or array.
     pub fn as_list(&self) -> Option<&[Value]> {
         match self {
-            Value::List(items) => Some(items),
+            Value::List(items) | Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&[(Value, Value)]> {
+        match self {
+            Value::Map(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    /// The name of the variant.
+    pub fn as_enum(&self) -> Option<&str> {
+        match self {
+            Value::Enum(def, value) => def.variant_with_value(*value).map(|v| v.name.as_str()),
+            _ => None,
+        }
+    }
+
+    /// `Some(None)` for an absent optional value.
+    pub fn as_optional(&self) -> Option<Option<&Value>> {
+        match self {
+            Value::Optional(v) => Some(v.as_deref()),
             _ => None,
         }
     }
@@ -168,6 +562,16 @@ impl<'s> Value<'s> {
     }
 
     pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(&EncodeOptions::default())
+    }
+
+    pub fn serialize_with(&self, options: &EncodeOptions) -> Vec<u8> {
+        if options.varint_integers {
+            if let Some(bytes) = self.varint() {
+                return bytes;
+            }
+        }
+
         match self {
             Value::Unit => Vec::new(),
             Value::Bool(v) => {
@@ -175,34 +579,182 @@ impl<'s> Value<'s> {
                 Vec::from(byte.to_le_bytes())
             }
             Value::U8(v) => Vec::from(v.to_le_bytes()),
-            Value::U32(v) => Vec::from(v.to_le_bytes()),
-            Value::U64(v) => Vec::from(v.to_le_bytes()),
+            Value::U16(v) => options.endianness.order(&v.to_le_bytes()),
+            Value::U32(v) => options.endianness.order(&v.to_le_bytes()),
+            Value::U64(v) => options.endianness.order(&v.to_le_bytes()),
+            Value::I8(v) => Vec::from(v.to_le_bytes()),
+            Value::I16(v) => options.endianness.order(&v.to_le_bytes()),
+            Value::I32(v) => options.endianness.order(&v.to_le_bytes()),
+            Value::I64(v) => options.endianness.order(&v.to_le_bytes()),
+            Value::F32(v) => options.endianness.order(&v.to_le_bytes()),
+            Value::F64(v) => options.endianness.order(&v.to_le_bytes()),
             Value::String(v) => var_int(v.len()).into_iter().chain(v.bytes()).collect(),
+            Value::Bytes(v) => var_int(v.len())
+                .into_iter()
+                .chain(v.iter().copied())
+                .collect(),
             Value::List(items) => {
-                let item_bytes = items.iter().flat_map(|i| i.serialize()).collect::<Vec<_>>();
+                let item_bytes = items
+                    .iter()
+                    .flat_map(|i| i.serialize_with(options))
+                    .collect::<Vec<_>>();
                 var_int(item_bytes.len())
                     .into_iter()
                     .chain(item_bytes)
                     .collect()
             }
+            Value::Array(items) => items
+                .iter()
+                .flat_map(|i| i.serialize_with(options))
+                .collect(),
+            Value::Map(entries) => {
+                let entry_bytes = sorted_entries(entries, options)
+                    .into_iter()
+                    .flat_map(|(k, v)| k.into_iter().chain(v))
+                    .collect::<Vec<_>>();
+                var_int(entry_bytes.len())
+                    .into_iter()
+                    .chain(entry_bytes)
+                    .collect()
+            }
+
+            Value::Optional(None) => vec![0],
+            Value::Optional(Some(v)) => core::iter::once(1)
+                .chain(v.serialize_with(options))
+                .collect(),
+            Value::Struct(obj) => obj.serialize_as_child(options),
+            Value::Enum(_, v) => options.endianness.order(&v.to_le_bytes()),
+        }
+    }
+
+    pub(crate) fn as_integer(&self) -> Option<i128> {
+        match self {
+            Value::U8(v) => Some((*v).into()),
+            Value::U16(v) => Some((*v).into()),
+            Value::U32(v) => Some((*v).into()),
+            Value::U64(v) => Some((*v).into()),
+            Value::I8(v) => Some((*v).into()),
+            Value::I16(v) => Some((*v).into()),
+            Value::I32(v) => Some((*v).into()),
+            Value::I64(v) => Some((*v).into()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn length(&self) -> Option<usize> {
+        match self {
+            Value::String(s) => Some(s.len()),
+            Value::Bytes(b) => Some(b.len()),
+            Value::List(items) | Value::Array(items) => Some(items.len()),
+            Value::Map(entries) => Some(entries.len()),
+            _ => None,
+        }
+    }
+
+    /// The varint form of a `u32`, `u64`, `i32` or `i64`, see
+    /// [`FieldAttributes::varint`](crate::FieldAttributes::varint).
+    fn varint(&self) -> Option<Vec<u8>> {
+        Some(match self {
+            Value::U32(v) => leb128((*v).into()),
+            Value::U64(v) => leb128(*v),
+            Value::I32(v) => leb128(zigzag((*v).into())),
+            Value::I64(v) => leb128(zigzag(*v)),
+            _ => return None,
+        })
+    }
+
+    fn hash_content(&self, hasher: &mut Blake2b512) {
+        match self {
+            Value::List(items) => {
+                hasher.update(var_int(items.len()));
+                for item in items {
+                    item.hash_content(hasher);
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    item.hash_content(hasher);
+                }
+            }
+            Value::Map(entries) => {
+                hasher.update(var_int(entries.len()));
+                for (k, v) in by_key(entries) {
+                    k.hash_content(hasher);
+                    v.hash_content(hasher);
+                }
+            }
+            Value::Optional(Some(v)) => {
+                hasher.update([1]);
+                v.hash_content(hasher);
+            }
+            Value::Struct(obj) => obj.hash_content(hasher),
+            v => hasher.update(v.serialize()),
+        }
+    }
 
-            Value::Struct(obj) => obj.serialize_as_child(),
+    fn content_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) | (Value::Array(a), Value::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.content_eq(b))
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                a.len() == b.len()
+                    && by_key(a)
+                        .into_iter()
+                        .zip(by_key(b))
+                        .all(|((ak, av), (bk, bv))| ak == bk && av.content_eq(bv))
+            }
+            (Value::Optional(Some(a)), Value::Optional(Some(b))) => a.content_eq(b),
+            (Value::Struct(a), Value::Struct(b)) => a.content_eq(b),
+            (a, b) => a == b,
         }
     }
 
-    pub fn assignable(&self, type_: &Type) -> Result<(), (Type, Type<String>)> {
+    pub fn assignable(&self, type_: &Type) -> Result<(), AssignError> {
         match (self, type_) {
             (Value::Unit, Type::Unit) => Ok(()),
             (Value::Bool(_), Type::Bool) => Ok(()),
             (Value::U8(_), Type::U8) => Ok(()),
+            (Value::U16(_), Type::U16) => Ok(()),
             (Value::U32(_), Type::U32) => Ok(()),
             (Value::U64(_), Type::U64) => Ok(()),
+            (Value::I8(_), Type::I8) => Ok(()),
+            (Value::I16(_), Type::I16) => Ok(()),
+            (Value::I32(_), Type::I32) => Ok(()),
+            (Value::I64(_), Type::I64) => Ok(()),
+            (Value::F32(_), Type::F32) => Ok(()),
+            (Value::F64(_), Type::F64) => Ok(()),
             (Value::String(_), Type::String) => Ok(()),
+            (Value::Bytes(_), Type::Bytes) => Ok(()),
             (Value::List(items), Type::List(inner)) => {
                 items.iter().try_for_each(|i| i.assignable(inner))
             }
-            (Value::Struct(obj), Type::Struct(def)) if obj.schema() == def.as_ref() => Ok(()),
-            (v, t) => Err((t.clone(), v.type_())),
+            (Value::Array(items), Type::Array(inner, len)) if items.len() == *len => {
+                items.iter().try_for_each(|i| i.assignable(inner))
+            }
+            (Value::Optional(None), Type::Optional(_)) => Ok(()),
+            (Value::Optional(Some(v)), Type::Optional(inner)) => v.assignable(inner),
+            (Value::Map(entries), Type::Map(key, value)) => {
+                let mut keys = HashSet::with_capacity(entries.len());
+                entries.iter().try_for_each(|(k, v)| {
+                    k.assignable(key)?;
+                    v.assignable(value)?;
+                    if keys.insert(k.serialize()) {
+                        Ok(())
+                    } else {
+                        Err(AssignError::DuplicateKey)
+                    }
+                })
+            }
+            (Value::Struct(obj), Type::Struct(def)) if obj.schema().same_layout(def) => Ok(()),
+            (Value::Struct(obj), Type::Recursive(name)) if obj.schema().type_name() == name => {
+                Ok(())
+            }
+            (Value::Enum(def, _), Type::Enum(expected)) if def == expected => Ok(()),
+            (v, t) => Err(AssignError::IncorrectType {
+                expected: t.clone(),
+                got: v.type_(),
+            }),
         }
     }
 
@@ -211,9 +763,17 @@ impl<'s> Value<'s> {
             Value::Unit => Type::Unit,
             Value::Bool(_) => Type::Bool,
             Value::U8(_) => Type::U8,
+            Value::U16(_) => Type::U16,
             Value::U32(_) => Type::U32,
             Value::U64(_) => Type::U64,
+            Value::I8(_) => Type::I8,
+            Value::I16(_) => Type::I16,
+            Value::I32(_) => Type::I32,
+            Value::I64(_) => Type::I64,
+            Value::F32(_) => Type::F32,
+            Value::F64(_) => Type::F64,
             Value::String(_) => Type::String,
+            Value::Bytes(_) => Type::Bytes,
             Value::List(items) => {
                 let item_type = items
                     .first()
@@ -221,14 +781,76 @@ impl<'s> Value<'s> {
                     .unwrap_or_else(|| Type::Unit);
                 Type::List(Box::new(item_type))
             }
+            Value::Array(items) => {
+                let item_type = items
+                    .first()
+                    .map(|i| i.type_())
+                    .unwrap_or_else(|| Type::Unit);
+                Type::Array(Box::new(item_type), items.len())
+            }
+            Value::Map(entries) => {
+                let (key_type, value_type) = entries
+                    .first()
+                    .map(|(k, v)| (k.type_(), v.type_()))
+                    .unwrap_or((Type::Unit, Type::Unit));
+                Type::Map(Box::new(key_type), Box::new(value_type))
+            }
+            Value::Optional(v) => Type::Optional(Box::new(
+                v.as_ref().map(|v| v.type_()).unwrap_or(Type::Unit),
+            )),
             Value::Struct(obj) => Type::Struct(obj.schema().type_name().to_string()),
+            Value::Enum(def, _) => Type::Enum(Arc::clone(def)),
         }
     }
 }
 
-fn var_int(val: usize) -> Vec<u8> {
+/// Why [`Value::assignable`] rejected a value.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AssignError {
+    IncorrectType {
+        expected: Type,
+        got: Type<String>,
+    },
+    /// A map holds two entries with the same key.
+    DuplicateKey,
+}
+
+/// Map entries ordered by encoded key, the order they are hashed and
+/// serialized in.
+fn by_key<'a, 's>(entries: &'a [(Value<'s>, Value<'s>)]) -> Vec<&'a (Value<'s>, Value<'s>)> {
+    let mut entries = entries.iter().collect::<Vec<_>>();
+    entries.sort_by_cached_key(|(k, _)| k.serialize());
+    entries
+}
+
+/// Encoded entries in wire order, which is by encoded key so that equal maps
+/// always produce the same bytes.
+fn sorted_entries(entries: &[(Value, Value)], options: &EncodeOptions) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut encoded = entries
+        .iter()
+        .map(|(k, v)| (k.serialize_with(options), v.serialize_with(options)))
+        .collect::<Vec<_>>();
+    encoded.sort();
+    encoded
+}
+
+/// Maps signed values to unsigned ones so those near zero, either side, stay
+/// small: 0, -1, 1, -2, ... become 0, 1, 2, 3, ...
+pub(crate) fn zigzag(val: i64) -> u64 {
+    ((val << 1) ^ (val >> 63)) as u64
+}
+
+pub(crate) fn unzigzag(val: u64) -> i64 {
+    (val >> 1) as i64 ^ -((val & 1) as i64)
+}
+
+pub(crate) fn var_int(val: usize) -> Vec<u8> {
+    leb128(val as u64)
+}
+
+fn leb128(val: u64) -> Vec<u8> {
     // VarInts use 8 bits to encode 7 bits, so need to be multiplied by 8/7.
-    let capacity = core::mem::size_of::<usize>() * 8 / 7 + 1;
+    let capacity = core::mem::size_of::<u64>() * 8 / 7 + 1;
     let mut result = Vec::with_capacity(capacity);
 
     let mut remaining = val;
@@ -268,11 +890,40 @@ mod tests {
             assert_eq!(Value::U8(42).serialize(), vec![42]);
         }
 
+        #[test]
+        fn map_is_sorted_by_key() {
+            let forward = Value::Map(vec![
+                (Value::U8(1), Value::U8(10)),
+                (Value::U8(2), Value::U8(20)),
+            ]);
+            let backward = Value::Map(vec![
+                (Value::U8(2), Value::U8(20)),
+                (Value::U8(1), Value::U8(10)),
+            ]);
+
+            assert_eq!(forward.serialize(), vec![4, 1, 10, 2, 20]);
+            assert_eq!(backward.serialize(), forward.serialize());
+        }
+
+        #[test]
+        fn u16_is_le_bytes() {
+            assert_eq!(Value::U16(0).serialize(), vec![0, 0]);
+            assert_eq!(Value::U16(255).serialize(), vec![255, 0]);
+            assert_eq!(Value::U16(256).serialize(), vec![0, 1]);
+            assert_eq!(Value::U16(65535).serialize(), vec![255, 255]);
+        }
+
         #[test]
         fn u32_is_le_bytes() {
             assert_eq!(Value::U32(42).serialize(), vec![42, 0, 0, 0]);
         }
 
+        #[test]
+        fn signed_is_twos_complement() {
+            assert_eq!(Value::I8(-2).serialize(), vec![0xfe]);
+            assert_eq!(Value::I32(-2).serialize(), vec![0xfe, 0xff, 0xff, 0xff]);
+        }
+
         #[test]
         fn u64_is_le_bytes() {
             assert_eq!(Value::U64(42).serialize(), vec![42, 0, 0, 0, 0, 0, 0, 0]);
@@ -321,7 +972,10 @@ mod tests {
             let list = Value::List(vec![Value::U8(4), Value::U32(32)]);
             assert_eq!(
                 list.assignable(&Type::List(Box::new(Type::U8))),
-                Err((Type::U8, Type::U32))
+                Err(AssignError::IncorrectType {
+                    expected: Type::U8,
+                    got: Type::U32
+                })
             );
         }
 
@@ -330,7 +984,10 @@ mod tests {
             let list = Value::List(vec![]);
             assert_eq!(
                 list.assignable(&Type::U8),
-                Err((Type::U8, Type::List(Box::new(Type::Unit))))
+                Err(AssignError::IncorrectType {
+                    expected: Type::U8,
+                    got: Type::List(Box::new(Type::Unit))
+                })
             );
         }
 
@@ -345,9 +1002,92 @@ mod tests {
             let struct_ = Value::Struct(obj);
             assert_eq!(
                 struct_.assignable(&Type::U8),
-                Err((Type::U8, Type::Struct(def.type_name().to_string())))
+                Err(AssignError::IncorrectType {
+                    expected: Type::U8,
+                    got: Type::Struct(def.type_name().to_string())
+                })
             );
         }
+
+        #[test]
+        fn map_with_duplicate_keys() {
+            let map = Value::Map(vec![
+                (Value::U8(1), Value::Bool(true)),
+                (Value::U8(2), Value::Bool(true)),
+                (Value::U8(1), Value::Bool(false)),
+            ]);
+            assert_eq!(
+                map.assignable(&Type::Map(Box::new(Type::U8), Box::new(Type::Bool))),
+                Err(AssignError::DuplicateKey)
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod content_hash {
+        use super::*;
+        use crate::schema::{FieldAttributes, FieldDef};
+
+        fn message_def() -> StructDef {
+            StructDef {
+                type_name: "Message".to_string(),
+                fields: vec![
+                    FieldDef {
+                        name: "body".to_string(),
+                        type_: Type::String,
+                        attributes: Default::default(),
+                    },
+                    FieldDef {
+                        name: "sent_at".to_string(),
+                        type_: Type::U64,
+                        attributes: FieldAttributes {
+                            ignore_in_hash: true,
+                            ..Default::default()
+                        },
+                    },
+                ],
+            }
+        }
+
+        fn message<'s>(def: &'s StructDef, body: &str, sent_at: u64) -> Object<'s> {
+            def.builder()
+                .set("body", body.to_string())
+                .set("sent_at", sent_at)
+                .try_build()
+                .unwrap()
+        }
+
+        #[test]
+        fn ignores_ignored_fields() {
+            let def = message_def();
+            let a = message(&def, "hello", 1);
+            let b = message(&def, "hello", 2);
+
+            assert_eq!(a.content_hash(), b.content_hash());
+            assert!(a.content_eq(&b));
+            assert_ne!(a, b);
+        }
+
+        #[test]
+        fn differs_on_other_fields() {
+            let def = message_def();
+            let a = message(&def, "hello", 1);
+            let b = message(&def, "goodbye", 1);
+
+            assert_ne!(a.content_hash(), b.content_hash());
+            assert!(!a.content_eq(&b));
+        }
+
+        #[test]
+        fn maps_ignore_entry_order() {
+            let a = Value::Map(vec![(1u8.into(), 10u8.into()), (2u8.into(), 20u8.into())]);
+            let b = Value::Map(vec![(2u8.into(), 20u8.into()), (1u8.into(), 10u8.into())]);
+            let c = Value::Map(vec![(2u8.into(), 10u8.into()), (1u8.into(), 20u8.into())]);
+
+            assert!(a.content_eq(&b));
+            assert_eq!(a.serialize(), b.serialize());
+            assert!(!a.content_eq(&c));
+        }
     }
 
     #[cfg(test)]
@@ -0,0 +1,154 @@
+/// Byte order of multi-byte integers, floats and enum values. Length
+/// prefixes are varints and have no byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// The order used unless options say otherwise.
+    pub const CANONICAL: Endianness = Endianness::Little;
+
+    /// `le_bytes` in this order.
+    pub(crate) fn order(self, le_bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Endianness::Little => le_bytes.to_vec(),
+            Endianness::Big => le_bytes.iter().rev().copied().collect(),
+        }
+    }
+}
+
+impl Default for Endianness {
+    fn default() -> Self {
+        Endianness::CANONICAL
+    }
+}
+
+impl From<Endianness> for nom::number::Endianness {
+    fn from(e: Endianness) -> Self {
+        match e {
+            Endianness::Little => nom::number::Endianness::Little,
+            Endianness::Big => nom::number::Endianness::Big,
+        }
+    }
+}
+
+/// Options controlling how objects are serialized.
+///
+/// The defaults produce the same bytes as `Object::serialize`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// Prefix every struct with its number of fields as a varint.
+    pub field_count_header: bool,
+    pub endianness: Endianness,
+    /// Write `u32` and `u64` as LEB128 varints, and `i32` and `i64` zigzag
+    /// encoded then as varints, so small values take fewer bytes.
+    pub varint_integers: bool,
+}
+
+use crate::{EnumDef, Error, Id};
+
+/// Options controlling how bytes are parsed. These must match the
+/// [`EncodeOptions`] the bytes were produced with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Expect the header written by [`EncodeOptions::field_count_header`] and
+    /// reject structs whose count doesn't match the schema.
+    pub field_count_header: bool,
+    /// How many lists and structs may be nested below the top-level struct
+    /// before decoding fails with [`Error::MaxDepthExceeded`]. Defaults to
+    /// [`DecodeOptions::DEFAULT_MAX_DEPTH`].
+    pub max_depth: usize,
+    pub endianness: Endianness,
+    /// Expect integers written with [`EncodeOptions::varint_integers`].
+    pub varint_integers: bool,
+    /// Let [`StructDef::decode_with`](crate::StructDef::decode_with) read
+    /// messages written with a newer version of the struct: whatever follows
+    /// the fields it knows about is skipped instead of being
+    /// [`Error::TrailingBytes`]. Adding a field changes the ID, so the newer
+    /// versions' IDs also go in [`DecodeOptions::accepted_ids`].
+    ///
+    /// This is only sound if new versions never change or reorder existing
+    /// fields and only ever append new ones, and only to the top-level
+    /// struct: nested structs aren't length prefixed, so extra fields in
+    /// them shift everything after. Nothing in the message can check this,
+    /// so it is up to the schema's authors.
+    pub skip_trailing_fields: bool,
+    /// IDs besides the struct's own that a message may start with, such as
+    /// those of newer versions read with
+    /// [`DecodeOptions::skip_trailing_fields`]. Any other ID is still
+    /// [`Error::MissingId`].
+    pub accepted_ids: Vec<Id>,
+    /// Whether an enum value with no variant fails, the default, or decodes
+    /// as a fallback variant.
+    pub unknown_enum: UnknownEnum,
+}
+
+impl DecodeOptions {
+    pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+    /// Options for decoding one level further down.
+    pub(crate) fn nested(&self) -> Result<DecodeOptions, Error> {
+        let max_depth = self
+            .max_depth
+            .checked_sub(1)
+            .ok_or(Error::MaxDepthExceeded)?;
+        Ok(DecodeOptions {
+            max_depth,
+            ..self.clone()
+        })
+    }
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            field_count_header: false,
+            max_depth: Self::DEFAULT_MAX_DEPTH,
+            endianness: Endianness::default(),
+            varint_integers: false,
+            skip_trailing_fields: false,
+            accepted_ids: Vec::new(),
+            unknown_enum: UnknownEnum::default(),
+        }
+    }
+}
+
+/// What decoding does with an enum value that has no variant, such as one
+/// added by a newer version of the schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownEnum {
+    /// Fail with [`Error::UnknownEnumVariant`].
+    Error,
+    /// Decode it as the variant with this value instead, typically an
+    /// `Unknown` catch-all. Enums without such a variant fail as with
+    /// `Error`.
+    Fallback(u32),
+}
+
+// `#[default]` on a variant needs a newer compiler than CI's.
+#[allow(clippy::derivable_impls)]
+impl Default for UnknownEnum {
+    fn default() -> Self {
+        UnknownEnum::Error
+    }
+}
+
+impl UnknownEnum {
+    /// What `value` of `def` decodes as.
+    pub(crate) fn apply(self, def: &EnumDef, value: u32) -> Result<u32, Error> {
+        if def.variant_with_value(value).is_some() {
+            return Ok(value);
+        }
+        match self {
+            UnknownEnum::Fallback(fallback) if def.variant_with_value(fallback).is_some() => {
+                Ok(fallback)
+            }
+            _ => Err(Error::UnknownEnumVariant {
+                enum_name: def.type_name().to_string(),
+                value,
+            }),
+        }
+    }
+}
@@ -1,12 +1,20 @@
 use blake2::{Blake2b512, Digest};
-use core::convert::TryInto;
-use std::sync::Arc;
+use core::{convert::TryInto, fmt, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    sync::Arc,
+};
 
-use crate::{Builder, Error, Object, Value};
+use crate::{
+    annotate, binary, definition_parser, json, object::unzigzag, stream, Builder, ByteSpan,
+    DecodeOptions, Error, Object, Parser, PartialObject, Value, ValueBuilder, MAX_ARRAY_LEN,
+    MAX_TYPE_DEPTH,
+};
 
 pub type Id = [u8; 8];
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct StructDef {
     pub(crate) type_name: String,
     pub(crate) fields: Vec<FieldDef>,
@@ -17,6 +25,10 @@ impl StructDef {
         &self.type_name
     }
 
+    /// Identifies the struct's layout: a hash of its name and each field's
+    /// name, type and whether it is `@varint`, recursing into nested structs. Every encoded message
+    /// starts with it. Blake2b based, so it is the same on every platform and
+    /// across releases.
     pub fn id(&self) -> [u8; 8] {
         let mut hasher = Blake2b512::new();
 
@@ -24,6 +36,9 @@ impl StructDef {
         for field in &self.fields {
             hasher.update(&field.name);
             hasher.update(field.type_.id());
+            if field.attributes.varint {
+                hasher.update("@varint");
+            }
         }
 
         let digest = hasher.finalize();
@@ -33,15 +48,71 @@ impl StructDef {
         bytes.try_into().expect("hash should always be > 8 bytes")
     }
 
+    /// The struct's [`id`](Self::id), for confirming that both ends of a
+    /// connection agree on a schema without sending a message.
+    pub fn fingerprint(&self) -> [u8; 8] {
+        self.id()
+    }
+
+    /// Fields in definition order, which is also the order they are encoded
+    /// in. Fields from included structs come first.
     pub fn fields(&self) -> &[FieldDef] {
         self.fields.as_ref()
     }
 
-    pub fn parse<'i>(&self, mut bytes: &'i [u8]) -> Result<(&'i [u8], Object), Error<'i>> {
+    /// Position of the field called `name` in [`StructDef::fields`].
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f.name() == name)
+    }
+
+    pub fn field(&self, index: usize) -> Option<&FieldDef> {
+        self.fields.get(index)
+    }
+
+    pub fn parse<'i>(&self, bytes: &'i [u8]) -> Result<(&'i [u8], Object), Error> {
+        self.parse_with(bytes, &DecodeOptions::default())
+    }
+
+    /// Whether both are the same struct, compiled from different places. A
+    /// struct reached through mutually recursive ones refers back to them as
+    /// `Type::Recursive`, where the one registered under its own name has the
+    /// full definition, so the two aren't equal though values of one are
+    /// values of the other.
+    pub(crate) fn same_layout(&self, other: &StructDef) -> bool {
+        self.type_name == other.type_name
+            && self.fields.len() == other.fields.len()
+            && self.fields.iter().zip(&other.fields).all(|(a, b)| {
+                a.name == b.name && a.attributes == b.attributes && a.type_.same_layout(&b.type_)
+            })
+    }
+
+    pub fn parse_with<'i>(
+        &self,
+        bytes: &'i [u8],
+        options: &DecodeOptions,
+    ) -> Result<(&'i [u8], Object), Error> {
+        self.parse_within(bytes, options, None)
+    }
+
+    fn parse_within<'s, 'i>(
+        &'s self,
+        mut bytes: &'i [u8],
+        options: &DecodeOptions,
+        parent: Option<&Enclosing<'_, 's>>,
+    ) -> Result<(&'i [u8], Object<'s>), Error> {
+        let here = Enclosing { def: self, parent };
+
+        if options.field_count_header {
+            let (new_bytes, found) = var_int(bytes).map_err(Error::from)?;
+            self.check_field_count(found)?;
+            bytes = new_bytes;
+        }
+
         let mut values = Vec::with_capacity(self.fields.len());
 
         for field in &self.fields {
-            let (new_bytes, value) = field.parse(bytes)?;
+            let (new_bytes, value) = field.parse_with(bytes, options, Some(&here))?;
+            self.check_constraints(field, &value)?;
             bytes = new_bytes;
             values.push(value);
         }
@@ -49,15 +120,524 @@ impl StructDef {
         Ok((bytes, Object::new(self, values)))
     }
 
+    /// Decodes a whole message as produced by [`Object::serialize`], ID
+    /// included. Any bytes after the message are an error.
+    pub fn decode(&self, bytes: &[u8]) -> Result<Object, Error> {
+        self.decode_with(bytes, &DecodeOptions::default())
+    }
+
+    /// [`decode`](Self::decode) with `options`. See
+    /// [`DecodeOptions::skip_trailing_fields`] for reading messages from a
+    /// newer version of the struct.
+    pub fn decode_with(&self, bytes: &[u8], options: &DecodeOptions) -> Result<Object, Error> {
+        let id = bytes.get(0..8).ok_or(Error::TooFewBytes)?;
+        self.check_id(id.try_into().expect("took 8 bytes"), options)?;
+
+        let (rest, obj) = self.parse_with(&bytes[8..], options)?;
+        if !rest.is_empty() && !options.skip_trailing_fields {
+            return Err(Error::TrailingBytes(rest.len()));
+        }
+        Ok(obj)
+    }
+
+    /// Whether a message with `id` may be decoded as this struct.
+    pub(crate) fn check_id(&self, id: &Id, options: &DecodeOptions) -> Result<(), Error> {
+        if *id != self.id() && !options.accepted_ids.contains(id) {
+            return Err(Error::MissingId(*id));
+        }
+        Ok(())
+    }
+
+    /// For the header written by `EncodeOptions::field_count_header`.
+    pub(crate) fn check_field_count(&self, found: usize) -> Result<(), Error> {
+        if found != self.fields.len() {
+            return Err(Error::FieldCountMismatch {
+                expected: self.fields.len(),
+                found,
+            });
+        }
+        Ok(())
+    }
+
+    /// Object for `json`, which must have exactly the struct's fields. The
+    /// JSON looks like what [`Object::to_json`] produces.
+    pub fn from_json<'a>(&'a self, json: &serde_json::Value) -> Result<Object<'a>, Error> {
+        json::object_from_json(json, self)
+    }
+
+    /// Decodes a whole message as produced by [`Object::serialize`] from
+    /// `reader`, reading exactly as many bytes as the message takes, so the
+    /// reader is left at the start of whatever follows. Fails with
+    /// [`Error::UnexpectedEof`] if the reader ends first.
+    pub fn decode_from<R: Read>(&self, reader: &mut R) -> Result<Object, Error> {
+        self.decode_from_with(reader, &DecodeOptions::default())
+    }
+
+    /// [`decode_from`](Self::decode_from) with `options`. Nothing marks where
+    /// the fields that [`DecodeOptions::skip_trailing_fields`] would skip
+    /// end, so they are left in `reader`; frame messages with
+    /// [`write_framed`](crate::write_framed) and decode the frames to skip
+    /// them.
+    pub fn decode_from_with<R: Read>(
+        &self,
+        reader: &mut R,
+        options: &DecodeOptions,
+    ) -> Result<Object, Error> {
+        stream::decode_from(self, reader, options)
+    }
+
+    /// Decodes as many leading fields of a message as `bytes` allows, ID
+    /// included as for [`decode`](Self::decode), for telemetry where a
+    /// truncated message is still worth looking at. The result only holds the
+    /// fields before the first failure, and the error, if any, names the
+    /// field that could not be decoded.
+    pub fn decode_partial(&self, bytes: &[u8]) -> (PartialObject, Option<Error>) {
+        self.decode_partial_with(bytes, &DecodeOptions::default())
+    }
+
+    /// [`decode_partial`](Self::decode_partial) with `options`.
+    pub fn decode_partial_with(
+        &self,
+        bytes: &[u8],
+        options: &DecodeOptions,
+    ) -> (PartialObject, Option<Error>) {
+        let mut values = Vec::with_capacity(self.fields.len());
+        let error = self.decode_fields(bytes, options, &mut values).err();
+        (
+            PartialObject {
+                schema: self,
+                values,
+            },
+            error,
+        )
+    }
+
+    /// Decodes into `values` for `decode_partial_with`, leaving those decoded
+    /// before any error.
+    fn decode_fields<'s>(
+        &'s self,
+        bytes: &[u8],
+        options: &DecodeOptions,
+        values: &mut Vec<Value<'s>>,
+    ) -> Result<(), Error> {
+        let id = bytes.get(0..8).ok_or(Error::TooFewBytes)?;
+        self.check_id(id.try_into().expect("took 8 bytes"), options)?;
+        let mut bytes = &bytes[8..];
+
+        let here = Enclosing {
+            def: self,
+            parent: None,
+        };
+
+        if options.field_count_header {
+            let (new_bytes, found) = var_int(bytes).map_err(Error::from)?;
+            self.check_field_count(found)?;
+            bytes = new_bytes;
+        }
+
+        for field in &self.fields {
+            let (new_bytes, value) = field
+                .parse_with(bytes, options, Some(&here))
+                .and_then(|(new_bytes, value)| {
+                    self.check_constraints(field, &value)?;
+                    Ok((new_bytes, value))
+                })
+                .map_err(|error| Error::FieldDecoding {
+                    field: format!("{}.{}", self.type_name, field.name),
+                    error: Box::new(error),
+                })?;
+            bytes = new_bytes;
+            values.push(value);
+        }
+
+        if !bytes.is_empty() && !options.skip_trailing_fields {
+            return Err(Error::TrailingBytes(bytes.len()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_constraints(&self, field: &FieldDef, value: &Value) -> Result<(), Error> {
+        let violation = |detail| Error::ConstraintViolation {
+            field: format!("{}.{}", self.type_name, field.name),
+            detail,
+        };
+
+        if let (Some((min, max)), Some(v)) = (field.attributes.range, value.as_integer()) {
+            if v < min || v > max {
+                return Err(violation(format!("{} is outside {}..={}", v, min, max)));
+            }
+        }
+        if let (Some(max), Some(len)) = (field.attributes.max_len, value.length()) {
+            if len > max {
+                return Err(violation(format!("length {} exceeds {}", len, max)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encoded size of the struct's fields, not counting the 8 byte ID that
+    /// [`Object::serialize`] puts in front.
+    pub fn size_hint(&self) -> SizeHint {
+        self.fields
+            .iter()
+            .map(FieldDef::size_hint)
+            .fold(SizeHint::Fixed(0), |total, hint| total + hint)
+    }
+
     pub fn builder(&self) -> Builder {
         Builder::new(self)
     }
+
+    /// An object to fill in field by field, checking each value as it is
+    /// set. See also [`builder`](Self::builder).
+    pub fn new_value(&self) -> ValueBuilder {
+        ValueBuilder::new(self)
+    }
+
+    /// Schema source for this struct preceded by every struct it references,
+    /// so that parsing the result on its own registers this struct.
+    pub fn source_with_dependencies(&self) -> String {
+        let mut defs = Vec::new();
+        self.collect_dependencies(&mut defs);
+        let mut enums = Vec::new();
+        for def in &defs {
+            for field in &def.fields {
+                field.type_.collect_enums(&mut enums);
+            }
+        }
+
+        enums
+            .iter()
+            .map(|e| e.to_string())
+            .chain(defs.iter().map(|def| def.to_string()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Describes which bytes of `bytes` (without the leading ID) belong to
+    /// which field, for debugging malformed messages. If the buffer ends early
+    /// the last span covers what's left and is previewed as `<truncated>`.
+    pub fn annotate_bytes(&self, bytes: &[u8]) -> Result<Vec<ByteSpan>, Error> {
+        annotate::annotate(self, bytes)
+    }
+
+    /// Compact binary form of this struct and every struct it references. See
+    /// the `binary` module for the layout.
+    pub fn to_binary(&self) -> Vec<u8> {
+        binary::encode(core::iter::once(self))
+    }
+
+    /// Loads a struct written by [`StructDef::to_binary`].
+    pub fn from_binary(bytes: &[u8]) -> Result<StructDef, Error> {
+        let root = binary::decode(bytes)?
+            .pop()
+            .ok_or_else(|| Error::BadSchemaBlob("no structs".to_string()))?;
+        Ok(Arc::try_unwrap(root).unwrap_or_else(|root| (*root).clone()))
+    }
+
+    pub(crate) fn collect_dependencies<'a>(&'a self, defs: &mut Vec<&'a StructDef>) {
+        for field in &self.fields {
+            field.type_.collect_dependencies(defs);
+        }
+        if !defs.iter().any(|d| d.id() == self.id()) {
+            defs.push(self);
+        }
+    }
+}
+
+/// A C-style enum such as `enum Status { Active; Closed; }`, encoded as the
+/// chosen variant's value as a `u32`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EnumDef {
+    pub(crate) type_name: String,
+    pub(crate) variants: Vec<EnumVariant>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EnumVariant {
+    pub name: String,
+    pub value: u32,
+}
+
+impl EnumDef {
+    pub fn type_name(&self) -> &str {
+        &self.type_name
+    }
+
+    pub fn variants(&self) -> &[EnumVariant] {
+        &self.variants
+    }
+
+    pub fn variant(&self, name: &str) -> Option<&EnumVariant> {
+        self.variants.iter().find(|v| v.name == name)
+    }
+
+    pub fn variant_with_value(&self, value: u32) -> Option<&EnumVariant> {
+        self.variants.iter().find(|v| v.value == value)
+    }
+
+    /// The value to set on a field of this enum type for the variant `name`.
+    pub fn value<'s>(self: &Arc<Self>, name: &str) -> Option<Value<'s>> {
+        let variant = self.variant(name)?;
+        Some(Value::Enum(Arc::clone(self), variant.value))
+    }
+
+    /// Covers the name and every variant, like [`StructDef::id`].
+    pub fn id(&self) -> [u8; 8] {
+        let mut hasher = Blake2b512::new();
+
+        hasher.update(&self.type_name);
+        for variant in &self.variants {
+            hasher.update(&variant.name);
+            hasher.update(variant.value.to_le_bytes());
+        }
+
+        let digest = hasher.finalize();
+        digest[0..8]
+            .try_into()
+            .expect("hash should always be > 8 bytes")
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl fmt::Display for EnumDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.variants.is_empty() {
+            return write!(f, "enum {} {{}}", self.type_name);
+        }
+
+        // Values are only written where they don't follow from the previous
+        // variant.
+        writeln!(f, "enum {} {{", self.type_name)?;
+        let mut implicit = Some(0);
+        for variant in &self.variants {
+            if implicit == Some(variant.value) {
+                writeln!(f, "    {};", variant.name)?;
+            } else {
+                writeln!(f, "    {} = {};", variant.name, variant.value)?;
+            }
+            implicit = variant.value.checked_add(1);
+        }
+        write!(f, "}}")
+    }
+}
+
+/// Constructs a `StructDef` programmatically, with the same validation as
+/// parsing a definition.
+#[derive(Debug)]
+pub struct StructDefBuilder {
+    type_name: String,
+    fields: Vec<FieldDef>,
+}
+
+impl StructDefBuilder {
+    pub fn new(type_name: impl Into<String>) -> Self {
+        StructDefBuilder {
+            type_name: type_name.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(self, name: impl Into<String>, type_: Type) -> Self {
+        self.field_with_attributes(name, type_, FieldAttributes::default())
+    }
+
+    pub fn field_with_attributes(
+        mut self,
+        name: impl Into<String>,
+        type_: Type,
+        attributes: FieldAttributes,
+    ) -> Self {
+        self.fields.push(FieldDef {
+            name: name.into(),
+            type_,
+            attributes,
+        });
+        self
+    }
+
+    pub fn build(self) -> Result<StructDef, Error> {
+        ensure_valid_type_name(&self.type_name)?;
+        for f in &self.fields {
+            ensure_valid_name(&f.name)?;
+            ensure_valid_arrays(&f.type_)?;
+            ensure_shallow(&f.type_)?;
+            if f.attributes.varint && !f.type_.has_varint_form() {
+                return Err(Error::InvalidAttribute {
+                    detail: format!("@varint on {}", f.type_),
+                    span: None,
+                });
+            }
+        }
+        ensure_unique_field_names(self.fields.iter().map(|f| f.name.as_str()))?;
+
+        Ok(StructDef {
+            type_name: self.type_name,
+            fields: self.fields,
+        })
+    }
+}
+
+pub(crate) fn ensure_valid_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(Error::EmptyName { span: None });
+    }
+    if definition_parser::is_reserved_name(name) {
+        return Err(Error::ReservedName {
+            name: name.to_string(),
+            span: None,
+        });
+    }
+    Ok(())
+}
+
+/// Like `ensure_valid_name`, but also rejects the keywords that start a
+/// definition, for the names of structs, enums and aliases.
+pub(crate) fn ensure_valid_type_name(name: &str) -> Result<(), Error> {
+    ensure_valid_name(name)?;
+    if definition_parser::is_definition_keyword(name) {
+        return Err(Error::ReservedName {
+            name: name.to_string(),
+            span: None,
+        });
+    }
+    Ok(())
+}
+
+/// Checks every `Array` in `type_`, but not those in the fields of any
+/// nested structs, which were checked when those were built.
+pub(crate) fn ensure_valid_arrays(type_: &Type) -> Result<(), Error> {
+    match type_ {
+        Type::Array(item, len) => {
+            ensure_valid_array(item, *len)?;
+            ensure_valid_arrays(item)
+        }
+        Type::List(t) | Type::Optional(t) => ensure_valid_arrays(t),
+        Type::Map(k, v) => ensure_valid_arrays(k).and_then(|()| ensure_valid_arrays(v)),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `type_` nests generics and structs no more than
+/// `MAX_TYPE_DEPTH` deep, nested structs included, as everything that walks
+/// a type recurses that deep.
+pub(crate) fn ensure_shallow(type_: &Type) -> Result<(), Error> {
+    match nesting(type_, MAX_TYPE_DEPTH, &mut HashMap::new()) {
+        Some(_) => Ok(()),
+        None => Err(Error::TypeTooDeep {
+            max: MAX_TYPE_DEPTH,
+            span: None,
+        }),
+    }
+}
+
+/// How deep `type_` nests, or `None` if that is more than `budget`. Structs
+/// are often shared, so their depth is remembered in `structs`.
+fn nesting(
+    type_: &Type,
+    budget: usize,
+    structs: &mut HashMap<*const StructDef, usize>,
+) -> Option<usize> {
+    let inner = match type_ {
+        Type::List(t) | Type::Optional(t) | Type::Array(t, _) => {
+            nesting(t, budget.checked_sub(1)?, structs)?
+        }
+        Type::Map(k, v) => {
+            let budget = budget.checked_sub(1)?;
+            nesting(k, budget, structs)?.max(nesting(v, budget, structs)?)
+        }
+        Type::Struct(def) => match structs.get(&Arc::as_ptr(def)) {
+            Some(&depth) => depth,
+            None => {
+                let budget = budget.checked_sub(1)?;
+                let mut depth = 0;
+                for field in &def.fields {
+                    depth = depth.max(nesting(&field.type_, budget, structs)?);
+                }
+                structs.insert(Arc::as_ptr(def), depth);
+                depth
+            }
+        },
+        _ => return Some(0),
+    };
+    Some(inner + 1).filter(|&depth| depth <= budget)
+}
+
+pub(crate) fn ensure_valid_array(item: &Type, len: usize) -> Result<(), Error> {
+    if len > MAX_ARRAY_LEN {
+        return Err(Error::ArrayTooLong {
+            len,
+            max: MAX_ARRAY_LEN,
+            span: None,
+        });
+    }
+    match item.size_hint() {
+        SizeHint::Fixed(0) | SizeHint::Variable { min: 0 } => Err(Error::ZeroWidthArrayItem {
+            item: item.to_string(),
+            span: None,
+        }),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn ensure_unique_field_names<'n, 'i>(
+    names: impl Iterator<Item = &'n str>,
+) -> Result<(), Error> {
+    let mut seen = HashSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            return Err(Error::DuplicateField {
+                name: name.to_string(),
+                span: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Schema text for the definition, which parses back to an equal `StructDef`
+/// once the structs it refers to are defined alongside it.
+impl fmt::Display for StructDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.fields.is_empty() {
+            return write!(f, "struct {} {{}}", self.type_name);
+        }
+
+        writeln!(f, "struct {} {{", self.type_name)?;
+        for field in &self.fields {
+            writeln!(f, "    {}", field)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct FieldDef {
     pub(crate) name: String,
     pub(crate) type_: Type,
+    pub(crate) attributes: FieldAttributes,
+}
+
+/// Annotations written after a field's type, e.g. `at :u64 @ignore_in_hash;`.
+///
+/// Only `varint` affects the wire format, so it is the only one that is part
+/// of the struct's ID.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct FieldAttributes {
+    pub ignore_in_hash: bool,
+    /// Inclusive bounds checked when decoding an integer field, from
+    /// `@range(min, max)`.
+    pub range: Option<(i128, i128)>,
+    /// Largest byte length of a string or item count of a list accepted when
+    /// decoding, from `@maxlen(n)`.
+    pub max_len: Option<usize>,
+    /// Encode a `u32`, `u64`, `i32` or `i64` field as a LEB128 varint,
+    /// zigzag encoded first if signed so that -1 takes a single byte, from
+    /// `@varint`. The same form [`EncodeOptions::varint_integers`] uses for
+    /// every integer.
+    ///
+    /// [`EncodeOptions::varint_integers`]: crate::EncodeOptions::varint_integers
+    pub varint: bool,
 }
 
 impl FieldDef {
@@ -69,8 +649,58 @@ impl FieldDef {
         &self.type_
     }
 
-    fn parse<'i>(&self, bytes: &'i [u8]) -> Result<(&'i [u8], Value), Error<'i>> {
-        self.type_.parse(bytes)
+    pub fn attributes(&self) -> &FieldAttributes {
+        &self.attributes
+    }
+
+    /// Whether the field takes the varint form of its type.
+    pub(crate) fn is_varint(&self) -> bool {
+        self.attributes.varint && self.type_.has_varint_form()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.is_varint() {
+            true => SizeHint::Variable { min: 1 },
+            false => self.type_.size_hint(),
+        }
+    }
+
+    #[cfg(test)]
+    fn parse<'i>(&self, bytes: &'i [u8]) -> Result<(&'i [u8], Value), Error> {
+        self.parse_with(bytes, &DecodeOptions::default(), None)
+    }
+
+    pub(crate) fn parse_with<'s, 'i>(
+        &'s self,
+        bytes: &'i [u8],
+        options: &DecodeOptions,
+        enclosing: Option<&Enclosing<'_, 's>>,
+    ) -> Result<(&'i [u8], Value<'s>), Error> {
+        if self.attributes.varint {
+            if let Some(parsed) = self.type_.parse_varint(bytes) {
+                return parsed;
+            }
+        }
+        self.type_.parse_within(bytes, options, enclosing)
+    }
+}
+
+impl fmt::Display for FieldDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} :{}", self.name, self.type_)?;
+        if self.attributes.ignore_in_hash {
+            write!(f, " @ignore_in_hash")?;
+        }
+        if let Some((min, max)) = self.attributes.range {
+            write!(f, " @range({}, {})", min, max)?;
+        }
+        if let Some(max) = self.attributes.max_len {
+            write!(f, " @maxlen({})", max)?;
+        }
+        if self.attributes.varint {
+            write!(f, " @varint")?;
+        }
+        write!(f, ";")
     }
 }
 
@@ -81,12 +711,22 @@ use nom::{
     IResult,
 };
 
-fn length_prefixed(b: &[u8]) -> IResult<&[u8], &[u8]> {
-    let (b, len) = var_int(b)?;
-    take(len)(b)
+/// A varint byte length and the bytes it covers. The length is checked
+/// against what is left before anything is taken, so a corrupt prefix can't
+/// cause a huge allocation further up.
+pub(crate) fn length_prefixed(b: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let (b, len) = var_int(b).map_err(Error::from)?;
+    if len > b.len() {
+        return Err(Error::LengthExceedsBuffer {
+            claimed: len,
+            remaining: b.len(),
+        });
+    }
+    let (taken, rest) = b.split_at(len);
+    Ok((rest, taken))
 }
 
-fn var_int(b: &[u8]) -> IResult<&[u8], usize> {
+pub(crate) fn var_int(b: &[u8]) -> IResult<&[u8], usize> {
     let (new_b, int_bytes) = recognize(pair(take_while(|b| b & 128 > 0), take(1usize)))(b)?;
 
     let mut result: usize = 0;
@@ -94,13 +734,12 @@ fn var_int(b: &[u8]) -> IResult<&[u8], usize> {
         let shift_by = 7 * i;
         let effective_byte = (byte & 0b0111_1111) as usize;
 
-        let shifted = effective_byte << shift_by;
-        if shifted >> shift_by != effective_byte {
-            return Err(nom::Err::Error(nom::error::Error::new(
-                b,
-                nom::error::ErrorKind::TooLarge,
-            )));
-        }
+        let shifted = effective_byte
+            .checked_shl(shift_by as u32)
+            .filter(|shifted| shifted >> shift_by == effective_byte)
+            .ok_or_else(|| {
+                nom::Err::Error(nom::error::Error::new(b, nom::error::ErrorKind::TooLarge))
+            })?;
 
         result |= shifted;
     }
@@ -108,16 +747,116 @@ fn var_int(b: &[u8]) -> IResult<&[u8], usize> {
     Ok((new_b, result))
 }
 
+/// An integer written with `EncodeOptions::varint_integers`, which must fit
+/// in `bits` and use as few bytes as possible.
+fn leb128(bytes: &[u8], bits: u32) -> Result<(&[u8], u64), Error> {
+    let max_len = ((bits + 6) / 7) as usize;
+
+    let mut value: u128 = 0;
+    for (i, &byte) in bytes.iter().enumerate().take(max_len) {
+        value |= u128::from(byte & 0b0111_1111) << (7 * i);
+        if byte & 0b1000_0000 != 0 {
+            continue;
+        }
+
+        if (i > 0 && byte == 0) || value >> bits != 0 {
+            return Err(Error::InvalidVarint { bits });
+        }
+        return Ok((&bytes[i + 1..], value as u64));
+    }
+
+    if bytes.len() < max_len {
+        return Err(Error::ValueParsing(nom::Err::Error(
+            nom::error::make_error(bytes.to_vec(), nom::error::ErrorKind::Eof),
+        )));
+    }
+    Err(Error::InvalidVarint { bits })
+}
+
+/// The structs a value is nested in, innermost first, for resolving
+/// [`Type::Recursive`] while decoding.
+pub(crate) struct Enclosing<'e, 's> {
+    def: &'s StructDef,
+    parent: Option<&'e Enclosing<'e, 's>>,
+}
+
+impl<'e, 's> Enclosing<'e, 's> {
+    fn find(&self, name: &str) -> Option<&'s StructDef> {
+        if self.def.type_name == name {
+            return Some(self.def);
+        }
+        self.parent.and_then(|p| p.find(name))
+    }
+}
+
+/// How many bytes a value takes once encoded, from [`Type::size_hint`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SizeHint {
+    /// Every value takes exactly this many bytes.
+    Fixed(usize),
+    /// Depends on the value, but always takes at least `min` bytes.
+    Variable { min: usize },
+}
+
+impl core::ops::Add for SizeHint {
+    type Output = SizeHint;
+
+    fn add(self, other: SizeHint) -> SizeHint {
+        match (self, other) {
+            (SizeHint::Fixed(a), SizeHint::Fixed(b)) => SizeHint::fixed(a.checked_add(b)),
+            (SizeHint::Fixed(a), SizeHint::Variable { min: b })
+            | (SizeHint::Variable { min: a }, SizeHint::Fixed(b))
+            | (SizeHint::Variable { min: a }, SizeHint::Variable { min: b }) => {
+                SizeHint::Variable {
+                    min: a.saturating_add(b),
+                }
+            }
+        }
+    }
+}
+
+impl SizeHint {
+    /// A size too large for `usize` can't be fixed, and is at least as
+    /// large as anything that fits.
+    fn fixed(size: Option<usize>) -> SizeHint {
+        match size {
+            Some(size) => SizeHint::Fixed(size),
+            None => SizeHint::Variable { min: usize::MAX },
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Type<StructType = Arc<StructDef>> {
     Unit,
     Bool,
     U8,
+    U16,
     U32,
     U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
     String,
+    /// A length prefix followed by the raw bytes.
+    Bytes,
     List(Box<Type<StructType>>),
+    /// Exactly this many elements, one after the other with no length
+    /// prefix.
+    Array(Box<Type<StructType>>, usize),
+    /// Encoded like a list of key/value pairs, sorted by encoded key.
+    Map(Box<Type<StructType>>, Box<Type<StructType>>),
+    /// A presence byte (0 or 1) followed by the value if present.
+    Optional(Box<Type<StructType>>),
     Struct(StructType),
+    Enum(Arc<EnumDef>),
+    /// The enclosing struct with this name, for self-referential schemas such
+    /// as trees. Only appears somewhere under a `List`, `Map` or `Optional`,
+    /// so values are always finite.
+    Recursive(String),
 }
 
 impl Type {
@@ -127,67 +866,370 @@ impl Type {
             Type::Unit => vec![5],
             Type::Bool => vec![7],
             Type::U8 => vec![0],
+            Type::U16 => vec![12],
             Type::U32 => vec![1],
             Type::U64 => vec![2],
+            Type::I8 => vec![8],
+            Type::I16 => vec![9],
+            Type::I32 => vec![10],
+            Type::I64 => vec![11],
+            Type::F32 => vec![13],
+            Type::F64 => vec![14],
             Type::String => vec![3],
+            Type::Bytes => vec![18],
             Type::List(t) => {
                 let mut res = vec![4];
                 res.extend(t.id());
                 res
             }
+            Type::Array(t, len) => {
+                let mut res = vec![20];
+                res.extend(t.id());
+                res.extend((*len as u64).to_le_bytes());
+                res
+            }
             Type::Struct(def) => {
                 let mut res = vec![6];
                 res.extend(def.id());
                 res
             }
+            Type::Enum(def) => {
+                let mut res = vec![19];
+                res.extend(def.id());
+                res
+            }
+            Type::Map(k, v) => {
+                let mut res = vec![15];
+                res.extend(k.id());
+                res.extend(v.id());
+                res
+            }
+            Type::Optional(t) => {
+                let mut res = vec![16];
+                res.extend(t.id());
+                res
+            }
+            Type::Recursive(name) => {
+                let mut res = vec![17];
+                res.extend(crate::object::var_int(name.len()));
+                res.extend(name.bytes());
+                res
+            }
         }
     }
 
-    fn parse<'i>(&self, bytes: &'i [u8]) -> Result<(&'i [u8], Value), Error<'i>> {
+    /// Parses a type written as in a schema, e.g. `List<u64>`, resolving
+    /// struct names and aliases against `parser`.
+    pub fn parse_with(s: &str, parser: &Parser) -> Result<Type, Error> {
+        definition_parser::parse_type(s, parser)
+    }
+
+    /// Encoded size of a value of this type with the default
+    /// [`EncodeOptions`](crate::EncodeOptions).
+    pub fn size_hint(&self) -> SizeHint {
+        match self {
+            Type::Unit => SizeHint::Fixed(0),
+            Type::Bool | Type::U8 | Type::I8 => SizeHint::Fixed(1),
+            Type::U16 | Type::I16 => SizeHint::Fixed(2),
+            Type::U32 | Type::I32 | Type::F32 | Type::Enum(_) => SizeHint::Fixed(4),
+            Type::U64 | Type::I64 | Type::F64 => SizeHint::Fixed(8),
+            // A one byte length prefix or presence byte, then nothing.
+            Type::String | Type::Bytes | Type::List(_) | Type::Map(..) | Type::Optional(_) => {
+                SizeHint::Variable { min: 1 }
+            }
+            Type::Array(t, len) => match t.size_hint() {
+                SizeHint::Fixed(size) => SizeHint::fixed(size.checked_mul(*len)),
+                SizeHint::Variable { min } => SizeHint::Variable {
+                    min: min.saturating_mul(*len),
+                },
+            },
+            Type::Struct(def) => def.size_hint(),
+            // Only reachable under one of the above, which stop early.
+            Type::Recursive(_) => SizeHint::Variable { min: 0 },
+        }
+    }
+
+    pub(crate) fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            Type::U8
+                | Type::U16
+                | Type::U32
+                | Type::U64
+                | Type::I8
+                | Type::I16
+                | Type::I32
+                | Type::I64
+        )
+    }
+
+    /// Integer types with a varint form, see [`FieldAttributes::varint`].
+    pub(crate) fn has_varint_form(&self) -> bool {
+        matches!(self, Type::U32 | Type::U64 | Type::I32 | Type::I64)
+    }
+
+    /// Parses the varint form of the type, or `None` if it has none.
+    fn parse_varint<'s, 'i>(
+        &self,
+        bytes: &'i [u8],
+    ) -> Option<Result<(&'i [u8], Value<'s>), Error>> {
+        let (bits, value): (_, fn(u64) -> Value<'s>) = match self {
+            Type::U32 => (32, |n| Value::U32(n as u32)),
+            Type::U64 => (64, Value::U64),
+            Type::I32 => (32, |n| Value::I32(unzigzag(n) as i32)),
+            Type::I64 => (64, |n| Value::I64(unzigzag(n))),
+            _ => return None,
+        };
+        Some(leb128(bytes, bits).map(|(bytes, n)| (bytes, value(n))))
+    }
+
+    /// Types that can be used as `Map` keys.
+    pub(crate) fn is_map_key(&self) -> bool {
+        self.is_integer() || matches!(self, Type::Bool | Type::String | Type::Bytes)
+    }
+
+    /// Whether both describe the same values, treating a struct and a
+    /// `Type::Recursive` reference to it as one. See
+    /// [`StructDef::same_layout`].
+    fn same_layout(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::List(a), Type::List(b)) | (Type::Optional(a), Type::Optional(b)) => {
+                a.same_layout(b)
+            }
+            (Type::Array(a, a_len), Type::Array(b, b_len)) => a_len == b_len && a.same_layout(b),
+            (Type::Map(a_key, a_value), Type::Map(b_key, b_value)) => {
+                a_key.same_layout(b_key) && a_value.same_layout(b_value)
+            }
+            (Type::Struct(a), Type::Struct(b)) => a.same_layout(b),
+            (Type::Struct(def), Type::Recursive(name))
+            | (Type::Recursive(name), Type::Struct(def)) => def.type_name == *name,
+            (a, b) => a == b,
+        }
+    }
+
+    fn collect_enums<'a>(&'a self, enums: &mut Vec<&'a EnumDef>) {
+        match self {
+            Type::List(t) | Type::Array(t, _) | Type::Optional(t) => t.collect_enums(enums),
+            Type::Map(k, v) => {
+                k.collect_enums(enums);
+                v.collect_enums(enums);
+            }
+            Type::Enum(def) if !enums.contains(&def.as_ref()) => enums.push(def),
+            // Nested structs are collected separately.
+            _ => {}
+        }
+    }
+
+    fn collect_dependencies<'a>(&'a self, defs: &mut Vec<&'a StructDef>) {
+        match self {
+            Type::List(t) | Type::Array(t, _) => t.collect_dependencies(defs),
+            Type::Map(_, v) => v.collect_dependencies(defs),
+            Type::Optional(t) => t.collect_dependencies(defs),
+            Type::Struct(def) => def.collect_dependencies(defs),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn parse<'i>(
+        &self,
+        bytes: &'i [u8],
+        options: &DecodeOptions,
+    ) -> Result<(&'i [u8], Value), Error> {
+        self.parse_within(bytes, options, None)
+    }
+
+    fn parse_within<'s, 'i>(
+        &'s self,
+        bytes: &'i [u8],
+        options: &DecodeOptions,
+        enclosing: Option<&Enclosing<'_, 's>>,
+    ) -> Result<(&'i [u8], Value<'s>), Error> {
         use nom::number::complete;
+        let endian = options.endianness.into();
+
+        if options.varint_integers {
+            if let Some(parsed) = self.parse_varint(bytes) {
+                return parsed;
+            }
+        }
 
         match self {
             Type::Unit => Ok((bytes, Value::Unit)),
             Type::Bool => {
-                let (b, n) = complete::le_u8(bytes).map_err(Error::ValueParsing)?;
+                let (b, n) = complete::le_u8(bytes).map_err(Error::from)?;
                 let value = match n {
                     0 => false,
                     1 => true,
                     _ => {
-                        return Err(Error::ValueParsing(nom::Err::Error(
-                            nom::error::make_error(bytes, nom::error::ErrorKind::IsNot),
-                        )));
+                        return Err(Error::from(nom::Err::Error(nom::error::make_error(
+                            bytes,
+                            nom::error::ErrorKind::IsNot,
+                        ))));
                     }
                 };
                 Ok((b, Value::Bool(value)))
             }
             Type::U8 => complete::le_u8(bytes).map(|(b, n)| (b, Value::U8(n))),
-            Type::U32 => complete::le_u32(bytes).map(|(b, n)| (b, Value::U32(n))),
-            Type::U64 => complete::le_u64(bytes).map(|(b, n)| (b, Value::U64(n))),
+            Type::U16 => complete::u16(endian)(bytes).map(|(b, n)| (b, Value::U16(n))),
+            Type::U32 => complete::u32(endian)(bytes).map(|(b, n)| (b, Value::U32(n))),
+            Type::U64 => complete::u64(endian)(bytes).map(|(b, n)| (b, Value::U64(n))),
+            Type::I8 => complete::le_i8(bytes).map(|(b, n)| (b, Value::I8(n))),
+            Type::I16 => complete::i16(endian)(bytes).map(|(b, n)| (b, Value::I16(n))),
+            Type::I32 => complete::i32(endian)(bytes).map(|(b, n)| (b, Value::I32(n))),
+            Type::I64 => complete::i64(endian)(bytes).map(|(b, n)| (b, Value::I64(n))),
+            Type::F32 => complete::f32(endian)(bytes).map(|(b, n)| (b, Value::F32(n))),
+            Type::F64 => complete::f64(endian)(bytes).map(|(b, n)| (b, Value::F64(n))),
             Type::String => {
-                let (bytes, str_bytes) = length_prefixed(bytes).map_err(Error::ValueParsing)?;
+                let (bytes, str_bytes) = length_prefixed(bytes)?;
                 let s = std::str::from_utf8(str_bytes)?;
 
                 Ok((bytes, Value::String(String::from(s))))
             }
+            Type::Bytes => {
+                let (bytes, raw) = length_prefixed(bytes)?;
+                Ok((bytes, Value::Bytes(raw.to_vec())))
+            }
             Type::List(t) => {
-                let (bytes, mut list_bytes) =
-                    length_prefixed(bytes).map_err(Error::ValueParsing)?;
+                let options = &options.nested()?;
+                let (bytes, mut list_bytes) = length_prefixed(bytes)?;
 
                 let mut items = Vec::new();
                 while !list_bytes.is_empty() {
-                    let (b, item) = t.parse(list_bytes)?;
+                    let (b, item) = t.parse_within(list_bytes, options, enclosing)?;
+                    check_progress(list_bytes, b)?;
                     list_bytes = b;
                     items.push(item);
                 }
                 Ok((bytes, Value::List(items)))
             }
+            Type::Array(t, len) => {
+                let options = &options.nested()?;
+                let mut bytes = bytes;
+                let mut items = Vec::with_capacity((*len).min(bytes.len()));
+                for _ in 0..*len {
+                    let (b, item) = t.parse_within(bytes, options, enclosing)?;
+                    bytes = b;
+                    items.push(item);
+                }
+                Ok((bytes, Value::Array(items)))
+            }
+            Type::Map(k, v) => {
+                let options = &options.nested()?;
+                let (bytes, mut map_bytes) = length_prefixed(bytes)?;
+
+                let mut entries = Vec::new();
+                let mut previous = None;
+                while !map_bytes.is_empty() {
+                    let (b, key) = k.parse_within(map_bytes, options, enclosing)?;
+                    let key_bytes = &map_bytes[..map_bytes.len() - b.len()];
+                    check_key_order(previous, key_bytes, entries.len())?;
+                    previous = Some(key_bytes);
+                    let (b, value) = v.parse_within(b, options, enclosing)?;
+                    check_progress(map_bytes, b)?;
+                    map_bytes = b;
+                    entries.push((key, value));
+                }
+                Ok((bytes, Value::Map(entries)))
+            }
+            Type::Optional(t) => {
+                let (after_flag, present) = complete::le_u8(bytes).map_err(Error::from)?;
+                match present {
+                    0 => Ok((after_flag, Value::Optional(None))),
+                    1 => {
+                        let (bytes, value) =
+                            t.parse_within(after_flag, &options.nested()?, enclosing)?;
+                        Ok((bytes, Value::Optional(Some(Box::new(value)))))
+                    }
+                    _ => Err(nom::Err::Error(nom::error::make_error(
+                        bytes,
+                        nom::error::ErrorKind::IsNot,
+                    ))),
+                }
+            }
             Type::Struct(def) => {
-                let (bytes, obj) = def.parse(bytes)?;
+                let (bytes, obj) = def.parse_within(bytes, &options.nested()?, enclosing)?;
                 Ok((bytes, Value::Struct(obj)))
             }
+            Type::Enum(def) => {
+                let (bytes, n) = complete::u32(endian)(bytes).map_err(Error::from)?;
+                let n = options.unknown_enum.apply(def, n)?;
+                Ok((bytes, Value::Enum(Arc::clone(def), n)))
+            }
+            Type::Recursive(name) => {
+                let def = enclosing.and_then(|e| e.find(name)).ok_or_else(|| {
+                    Error::UnrecognizedType {
+                        name: name.clone(),
+                        span: None,
+                    }
+                })?;
+                let (bytes, obj) = def.parse_within(bytes, &options.nested()?, enclosing)?;
+                Ok((bytes, Value::Struct(obj)))
+            }
+        }
+        .map_err(Error::from)
+    }
+}
+
+/// Fails if a list item or map entry took no bytes, as one of a zero-width
+/// type such as `()` does; the rest of the list would never shrink.
+fn check_progress(before: &[u8], after: &[u8]) -> Result<(), Error> {
+    if after.len() == before.len() {
+        return Err(Error::from(nom::Err::Error(nom::error::make_error(
+            before,
+            nom::error::ErrorKind::Many0,
+        ))));
+    }
+    Ok(())
+}
+
+/// Fails unless `key`, the encoded key of map entry `index`, comes after
+/// `previous`, that of the entry before.
+pub(crate) fn check_key_order(
+    previous: Option<&[u8]>,
+    key: &[u8],
+    index: usize,
+) -> Result<(), Error> {
+    match previous {
+        Some(previous) if previous >= key => Err(Error::UnorderedMapKey { index }),
+        _ => Ok(()),
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Unit => write!(f, "()"),
+            Type::Bool => write!(f, "bool"),
+            Type::U8 => write!(f, "u8"),
+            Type::U16 => write!(f, "u16"),
+            Type::U32 => write!(f, "u32"),
+            Type::U64 => write!(f, "u64"),
+            Type::I8 => write!(f, "i8"),
+            Type::I16 => write!(f, "i16"),
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::F32 => write!(f, "f32"),
+            Type::F64 => write!(f, "f64"),
+            Type::String => write!(f, "string"),
+            Type::Bytes => write!(f, "bytes"),
+            Type::List(t) => write!(f, "List<{}>", t),
+            Type::Array(t, len) => write!(f, "Array<{}, {}>", t, len),
+            Type::Map(k, v) => write!(f, "Map<{}, {}>", k, v),
+            Type::Optional(t) => write!(f, "Optional<{}>", t),
+            Type::Struct(def) => write!(f, "{}", def.type_name),
+            Type::Enum(def) => write!(f, "{}", def.type_name),
+            Type::Recursive(name) => write!(f, "{}", name),
         }
-        .map_err(Error::ValueParsing)
+    }
+}
+
+/// Parses a type that only uses primitives and generics; struct names fail
+/// with [`Error::UnrecognizedType`]. Use [`Type::parse_with`] to resolve them.
+impl FromStr for Type {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Type::parse_with(s, &Parser::default())
     }
 }
 
@@ -212,6 +1254,7 @@ mod tests {
             fields: vec![FieldDef {
                 name: "bar".to_string(),
                 type_: Type::U64,
+                attributes: Default::default(),
             }],
         };
 
@@ -227,14 +1270,17 @@ mod tests {
                 FieldDef {
                     name: "bar".to_string(),
                     type_: Type::U8,
+                    attributes: Default::default(),
                 },
                 FieldDef {
                     name: "baz".to_string(),
                     type_: Type::U8,
+                    attributes: Default::default(),
                 },
                 FieldDef {
                     name: "qux".to_string(),
                     type_: Type::U8,
+                    attributes: Default::default(),
                 },
             ],
         };
@@ -252,6 +1298,7 @@ mod tests {
             fields: vec![FieldDef {
                 name: "bar".to_string(),
                 type_: Type::U32,
+                attributes: Default::default(),
             }],
         };
 
@@ -259,6 +1306,343 @@ mod tests {
         assert!(matches!(result, Err(Error::ValueParsing(_))));
     }
 
+    mod zero_width_items {
+        use super::*;
+
+        fn decode(source: &str, body: &[u8]) -> Result<(), Error> {
+            let parser = Parser::from_schema(source).unwrap();
+            let def = parser.get("Foo").unwrap();
+            let mut bytes = def.id().to_vec();
+            bytes.extend_from_slice(body);
+            def.decode(&bytes).map(|_| ())
+        }
+
+        fn no_progress(e: Result<(), Error>) -> bool {
+            matches!(e, Err(Error::ValueParsing(nom::Err::Error(e)))
+                if e.code == nom::error::ErrorKind::Many0)
+        }
+
+        #[test]
+        fn list() {
+            assert!(no_progress(decode("struct Foo { a :List<()>; }", &[1, 0])));
+            assert!(no_progress(decode(
+                "struct E {} struct Foo { a :List<E>; }",
+                &[1, 0]
+            )));
+            assert_eq!(decode("struct Foo { a :List<()>; }", &[0]), Ok(()));
+        }
+
+        /// Map keys always take a byte or more, but a hand-built
+        /// `StructDef` needn't be checked.
+        #[test]
+        fn map() {
+            let def = StructDef {
+                type_name: "Foo".to_string(),
+                fields: vec![FieldDef {
+                    name: "a".to_string(),
+                    type_: Type::Map(Box::new(Type::Unit), Box::new(Type::Unit)),
+                    attributes: Default::default(),
+                }],
+            };
+            assert!(no_progress(def.parse(&[1, 0]).map(|_| ())));
+        }
+    }
+
+    #[cfg(test)]
+    mod struct_def_builder {
+        use super::*;
+
+        #[test]
+        fn builds_fields_in_order() {
+            let built = StructDefBuilder::new("Foo")
+                .field("bar", Type::U8)
+                .field("baz", Type::List(Box::new(Type::U32)))
+                .build()
+                .unwrap();
+
+            assert_eq!(
+                built,
+                StructDef {
+                    type_name: "Foo".to_string(),
+                    fields: vec![
+                        FieldDef {
+                            name: "bar".to_string(),
+                            type_: Type::U8,
+                            attributes: Default::default(),
+                        },
+                        FieldDef {
+                            name: "baz".to_string(),
+                            type_: Type::List(Box::new(Type::U32)),
+                            attributes: Default::default(),
+                        },
+                    ],
+                }
+            );
+        }
+
+        #[test]
+        fn duplicate_field() {
+            let result = StructDefBuilder::new("Foo")
+                .field("bar", Type::U8)
+                .field("bar", Type::U64)
+                .build();
+
+            assert_eq!(
+                result,
+                Err(Error::DuplicateField {
+                    name: "bar".to_string(),
+                    span: None,
+                })
+            );
+        }
+
+        #[test]
+        fn empty_names() {
+            assert_eq!(
+                StructDefBuilder::new("").build(),
+                Err(Error::EmptyName { span: None })
+            );
+            assert_eq!(
+                StructDefBuilder::new("Foo").field("", Type::U8).build(),
+                Err(Error::EmptyName { span: None })
+            );
+        }
+
+        #[test]
+        fn keywords_name_fields_but_not_structs() {
+            assert!(StructDefBuilder::new("Foo")
+                .field("type", Type::U8)
+                .build()
+                .is_ok());
+            assert_eq!(
+                StructDefBuilder::new("include").build(),
+                Err(Error::ReservedName {
+                    name: "include".to_string(),
+                    span: None,
+                })
+            );
+        }
+
+        #[test]
+        fn reserved_names() {
+            assert_eq!(
+                StructDefBuilder::new("Optional").build(),
+                Err(Error::ReservedName {
+                    name: "Optional".to_string(),
+                    span: None,
+                })
+            );
+            assert_eq!(
+                StructDefBuilder::new("Foo").field("u8", Type::U8).build(),
+                Err(Error::ReservedName {
+                    name: "u8".to_string(),
+                    span: None,
+                })
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod constraints {
+        use super::*;
+
+        fn person() -> StructDef {
+            StructDef {
+                type_name: "Person".to_string(),
+                fields: vec![
+                    FieldDef {
+                        name: "age".to_string(),
+                        type_: Type::U8,
+                        attributes: FieldAttributes {
+                            range: Some((0, 150)),
+                            ..Default::default()
+                        },
+                    },
+                    FieldDef {
+                        name: "name".to_string(),
+                        type_: Type::String,
+                        attributes: FieldAttributes {
+                            max_len: Some(3),
+                            ..Default::default()
+                        },
+                    },
+                ],
+            }
+        }
+
+        #[test]
+        fn within_constraints() {
+            let parsed = person()
+                .parse(&[150, 3, 65, 66, 67])
+                .map(|(_, o)| o["age"].as_u8());
+            assert_eq!(parsed, Ok(Some(150)));
+        }
+
+        #[test]
+        fn out_of_range() {
+            assert_eq!(
+                person().parse(&[151, 0]).unwrap_err(),
+                Error::ConstraintViolation {
+                    field: "Person.age".to_string(),
+                    detail: "151 is outside 0..=150".to_string(),
+                }
+            );
+        }
+
+        #[test]
+        fn too_long() {
+            assert_eq!(
+                person().parse(&[42, 4, 65, 66, 67, 68]).unwrap_err(),
+                Error::ConstraintViolation {
+                    field: "Person.name".to_string(),
+                    detail: "length 4 exceeds 3".to_string(),
+                }
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod decode_partial {
+        use super::*;
+
+        fn reading() -> StructDef {
+            StructDefBuilder::new("Reading")
+                .field("sensor", Type::U8)
+                .field("reading", Type::U32)
+                .field("label", Type::String)
+                .build()
+                .unwrap()
+        }
+
+        fn message(schema: &StructDef, body: &[u8]) -> Vec<u8> {
+            let mut bytes = schema.id().to_vec();
+            bytes.extend_from_slice(body);
+            bytes
+        }
+
+        #[test]
+        fn complete() {
+            let schema = reading();
+            let bytes = message(&schema, &[1, 2, 0, 0, 0, 1, 65]);
+            let (partial, error) = schema.decode_partial(&bytes);
+            assert_eq!(error, None);
+            assert_eq!(partial.get("label").and_then(Value::as_string), Some("A"));
+
+            let object = partial.into_object().unwrap();
+            assert_eq!(object.serialize(), bytes);
+        }
+
+        #[test]
+        fn truncated_in_third_field() {
+            let schema = reading();
+            let (partial, error) =
+                schema.decode_partial(&message(&schema, &[1, 2, 0, 0, 0, 3, 65]));
+            assert_eq!(partial.get("sensor").and_then(Value::as_u8), Some(1));
+            assert_eq!(partial.get("reading").and_then(Value::as_u32), Some(2));
+            assert_eq!(partial.get("label"), None);
+            assert!(!partial.is_complete());
+            assert!(matches!(
+                error,
+                Some(Error::FieldDecoding { field, .. }) if field == "Reading.label"
+            ));
+
+            assert_eq!(
+                partial.to_json(),
+                Ok(serde_json::json!({ "sensor": 1, "reading": 2 }))
+            );
+            // Nothing to serialize it with until every field is there.
+            assert!(partial.into_object().is_err());
+        }
+
+        #[test]
+        fn checks_the_id() {
+            let schema = reading();
+            let (partial, error) = schema.decode_partial(&[1, 2, 0, 0, 0, 1, 65, 0]);
+            assert_eq!(partial.decoded().count(), 0);
+            assert!(matches!(error, Some(Error::MissingId(_))));
+
+            let (_, error) = schema.decode_partial(&schema.id()[..7]);
+            assert_eq!(error, Some(Error::TooFewBytes));
+        }
+
+        #[test]
+        fn honours_options() {
+            let schema = reading();
+            let options = DecodeOptions {
+                field_count_header: true,
+                endianness: crate::Endianness::Big,
+                skip_trailing_fields: true,
+                ..Default::default()
+            };
+            let bytes = message(&schema, &[3, 1, 0, 0, 0, 2, 1, 65, 9]);
+
+            let (object, error) = schema.decode_partial_with(&bytes, &options);
+            assert_eq!(error, None);
+            assert_eq!(object.get("reading").and_then(Value::as_u32), Some(2));
+            assert_eq!(object.get("label").and_then(Value::as_string), Some("A"));
+
+            let bytes = message(&schema, &[1, 0, 0, 0, 2, 1, 65, 9]);
+            let (object, error) = schema.decode_partial_with(&bytes, &options);
+            assert_eq!(
+                error,
+                Some(Error::FieldCountMismatch {
+                    expected: 3,
+                    found: 1
+                })
+            );
+            assert_eq!(object.get("sensor"), None);
+        }
+    }
+
+    #[cfg(test)]
+    mod from_str {
+        use super::*;
+
+        #[test]
+        fn primitive() {
+            assert_eq!("u64".parse(), Ok(Type::U64));
+        }
+
+        #[test]
+        fn list() {
+            assert_eq!(
+                "List<string>".parse(),
+                Ok(Type::List(Box::new(Type::String)))
+            );
+        }
+
+        #[test]
+        fn nested_list() {
+            assert_eq!(
+                "List<List<u8>>".parse(),
+                Ok(Type::List(Box::new(Type::List(Box::new(Type::U8)))))
+            );
+        }
+
+        #[test]
+        fn unresolved_struct() {
+            assert_eq!(
+                "Header".parse::<Type>(),
+                Err(Error::UnrecognizedType {
+                    name: "Header".to_string(),
+                    span: Some(crate::Span { start: 0, end: 6 }),
+                })
+            );
+        }
+
+        #[test]
+        fn struct_from_parser() {
+            let mut parser = Parser::default();
+            parser.add_file_defs("struct Header { seq :u32; }").unwrap();
+            let header = parser.struct_def("Header").unwrap().clone();
+
+            assert_eq!(
+                Type::parse_with("List<Header>", &parser),
+                Ok(Type::List(Box::new(Type::Struct(header))))
+            );
+        }
+    }
+
     #[cfg(test)]
     mod id {
         use super::*;
@@ -270,6 +1654,7 @@ mod tests {
                 fields: vec![FieldDef {
                     name: "bar".to_string(),
                     type_: Type::U8,
+                    attributes: Default::default(),
                 }],
             };
             let struct_b = StructDef {
@@ -277,6 +1662,7 @@ mod tests {
                 fields: vec![FieldDef {
                     name: "baz".to_string(),
                     type_: Type::U8,
+                    attributes: Default::default(),
                 }],
             };
 
@@ -290,6 +1676,7 @@ mod tests {
                 fields: vec![FieldDef {
                     name: "bar".to_string(),
                     type_: Type::U8,
+                    attributes: Default::default(),
                 }],
             };
             let struct_b = StructDef {
@@ -297,6 +1684,7 @@ mod tests {
                 fields: vec![FieldDef {
                     name: "bar".to_string(),
                     type_: Type::U32,
+                    attributes: Default::default(),
                 }],
             };
 
@@ -310,6 +1698,7 @@ mod tests {
                 fields: vec![FieldDef {
                     name: "bar".to_string(),
                     type_: Type::U8,
+                    attributes: Default::default(),
                 }],
             };
             let struct_b = StructDef {
@@ -317,6 +1706,7 @@ mod tests {
                 fields: vec![FieldDef {
                     name: "bar".to_string(),
                     type_: Type::U8,
+                    attributes: Default::default(),
                 }],
             };
 
@@ -330,6 +1720,7 @@ mod tests {
                 fields: vec![FieldDef {
                     name: "bar".to_string(),
                     type_: Type::List(Box::new(Type::U8)),
+                    attributes: Default::default(),
                 }],
             };
             let struct_b = StructDef {
@@ -337,6 +1728,7 @@ mod tests {
                 fields: vec![FieldDef {
                     name: "bar".to_string(),
                     type_: Type::List(Box::new(Type::U32)),
+                    attributes: Default::default(),
                 }],
             };
 
@@ -353,6 +1745,7 @@ mod tests {
                         type_name: "Bar".to_string(),
                         fields: vec![],
                     })),
+                    attributes: Default::default(),
                 }],
             };
             let struct_b = StructDef {
@@ -363,6 +1756,7 @@ mod tests {
                         type_name: "Baz".to_string(),
                         fields: vec![],
                     })),
+                    attributes: Default::default(),
                 }],
             };
 
@@ -370,6 +1764,125 @@ mod tests {
         }
     }
 
+    mod field_index {
+        use super::*;
+
+        const SOURCE: &str = r#"
+        struct Header { id :u64; }
+        struct Foo includes Header { zebra :u8; apple :string; mango :bool; }
+        "#;
+
+        fn def() -> Arc<StructDef> {
+            let mut parser = Parser::default();
+            parser.add_file_defs(SOURCE).unwrap();
+            Arc::clone(parser.struct_def("Foo").unwrap())
+        }
+
+        #[test]
+        fn follows_definition_order() {
+            let def = def();
+            for (i, name) in ["id", "zebra", "apple", "mango"].iter().enumerate() {
+                assert_eq!(def.field_index(name), Some(i));
+                assert_eq!(def.field(i).map(FieldDef::name), Some(*name));
+            }
+        }
+
+        #[test]
+        fn unknown() {
+            let def = def();
+            assert_eq!(def.field_index("missing"), None);
+            assert!(def.field(4).is_none());
+        }
+    }
+
+    mod size_hint {
+        use super::*;
+
+        fn def(source: &str) -> Arc<StructDef> {
+            let mut parser = Parser::default();
+            parser.add_file_defs(source).unwrap();
+            Arc::clone(parser.struct_def("Foo").unwrap())
+        }
+
+        #[test]
+        fn all_fixed() {
+            let def =
+                def("struct Inner { x :f32; } struct Foo { a :u8; b :i64; c :Inner; d :(); }");
+            assert_eq!(def.size_hint(), SizeHint::Fixed(13));
+
+            let inner = match def.fields()[2].type_() {
+                Type::Struct(inner) => inner.builder().set("x", 1.5f32).try_build().unwrap(),
+                t => panic!("expected a struct, got {}", t),
+            };
+            let message = def
+                .builder()
+                .set("a", 1u8)
+                .set("b", 2i64)
+                .set("c", inner)
+                .set("d", ())
+                .try_build()
+                .unwrap();
+            assert_eq!(message.serialize().len(), 8 + 13);
+        }
+
+        #[test]
+        fn with_string() {
+            let def = def("struct Foo { a :u32; name :string; tags :List<u64>; }");
+            assert_eq!(def.size_hint(), SizeHint::Variable { min: 6 });
+        }
+
+        #[test]
+        fn overflow_saturates() {
+            let huge = Type::Array(Box::new(Type::U64), usize::MAX);
+            assert_eq!(huge.size_hint(), SizeHint::Variable { min: usize::MAX });
+
+            let strings = Type::Array(Box::new(Type::String), usize::MAX);
+            assert_eq!(strings.size_hint(), SizeHint::Variable { min: usize::MAX });
+
+            let sum = SizeHint::Fixed(usize::MAX) + SizeHint::Fixed(1);
+            assert_eq!(sum, SizeHint::Variable { min: usize::MAX });
+        }
+    }
+
+    mod fingerprint {
+        use super::*;
+
+        const BASE: &'static str = "struct Inner { x :u8; } struct Foo { a :u32; b :Inner; }";
+        // Pinned so that a change to the hash shows up as a test failure
+        // rather than as peers that can no longer talk to each other.
+        const FINGERPRINT: [u8; 8] = [112, 16, 46, 25, 170, 150, 192, 139];
+
+        fn fingerprint(source: &str) -> [u8; 8] {
+            let mut parser = Parser::default();
+            parser.add_file_defs(source).unwrap();
+            parser.struct_def("Foo").unwrap().fingerprint()
+        }
+
+        #[test]
+        fn is_stable() {
+            assert_eq!(fingerprint(BASE), fingerprint(BASE));
+            assert_eq!(
+                fingerprint(BASE),
+                fingerprint("struct Foo { a :u32; b :Inner; }\nstruct Inner {\n    x :u8;\n}")
+            );
+            assert_eq!(fingerprint(BASE), FINGERPRINT);
+        }
+
+        #[test]
+        fn changes_with_layout() {
+            let changed = [
+                "struct Inner { x :u8; } struct Foo { a :u32; b :Inner; c :u8; }",
+                "struct Inner { x :u8; } struct Foo { z :u32; b :Inner; }",
+                "struct Inner { x :u8; } struct Foo { a :u64; b :Inner; }",
+                "struct Inner { x :u8; } struct Foo { b :Inner; a :u32; }",
+                "struct Inner { x :u16; } struct Foo { a :u32; b :Inner; }",
+            ];
+            for source in &changed {
+                assert_ne!(fingerprint(source), fingerprint(BASE), "{}", source);
+            }
+        }
+    }
+
     #[cfg(test)]
     mod parsing {
         use super::*;
@@ -383,6 +1896,7 @@ mod tests {
                 let field = FieldDef {
                     name: "foo".to_string(),
                     type_: Type::String,
+                    attributes: Default::default(),
                 };
 
                 assert_eq!(
@@ -396,6 +1910,7 @@ mod tests {
                 let field = FieldDef {
                     name: "foo".to_string(),
                     type_: Type::String,
+                    attributes: Default::default(),
                 };
 
                 assert_eq!(
@@ -409,12 +1924,13 @@ mod tests {
                 let field = FieldDef {
                     name: "foo".to_string(),
                     type_: Type::String,
+                    attributes: Default::default(),
                 };
 
-                assert!(matches!(
+                assert_eq!(
                     field.parse(&[4, 0, 159, 146, 150]),
-                    Err(Error::InvalidUtf8(_))
-                ));
+                    Err(Error::InvalidUtf8 { offset: 1 })
+                );
             }
 
             #[test]
@@ -422,6 +1938,7 @@ mod tests {
                 let field = FieldDef {
                     name: "foo".to_string(),
                     type_: Type::String,
+                    attributes: Default::default(),
                 };
 
                 assert_eq!(
@@ -435,6 +1952,7 @@ mod tests {
                 let field = FieldDef {
                     name: "foo".to_string(),
                     type_: Type::String,
+                    attributes: Default::default(),
                 };
 
                 assert!(matches!(field.parse(&[]), Err(Error::ValueParsing(_))));
@@ -445,12 +1963,16 @@ mod tests {
                 let field = FieldDef {
                     name: "foo".to_string(),
                     type_: Type::String,
+                    attributes: Default::default(),
                 };
 
-                assert!(matches!(
+                assert_eq!(
                     field.parse(&[3, 65, 66]),
-                    Err(Error::ValueParsing(_))
-                ));
+                    Err(Error::LengthExceedsBuffer {
+                        claimed: 3,
+                        remaining: 2
+                    })
+                );
             }
 
             #[test]
@@ -460,12 +1982,14 @@ mod tests {
                     fields: vec![FieldDef {
                         name: "bar".to_string(),
                         type_: Type::U8,
+                        attributes: Default::default(),
                     }],
                 });
 
                 let field = FieldDef {
                     name: "foo".to_string(),
                     type_: Type::Struct(struct_),
+                    attributes: Default::default(),
                 };
 
                 let value = field.parse(&[42]).unwrap().1;
@@ -0,0 +1,558 @@
+use std::io::{self, Read, Write};
+
+use core::convert::TryFrom;
+
+use crate::{
+    schema::{self, SizeHint},
+    DecodeOptions, Error, Object, StructDef, Type, Value, MAX_FRAME_LEN,
+};
+
+/// Decodes a message from `reader`, reading only as far as its last byte.
+/// Lists and maps are read item by item under their length prefix, so nothing
+/// bigger than a single string or byte blob is buffered. Everything else is
+/// read as the bytes of one value and decoded by the slice decoder, so both
+/// agree on every detail of the encoding.
+pub(crate) fn decode_from<'d>(
+    def: &'d StructDef,
+    reader: &mut dyn Read,
+    options: &DecodeOptions,
+) -> Result<Object<'d>, Error> {
+    let mut id = [0; 8];
+    read_exact(reader, &mut id, "<id>")?;
+    def.check_id(&id, options)?;
+
+    struct_(reader, def, "", options, &mut Vec::new())
+}
+
+/// Writes `buf` preceded by its length as a little-endian u32, for sending
+/// messages over a stream. See [`read_framed`].
+pub fn write_framed<W: Write>(buf: &[u8], w: &mut W) -> Result<(), Error> {
+    let len = u32::try_from(buf.len()).map_err(|_| Error::FrameTooLarge {
+        len: buf.len(),
+        max: u32::MAX as usize,
+    })?;
+    w.write_all(&len.to_le_bytes())
+        .and_then(|()| w.write_all(buf))
+        .map_err(|e| Error::Io(e.kind()))
+}
+
+/// Reads one frame written by [`write_framed`], rejecting frames longer than
+/// [`MAX_FRAME_LEN`].
+pub fn read_framed<R: Read>(r: &mut R) -> Result<Vec<u8>, Error> {
+    read_framed_with_limit(r, MAX_FRAME_LEN)
+}
+
+/// Like [`read_framed`], rejecting frames longer than `max_len` with
+/// [`Error::FrameTooLarge`] before reading any of the frame.
+pub fn read_framed_with_limit<R: Read>(r: &mut R, max_len: usize) -> Result<Vec<u8>, Error> {
+    let len = u32::from_le_bytes(fixed(r, "<frame len>")?) as usize;
+    if len > max_len {
+        return Err(Error::FrameTooLarge { len, max: max_len });
+    }
+    read_payload(r, len, "<frame>")
+}
+
+/// `enclosing` holds the structs being decoded, innermost last, for
+/// resolving `Type::Recursive`.
+fn struct_<'d>(
+    reader: &mut dyn Read,
+    def: &'d StructDef,
+    path: &str,
+    options: &DecodeOptions,
+    enclosing: &mut Vec<&'d StructDef>,
+) -> Result<Object<'d>, Error> {
+    if options.field_count_header {
+        def.check_field_count(var_int(reader, path)?)?;
+    }
+
+    enclosing.push(def);
+    let mut values = Vec::with_capacity(def.fields().len());
+    for field in def.fields() {
+        let field_path = if path.is_empty() {
+            field.name().to_string()
+        } else {
+            format!("{}.{}", path, field.name())
+        };
+        let value = if field.is_varint() {
+            let bytes = leaf_bytes(reader, field.type_(), &field_path, true)?;
+            field.parse_with(&bytes, options, None)?.1
+        } else {
+            self::value(reader, field.type_(), &field_path, options, enclosing)?
+        };
+        def.check_constraints(field, &value)?;
+        values.push(value);
+    }
+    enclosing.pop();
+    Ok(Object::new(def, values))
+}
+
+fn value<'d>(
+    reader: &mut dyn Read,
+    type_: &'d Type,
+    path: &str,
+    options: &DecodeOptions,
+    enclosing: &mut Vec<&'d StructDef>,
+) -> Result<Value<'d>, Error> {
+    Ok(match type_ {
+        Type::List(t) => {
+            let options = &options.nested()?;
+            let len = var_int(reader, path)?;
+            let mut items_reader = reader.take(len as u64);
+
+            let mut items = Vec::new();
+            while items_reader.limit() > 0 {
+                let before = items_reader.limit();
+                let item_path = format!("{}[{}]", path, items.len());
+                items.push(self::value(
+                    &mut items_reader,
+                    t,
+                    &item_path,
+                    options,
+                    enclosing,
+                )?);
+                check_progress(before, items_reader.limit())?;
+            }
+            Value::List(items)
+        }
+        Type::Array(t, len) => {
+            let options = &options.nested()?;
+            let mut items = Vec::new();
+            for index in 0..*len {
+                let item_path = format!("{}[{}]", path, index);
+                items.push(self::value(reader, t, &item_path, options, enclosing)?);
+            }
+            Value::Array(items)
+        }
+        Type::Map(k, v) => {
+            let options = &options.nested()?;
+            let len = var_int(reader, path)?;
+            let mut entries_reader = reader.take(len as u64);
+
+            let mut entries = Vec::new();
+            let mut previous: Option<Vec<u8>> = None;
+            while entries_reader.limit() > 0 {
+                let before = entries_reader.limit();
+                let entry_path = format!("{}[{}]", path, entries.len());
+                let mut key_reader = Recording {
+                    reader: &mut entries_reader,
+                    bytes: Vec::new(),
+                };
+                let key = self::value(
+                    &mut key_reader,
+                    k,
+                    &format!("{}.key", entry_path),
+                    options,
+                    enclosing,
+                )?;
+                let key_bytes = key_reader.bytes;
+                schema::check_key_order(previous.as_deref(), &key_bytes, entries.len())?;
+                previous = Some(key_bytes);
+                let value = self::value(
+                    &mut entries_reader,
+                    v,
+                    &format!("{}.value", entry_path),
+                    options,
+                    enclosing,
+                )?;
+                check_progress(before, entries_reader.limit())?;
+                entries.push((key, value));
+            }
+            Value::Map(entries)
+        }
+        Type::Optional(t) => match fixed::<1>(reader, path)? {
+            [0] => Value::Optional(None),
+            [1] => {
+                let inner = self::value(reader, t, path, &options.nested()?, enclosing)?;
+                Value::Optional(Some(Box::new(inner)))
+            }
+            _ => return Err(malformed(nom::error::ErrorKind::IsNot)),
+        },
+        Type::Struct(def) => {
+            Value::Struct(struct_(reader, def, path, &options.nested()?, enclosing)?)
+        }
+        Type::Recursive(name) => {
+            let def = enclosing
+                .iter()
+                .rev()
+                .find(|d| d.type_name() == name)
+                .copied()
+                .ok_or_else(|| Error::UnrecognizedType {
+                    name: name.clone(),
+                    span: None,
+                })?;
+            Value::Struct(struct_(reader, def, path, &options.nested()?, enclosing)?)
+        }
+        leaf => {
+            let bytes = leaf_bytes(reader, leaf, path, options.varint_integers)?;
+            let (rest, value) = leaf.parse(&bytes, options)?;
+            debug_assert!(rest.is_empty(), "read more of {} than it took", path);
+            value
+        }
+    })
+}
+
+/// The bytes of one value of `type_`, which holds no other values. `varint`
+/// is whether integers take their varint form.
+fn leaf_bytes(
+    reader: &mut dyn Read,
+    type_: &Type,
+    path: &str,
+    varint: bool,
+) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    match type_ {
+        Type::String | Type::Bytes => {
+            let len = var_int_into(reader, path, &mut bytes)?;
+            bytes.extend(read_payload(reader, len, path)?);
+        }
+        Type::U32 | Type::I32 if varint => {
+            varint_bytes(reader, path, 5, &mut bytes)?;
+        }
+        Type::U64 | Type::I64 if varint => {
+            varint_bytes(reader, path, 10, &mut bytes)?;
+        }
+        t => match t.size_hint() {
+            SizeHint::Fixed(width) => {
+                bytes.resize(width, 0);
+                read_exact(reader, &mut bytes, path)?;
+            }
+            SizeHint::Variable { .. } => unreachable!("{} is not fixed width", t),
+        },
+    }
+    Ok(bytes)
+}
+
+fn fixed<const N: usize>(reader: &mut dyn Read, path: &str) -> Result<[u8; N], Error> {
+    let mut bytes = [0; N];
+    read_exact(reader, &mut bytes, path)?;
+    Ok(bytes)
+}
+
+/// Reads the payload in chunks as it arrives rather than allocating the
+/// claimed length up front, which may be corrupt.
+fn read_payload(reader: &mut dyn Read, len: usize, path: &str) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    reader
+        .take(len as u64)
+        .read_to_end(&mut bytes)
+        .map_err(|e| io_error(e, path))?;
+    if bytes.len() < len {
+        return Err(Error::UnexpectedEof {
+            field: path.to_string(),
+        });
+    }
+    Ok(bytes)
+}
+
+/// A length prefix, decoded by `schema::var_int`.
+fn var_int(reader: &mut dyn Read, path: &str) -> Result<usize, Error> {
+    var_int_into(reader, path, &mut Vec::new())
+}
+
+/// Like `var_int`, also appending the prefix's bytes to `bytes`.
+fn var_int_into(reader: &mut dyn Read, path: &str, bytes: &mut Vec<u8>) -> Result<usize, Error> {
+    let start = bytes.len();
+    // Ten bytes hold any 64-bit length; `schema::var_int` rejects the rest.
+    if !varint_bytes(reader, path, 10, bytes)? {
+        return Err(malformed(nom::error::ErrorKind::TooLarge));
+    }
+    let (_, len) = schema::var_int(&bytes[start..]).map_err(Error::from)?;
+    Ok(len)
+}
+
+/// Appends the bytes of a varint to `bytes`, stopping after `max_len` of
+/// them. Returns whether the last one ended the varint.
+fn varint_bytes(
+    reader: &mut dyn Read,
+    path: &str,
+    max_len: usize,
+    bytes: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    for _ in 0..max_len {
+        let [byte] = fixed::<1>(reader, path)?;
+        bytes.push(byte);
+        if byte & 0b1000_0000 == 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Keeps a copy of whatever is read through it.
+struct Recording<'r> {
+    reader: &'r mut dyn Read,
+    bytes: Vec<u8>,
+}
+
+impl Read for Recording<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn read_exact(reader: &mut dyn Read, buf: &mut [u8], path: &str) -> Result<(), Error> {
+    reader.read_exact(buf).map_err(|e| io_error(e, path))
+}
+
+fn io_error(e: io::Error, path: &str) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::UnexpectedEof {
+            field: path.to_string(),
+        },
+        kind => Error::Io(kind),
+    }
+}
+
+/// See `schema::check_progress`; `before` and `after` are the bytes left
+/// under the length prefix.
+fn check_progress(before: u64, after: u64) -> Result<(), Error> {
+    if after == before {
+        return Err(malformed(nom::error::ErrorKind::Many0));
+    }
+    Ok(())
+}
+
+/// The error the slice decoder gives for the same bytes, minus the input it
+/// would point at, which a stream no longer has.
+fn malformed(kind: nom::error::ErrorKind) -> Error {
+    Error::ValueParsing(nom::Err::Error(nom::error::make_error(Vec::new(), kind)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncodeOptions, Endianness, Parser};
+    use std::io::Cursor;
+
+    const SCHEMA: &'static str = r#"
+    struct Header {
+        id :u64;
+        tags :List<string>;
+    }
+
+    struct Message {
+        header :Header;
+        scores :Map<string, i32>;
+        reply_to :Optional<u32>;
+        body :string;
+    }
+    "#;
+
+    fn message(parser: &Parser) -> Vec<u8> {
+        message_with(parser, &EncodeOptions::default())
+    }
+
+    fn message_with(parser: &Parser, options: &EncodeOptions) -> Vec<u8> {
+        let header = parser
+            .struct_def("Header")
+            .unwrap()
+            .builder()
+            .set("id", 7u64)
+            .set(
+                "tags",
+                Value::List(vec![
+                    Value::String("a".to_string()),
+                    Value::String("bc".to_string()),
+                ]),
+            )
+            .try_build()
+            .unwrap();
+        parser
+            .struct_def("Message")
+            .unwrap()
+            .builder()
+            .set("header", header)
+            .set(
+                "scores",
+                Value::Map(vec![(Value::String("x".to_string()), Value::I32(-3))]),
+            )
+            .set("reply_to", Value::Optional(Some(Box::new(Value::U32(9)))))
+            .set("body", "hello".to_string())
+            .try_build()
+            .unwrap()
+            .serialize_with(options)
+    }
+
+    /// Hands out at most one byte per `read` call.
+    struct Trickle<'b>(&'b [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((&b, rest)), Some(out)) => {
+                    *out = b;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn from_cursor() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(SCHEMA).unwrap();
+        let def = parser.struct_def("Message").unwrap();
+        let mut bytes = message(&parser);
+        bytes.extend_from_slice(b"next message");
+
+        let mut cursor = Cursor::new(&bytes);
+        let decoded = def.decode_from(&mut cursor).unwrap();
+        assert_eq!(decoded, def.decode(&bytes[..bytes.len() - 12]).unwrap());
+        assert_eq!(&bytes[cursor.position() as usize..], b"next message");
+    }
+
+    #[test]
+    fn from_partial_reads() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(SCHEMA).unwrap();
+        let def = parser.struct_def("Message").unwrap();
+        let bytes = message(&parser);
+
+        let decoded = def.decode_from(&mut Trickle(&bytes)).unwrap();
+        assert_eq!(decoded, def.decode(&bytes).unwrap());
+    }
+
+    #[test]
+    fn with_options() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(SCHEMA).unwrap();
+        let def = parser.struct_def("Message").unwrap();
+        let encode = EncodeOptions {
+            field_count_header: true,
+            endianness: Endianness::Big,
+            varint_integers: true,
+        };
+        let decode = DecodeOptions {
+            field_count_header: true,
+            endianness: Endianness::Big,
+            varint_integers: true,
+            ..Default::default()
+        };
+        let bytes = message_with(&parser, &encode);
+
+        let decoded = def.decode_from_with(&mut Trickle(&bytes), &decode).unwrap();
+        assert_eq!(decoded, def.decode_with(&bytes, &decode).unwrap());
+        assert_eq!(decoded, def.decode(&message(&parser)).unwrap());
+        assert!(def.decode_from(&mut Trickle(&bytes)).is_err());
+
+        let shallow = DecodeOptions {
+            max_depth: 1,
+            ..decode
+        };
+        assert_eq!(
+            def.decode_from_with(&mut Trickle(&bytes), &shallow),
+            Err(Error::MaxDepthExceeded)
+        );
+        assert_eq!(
+            def.decode_with(&bytes, &shallow),
+            Err(Error::MaxDepthExceeded)
+        );
+    }
+
+    #[test]
+    fn overlong_length_prefix() {
+        let parser = Parser::from_schema("struct Foo { a :bytes; }").unwrap();
+        let def = parser.get("Foo").unwrap();
+        let mut bytes = def.id().to_vec();
+        bytes.extend_from_slice(&[0xff; 11]);
+        assert_eq!(
+            def.decode_from(&mut Cursor::new(&bytes)).err(),
+            Some(malformed(nom::error::ErrorKind::TooLarge))
+        );
+        assert!(def.decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn framing_roundtrip() {
+        let mut stream = Vec::new();
+        write_framed(b"first", &mut stream).unwrap();
+        write_framed(b"", &mut stream).unwrap();
+        write_framed(&[7; 300], &mut stream).unwrap();
+        assert_eq!(&stream[..9], b"\x05\0\0\0first");
+
+        let mut reader = Trickle(&stream);
+        assert_eq!(read_framed(&mut reader).unwrap(), b"first");
+        assert_eq!(read_framed(&mut reader).unwrap(), b"");
+        assert_eq!(read_framed(&mut reader).unwrap(), vec![7; 300]);
+        assert_eq!(
+            read_framed(&mut reader),
+            Err(Error::UnexpectedEof {
+                field: "<frame len>".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut stream = Vec::new();
+        write_framed(&[0; 65], &mut stream).unwrap();
+        assert_eq!(
+            read_framed_with_limit(&mut Cursor::new(&stream), 64),
+            Err(Error::FrameTooLarge { len: 65, max: 64 })
+        );
+        assert_eq!(
+            read_framed_with_limit(&mut Cursor::new(&stream), 65).map(|f| f.len()),
+            Ok(65)
+        );
+
+        let huge = u32::MAX.to_le_bytes();
+        assert_eq!(
+            read_framed(&mut Cursor::new(&huge)),
+            Err(Error::FrameTooLarge {
+                len: u32::MAX as usize,
+                max: MAX_FRAME_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn eof_names_the_field() {
+        let mut parser = Parser::default();
+        parser.add_file_defs(SCHEMA).unwrap();
+        let def = parser.struct_def("Message").unwrap();
+        let bytes = message(&parser);
+
+        let eof = |len: usize| def.decode_from(&mut Trickle(&bytes[..len])).unwrap_err();
+        let field = |field: &str| Error::UnexpectedEof {
+            field: field.to_string(),
+        };
+        assert_eq!(eof(3), field("<id>"));
+        assert_eq!(eof(8 + 4), field("header.id"));
+        // u64 id, list length, then "a" with its length.
+        assert_eq!(eof(8 + 8 + 1 + 1), field("header.tags[0]"));
+        assert_eq!(eof(bytes.len() - 1), field("body"));
+    }
+
+    #[test]
+    fn zero_width_items() {
+        let parser = Parser::from_schema(
+            "struct E {} struct Foo { a :List<()>; } struct Bar { a :List<E>; }",
+        )
+        .unwrap();
+        // Map keys always take a byte or more, so only by hand.
+        let map = StructDef {
+            type_name: "Baz".to_string(),
+            fields: vec![crate::FieldDef {
+                name: "a".to_string(),
+                type_: Type::Map(Box::new(Type::Unit), Box::new(Type::Unit)),
+                attributes: Default::default(),
+            }],
+        };
+
+        for def in [parser.get("Foo").unwrap(), parser.get("Bar").unwrap(), &map] {
+            let mut bytes = def.id().to_vec();
+            bytes.extend_from_slice(&[1, 0]);
+
+            assert_eq!(
+                def.decode_from(&mut Cursor::new(&bytes)).err(),
+                Some(malformed(nom::error::ErrorKind::Many0)),
+                "{}",
+                def
+            );
+        }
+    }
+}
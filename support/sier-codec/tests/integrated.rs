@@ -1,4 +1,9 @@
-use sier_codec::Parser;
+use std::collections::BTreeMap;
+
+use sier_codec::{
+    BuildError, DecodeOptions, EncodeOptions, Endianness, Error, Parser, Span, Type, UnknownEnum,
+    Value,
+};
 
 const VOID_DEF: &'static str = r#"
 struct Foo {}
@@ -81,6 +86,330 @@ fn serialize_object() {
     assert_eq!(encoded, expected);
 }
 
+const SIGNED_NUMBERS: &'static str = r#"
+struct Delta {
+    tiny :i8;
+    small :i16;
+    medium :i32;
+    large :i64;
+}
+"#;
+
+#[test]
+fn signed_numbers_roundtrip() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(SIGNED_NUMBERS).unwrap();
+
+    let def = parser.struct_def("Delta").unwrap();
+    let message = def
+        .builder()
+        .set("tiny", -1i8)
+        .set("small", -300i16)
+        .set("medium", i32::MIN)
+        .set("large", i64::MIN)
+        .try_build()
+        .unwrap();
+
+    let encoded = message.serialize();
+    assert_eq!(&encoded[8..11], &[0xff, 0xd4, 0xfe]);
+
+    let decoded = parser.parse(&encoded).unwrap();
+    assert_eq!(decoded, message);
+    assert_eq!(decoded["tiny"].as_i8(), Some(-1));
+    assert_eq!(decoded["small"].as_i16(), Some(-300));
+    assert_eq!(decoded["medium"].as_i32(), Some(i32::MIN));
+    assert_eq!(decoded["large"].as_i64(), Some(i64::MIN));
+}
+
+const PORTS: &'static str = r#"
+struct Ports {
+    ports :List<u16>;
+}
+"#;
+
+#[test]
+fn u16_boundaries() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(PORTS).unwrap();
+
+    let def = parser.struct_def("Ports").unwrap();
+    let message = def
+        .builder()
+        .set("ports", vec![0u16, 255, 256, 65535])
+        .try_build()
+        .unwrap();
+
+    let encoded = message.serialize();
+    assert_eq!(&encoded[8..], &[8, 0, 0, 255, 0, 0, 1, 255, 255]);
+
+    let decoded = parser.parse(&encoded).unwrap();
+    let ports = decoded["ports"]
+        .as_list()
+        .unwrap()
+        .iter()
+        .map(|p| p.as_u16())
+        .collect::<Vec<_>>();
+    assert_eq!(ports, vec![Some(0), Some(255), Some(256), Some(65535)]);
+}
+
+const READING: &'static str = r#"
+struct Reading {
+    coarse :f32;
+    fine :f64;
+}
+"#;
+
+#[test]
+fn floats_roundtrip_bits() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(READING).unwrap();
+    let def = parser.struct_def("Reading").unwrap();
+
+    let values = [
+        (0.0f32, 0.0f64),
+        (-0.0, -0.0),
+        (f32::NAN, f64::NAN),
+        (f32::INFINITY, f64::NEG_INFINITY),
+        (21.5, -273.15),
+    ];
+    for (coarse, fine) in values {
+        let message = def
+            .builder()
+            .set("coarse", coarse)
+            .set("fine", fine)
+            .try_build()
+            .unwrap();
+
+        let encoded = message.serialize();
+        assert_eq!(&encoded[8..12], &coarse.to_bits().to_le_bytes());
+        assert_eq!(&encoded[12..], &fine.to_bits().to_le_bytes());
+
+        let decoded = parser.parse(&encoded).unwrap();
+        assert_eq!(
+            decoded["coarse"].as_f32().map(f32::to_bits),
+            Some(coarse.to_bits())
+        );
+        assert_eq!(
+            decoded["fine"].as_f64().map(f64::to_bits),
+            Some(fine.to_bits())
+        );
+    }
+}
+
+const BALANCES: &'static str = r#"
+struct Balances {
+    by_name :Map<string, u64>;
+}
+"#;
+
+#[test]
+fn map_roundtrip() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(BALANCES).unwrap();
+
+    let mut balances = BTreeMap::new();
+    balances.insert("bob".to_string(), 7u64);
+    balances.insert("amy".to_string(), 300u64);
+
+    let def = parser.struct_def("Balances").unwrap();
+    let message = def.builder().set("by_name", balances).try_build().unwrap();
+
+    let encoded = message.serialize();
+    assert_eq!(encoded[8], 24);
+    assert_eq!(&encoded[9..13], b"\x03amy");
+
+    let decoded = parser.parse(&encoded).unwrap();
+    assert_eq!(decoded, message);
+    let entries = decoded["by_name"].as_map().unwrap();
+    assert_eq!(entries[1].0.as_string(), Some("bob"));
+    assert_eq!(entries[1].1.as_u64(), Some(7));
+}
+
+#[test]
+fn map_keys_must_increase() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(BALANCES).unwrap();
+    let def = parser.struct_def("Balances").unwrap();
+
+    let message = |keys: &[&[u8]]| {
+        let mut entries = Vec::new();
+        for key in keys {
+            entries.push(key.len() as u8);
+            entries.extend_from_slice(key);
+            entries.extend_from_slice(&7u64.to_le_bytes());
+        }
+        let mut bytes = def.id().to_vec();
+        bytes.push(entries.len() as u8);
+        bytes.extend(entries);
+        bytes
+    };
+
+    // Ordered by encoded key, so the shorter string comes first.
+    let ordered = message(&[b"b", b"aa", b"ab"]);
+    assert_eq!(
+        def.decode(&ordered).unwrap()["by_name"]
+            .as_map()
+            .unwrap()
+            .len(),
+        3
+    );
+    assert!(def.decode_from(&mut &ordered[..]).is_ok());
+
+    for keys in [
+        &[&b"bob"[..], b"amy"][..],
+        &[b"amy", b"amy"],
+        &[b"aa", b"b"],
+    ] {
+        let bytes = message(keys);
+        assert_eq!(
+            def.decode(&bytes).unwrap_err(),
+            Error::UnorderedMapKey { index: 1 },
+            "{:?}",
+            keys
+        );
+        assert_eq!(
+            def.decode_from(&mut &bytes[..]).unwrap_err(),
+            Error::UnorderedMapKey { index: 1 },
+            "{:?}",
+            keys
+        );
+    }
+}
+
+const OPTIONAL: &'static str = r#"
+struct Address {
+    zip :u32;
+}
+
+struct Person {
+    middle :Optional<string>;
+    home :Optional<Address>;
+}
+"#;
+
+#[test]
+fn optional_present() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(OPTIONAL).unwrap();
+
+    let address = parser.struct_def("Address").unwrap();
+    let home = address.builder().set("zip", 12345u32).try_build().unwrap();
+    let person = parser.struct_def("Person").unwrap();
+    let message = person
+        .builder()
+        .set("middle", Some("Q".to_string()))
+        .set("home", Some(home))
+        .try_build()
+        .unwrap();
+
+    let encoded = message.serialize();
+    assert_eq!(&encoded[8..], &[1, 1, b'Q', 1, 0x39, 0x30, 0, 0]);
+
+    let decoded = parser.parse(&encoded).unwrap();
+    assert_eq!(decoded, message);
+    let middle = decoded["middle"].as_optional().unwrap();
+    assert_eq!(middle.and_then(|m| m.as_string()), Some("Q"));
+    let home = decoded["home"].as_optional().unwrap().unwrap();
+    assert_eq!(home.as_object().unwrap()["zip"].as_u32(), Some(12345));
+}
+
+const INLINE: &str = r#"
+struct Person {
+    name :string;
+    address : { street : string; zip : u32; };
+}
+"#;
+
+#[test]
+fn inline_struct_roundtrip() {
+    let parser = Parser::from_schema(INLINE).unwrap();
+    let person = parser.get("Person").unwrap();
+    let address = match person.field(1).unwrap().type_() {
+        Type::Struct(def) => def,
+        t => panic!("expected a struct, got {}", t),
+    };
+    assert_eq!(address.type_name(), "Person_address");
+
+    let home = address
+        .builder()
+        .set("street", "Main St".to_string())
+        .set("zip", 12345u32)
+        .try_build()
+        .unwrap();
+    let message = person
+        .builder()
+        .set("name", "Ada".to_string())
+        .set("address", home)
+        .try_build()
+        .unwrap();
+
+    let decoded = parser.parse(&message.serialize()).unwrap();
+    assert_eq!(decoded, message);
+    let home = decoded["address"].as_object().unwrap();
+    assert_eq!(home["zip"].as_u32(), Some(12345));
+
+    let encoded = message.serialize_self_describing();
+    let decoded = Parser::parse_self_describing(&encoded).unwrap();
+    assert_eq!(decoded.object(), Ok(message));
+}
+
+#[test]
+fn optional_absent() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(OPTIONAL).unwrap();
+
+    let person = parser.struct_def("Person").unwrap();
+    let message = person.builder().try_build().unwrap();
+
+    let encoded = message.serialize();
+    assert_eq!(&encoded[8..], &[0, 0]);
+
+    let decoded = parser.parse(&encoded).unwrap();
+    assert_eq!(decoded["middle"].as_optional(), Some(None));
+    assert_eq!(decoded["home"].as_optional(), Some(None));
+}
+
+#[test]
+fn optional_bad_presence_byte() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(OPTIONAL).unwrap();
+
+    let id = parser.struct_def("Person").unwrap().id();
+    let encoded = id.iter().chain(&[2, 0]).cloned().collect::<Vec<_>>();
+    assert!(matches!(
+        parser.parse(&encoded),
+        Err(Error::ValueParsing(_))
+    ));
+}
+
+const BLOB: &'static str = r#"
+struct Blob {
+    data :bytes;
+}
+"#;
+
+#[test]
+fn bytes_have_no_per_byte_overhead() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(BLOB).unwrap();
+
+    let data = (0..1024).map(|i| i as u8).collect::<Vec<_>>();
+    let def = parser.struct_def("Blob").unwrap();
+    let message = def
+        .builder()
+        .set("data", data.as_slice())
+        .try_build()
+        .unwrap();
+
+    let encoded = message.serialize();
+    // ID, a two byte varint for 1024, then the blob itself.
+    assert_eq!(encoded.len(), 8 + 2 + data.len());
+    assert_eq!(&encoded[10..], data.as_slice());
+
+    let decoded = parser.parse(&encoded).unwrap();
+    assert_eq!(decoded["data"].as_bytes(), Some(data.as_slice()));
+}
+
 const STRING: &'static str = r#"
 struct Foo {
     foo :string;
@@ -228,3 +557,1039 @@ fn to_json() {
     let serde_json: serde_json::Value = serde_json::from_str(JSON).unwrap();
     assert_eq!(json, serde_json);
 }
+
+const JSON_ROUNDTRIP: &'static str = r#"
+enum Level { Low; High; }
+
+struct Reading {
+    at :i64;
+    value :f64;
+    level :Level;
+}
+
+struct Sensor {
+    id :u64;
+    name :string;
+    calibration :Optional<u16>;
+    readings :List<Reading>;
+    labels :Map<u8, string>;
+    raw :bytes;
+}
+"#;
+
+#[test]
+fn json_roundtrip_nested() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(JSON_ROUNDTRIP).unwrap();
+    let def = parser.struct_def("Sensor").unwrap();
+
+    let json = serde_json::json!({
+        "id": 18446744073709551615u64,
+        "name": "probe",
+        "calibration": null,
+        "readings": [
+            { "at": -5, "value": 1.5, "level": "High" },
+            { "at": 7, "value": -0.25, "level": "Low" }
+        ],
+        "labels": { "1": "one", "2": "two" },
+        "raw": [0, 255]
+    });
+
+    let obj = def.from_json(&json).unwrap();
+    assert_eq!(obj.get_u64("id"), Ok(u64::MAX));
+    assert_eq!(obj.to_json().unwrap(), json);
+
+    let bytes = obj.serialize();
+    assert_eq!(def.decode(&bytes).unwrap().to_json().unwrap(), json);
+}
+
+#[test]
+fn json_must_match_fields() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(JSON_STRUCT_DEF).unwrap();
+    let def = parser.struct_def("Corge").unwrap();
+
+    assert_eq!(
+        def.from_json(&serde_json::json!({ "gz": 1 })),
+        Err(Error::MissingField("Corge.op".to_string()))
+    );
+    assert_eq!(
+        def.from_json(&serde_json::json!({ "gz": 1, "op": true, "extra": 0 })),
+        Err(Error::UnknownField("Corge.extra".to_string()))
+    );
+    assert_eq!(
+        def.from_json(&serde_json::json!({ "gz": -1, "op": true })),
+        Err(Error::InvalidJson)
+    );
+}
+
+#[test]
+fn self_describing() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(STRUCT).unwrap();
+
+    let foo = parser
+        .struct_def("Foo")
+        .unwrap()
+        .builder()
+        .set("foo", 42u8)
+        .try_build()
+        .unwrap();
+    let bar_def = parser.struct_def("Bar").unwrap();
+    let bar = bar_def.builder().set("bar", foo).try_build().unwrap();
+    let encoded = bar.serialize_self_describing();
+
+    let decoded = Parser::parse_self_describing(&encoded).unwrap();
+
+    assert_eq!(decoded.def(), bar_def);
+    assert_eq!(decoded.object(), Ok(bar));
+}
+
+#[test]
+fn self_describing_bad_body() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(STRUCT).unwrap();
+    let foo = parser.struct_def("Foo").unwrap();
+    let mut encoded = foo
+        .builder()
+        .set("foo", 42u8)
+        .try_build()
+        .unwrap()
+        .serialize_self_describing();
+    encoded.pop();
+
+    let decoded = Parser::parse_self_describing(&encoded).unwrap();
+    assert_eq!(decoded.def(), foo);
+    assert!(decoded.object().is_err());
+}
+
+#[test]
+fn self_describing_schema_nests_too_deeply() {
+    let source = format!(
+        "struct Foo {{ a :{}u8{}; }}",
+        "List<".repeat(500),
+        ">".repeat(500)
+    );
+    // Two byte varint length.
+    let mut encoded = vec![source.len() as u8 | 0x80, (source.len() >> 7) as u8];
+    encoded.extend(source.bytes());
+    encoded.extend(&[0; 8]);
+
+    assert!(matches!(
+        Parser::parse_self_describing(&encoded),
+        Err(Error::TypeTooDeep {
+            max: sier_codec::MAX_TYPE_DEPTH,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn self_describing_schema_is_limited() {
+    let source = "struct Foo { a :Array<(), 1099511627776>; }";
+    let mut encoded = vec![source.len() as u8];
+    encoded.extend(source.bytes());
+    encoded.extend(&[0; 8]);
+
+    assert_eq!(
+        Parser::parse_self_describing(&encoded).err(),
+        Some(Error::ArrayTooLong {
+            len: 1099511627776,
+            max: sier_codec::MAX_ARRAY_LEN,
+            span: Some(Span { start: 16, end: 40 }),
+        })
+    );
+}
+
+#[test]
+fn self_describing_schema_too_large() {
+    let mut encoded = vec![0x80, 0x80, 0x80, 0x01];
+    encoded.extend(&[b' '; 64]);
+
+    assert!(matches!(
+        Parser::parse_self_describing(&encoded),
+        Err(Error::EmbeddedSchemaTooLarge(_))
+    ));
+}
+
+#[test]
+fn field_count_header() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(MULTIPLE_NUMBERS).unwrap();
+
+    let def = parser.struct_def("Foo").unwrap();
+    let message = def
+        .builder()
+        .set("foo", 42u64)
+        .set("bar", 43u32)
+        .try_build()
+        .unwrap();
+
+    let encoded = message.serialize_with(&EncodeOptions {
+        field_count_header: true,
+        ..Default::default()
+    });
+    assert_eq!(encoded[8], 2);
+
+    let options = DecodeOptions {
+        field_count_header: true,
+        ..Default::default()
+    };
+    assert_eq!(parser.parse_with(&encoded, &options).unwrap(), message);
+}
+
+#[test]
+fn endianness() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(MULTIPLE_NUMBERS).unwrap();
+
+    let def = parser.struct_def("Foo").unwrap();
+    let message = def
+        .builder()
+        .set("foo", 1u64)
+        .set("bar", 0x0102_0304u32)
+        .try_build()
+        .unwrap();
+    assert_eq!(Endianness::CANONICAL, Endianness::Little);
+
+    let little = message.serialize();
+    assert_eq!(&little[16..], &[0x04, 0x03, 0x02, 0x01]);
+    assert_eq!(
+        little,
+        message.serialize_with(&EncodeOptions {
+            endianness: Endianness::Little,
+            ..Default::default()
+        })
+    );
+
+    let big = message.serialize_with(&EncodeOptions {
+        endianness: Endianness::Big,
+        ..Default::default()
+    });
+    assert_eq!(&big[8..16], &[0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(&big[16..], &[0x01, 0x02, 0x03, 0x04]);
+
+    let options = DecodeOptions {
+        endianness: Endianness::Big,
+        ..Default::default()
+    };
+    assert_eq!(parser.parse_with(&big, &options).unwrap(), message);
+    assert_eq!(
+        parser.parse(&big).unwrap()["bar"].as_u32(),
+        Some(0x0403_0201)
+    );
+}
+
+const VARINTS: &'static str = r#"
+struct Counters {
+    small :u64;
+    count :u32;
+    delta :i64;
+    offset :i32;
+    flags :u16;
+}
+"#;
+
+fn varint_options() -> (EncodeOptions, DecodeOptions) {
+    let encode = EncodeOptions {
+        varint_integers: true,
+        ..Default::default()
+    };
+    let decode = DecodeOptions {
+        varint_integers: true,
+        ..Default::default()
+    };
+    (encode, decode)
+}
+
+#[test]
+fn varints_shrink_small_values() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(VARINTS).unwrap();
+    let (encode, decode) = varint_options();
+
+    let message = parser
+        .struct_def("Counters")
+        .unwrap()
+        .builder()
+        .set("small", 5u64)
+        .set("count", 300u32)
+        .set("delta", -1i64)
+        .set("offset", 63i32)
+        .set("flags", 7u16)
+        .try_build()
+        .unwrap();
+
+    let fixed = message.serialize();
+    let varint = message.serialize_with(&encode);
+    assert_eq!(fixed.len(), 8 + 8 + 4 + 8 + 4 + 2);
+    // u16 stays fixed width.
+    assert_eq!(&varint[8..], &[5, 0xac, 0x02, 1, 126, 7, 0]);
+    assert_eq!(parser.parse_with(&varint, &decode).unwrap(), message);
+}
+
+#[test]
+fn varint_boundaries_roundtrip() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(VARINTS).unwrap();
+    let def = parser.struct_def("Counters").unwrap();
+    let (encode, decode) = varint_options();
+
+    let cases = [
+        (0u64, 0u32, 0i64, 0i32),
+        (127, 127, -64, 63),
+        (128, 128, 64, -65),
+        (u64::MAX, u32::MAX, i64::MIN, i32::MIN),
+        (u64::MAX - 1, u32::MAX - 1, i64::MAX, i32::MAX),
+    ];
+    for &(small, count, delta, offset) in &cases {
+        let message = def
+            .builder()
+            .set("small", small)
+            .set("count", count)
+            .set("delta", delta)
+            .set("offset", offset)
+            .set("flags", 0u16)
+            .try_build()
+            .unwrap();
+        let bytes = message.serialize_with(&encode);
+        assert_eq!(parser.parse_with(&bytes, &decode).unwrap(), message);
+    }
+}
+
+#[test]
+fn varint_rejects_overlong_and_oversized() {
+    let mut parser = Parser::default();
+    parser.add_file_defs("struct Foo { n :u32; }").unwrap();
+    let id = parser.struct_def("Foo").unwrap().id();
+    let (_, decode) = varint_options();
+    let message = |n: &[u8]| id.iter().chain(n).copied().collect::<Vec<_>>();
+
+    assert!(parser
+        .parse_with(&message(&[0xff, 0xff, 0xff, 0xff, 0x0f]), &decode)
+        .is_ok());
+    for bad in &[
+        // Redundant trailing zero.
+        &[0x85, 0x00][..],
+        // Needs 33 bits.
+        &[0xff, 0xff, 0xff, 0xff, 0x1f],
+        // Longer than a u32 can take.
+        &[0x80, 0x80, 0x80, 0x80, 0x80, 0x01],
+    ] {
+        assert_eq!(
+            parser.parse_with(&message(bad), &decode).unwrap_err(),
+            Error::InvalidVarint { bits: 32 }
+        );
+    }
+}
+
+const ZIGZAG: &'static str = r#"
+struct Delta {
+    d :i64 @varint;
+    n :u32;
+}
+"#;
+
+#[test]
+fn varint_field_zigzags() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(ZIGZAG).unwrap();
+    let def = parser.struct_def("Delta").unwrap();
+
+    for &(d, len) in &[(-1i64, 1), (0, 1), (1, 1), (i64::MIN, 10), (i64::MAX, 10)] {
+        let message = def
+            .builder()
+            .set("d", d)
+            .set("n", 7u32)
+            .try_build()
+            .unwrap();
+        let bytes = message.serialize();
+        // Only the attributed field shrinks.
+        assert_eq!(bytes.len(), 8 + len + 4, "{}", d);
+        assert_eq!(&bytes[bytes.len() - 4..], &[7, 0, 0, 0]);
+
+        assert_eq!(parser.parse(&bytes).unwrap(), message);
+        assert_eq!(def.decode_from(&mut &bytes[..]).unwrap(), message);
+        let spans = def.annotate_bytes(&bytes[8..]).unwrap();
+        assert_eq!(spans[0].byte_range, 0..len);
+    }
+}
+
+#[test]
+fn varint_field_is_part_of_the_id() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(ZIGZAG).unwrap();
+    let def = parser.struct_def("Delta").unwrap();
+
+    let fixed = Parser::from_schema("struct Delta { d :i64; n :u32; }").unwrap();
+    assert_ne!(fixed.struct_def("Delta").unwrap().id(), def.id());
+
+    let canonical = Parser::from_schema(&def.to_string()).unwrap();
+    assert_eq!(canonical.struct_def("Delta"), Some(def));
+}
+
+const PROFILE_V1: &'static str = r#"
+struct Profile {
+    id :u64;
+    tags :List<string>;
+}
+"#;
+
+const PROFILE_V2: &'static str = r#"
+struct Profile {
+    id :u64;
+    tags :List<string>;
+    nickname :Optional<string>;
+}
+"#;
+
+#[test]
+fn skip_trailing_fields_from_newer_struct() {
+    let mut old = Parser::default();
+    old.add_file_defs(PROFILE_V1).unwrap();
+    let mut new = Parser::default();
+    new.add_file_defs(PROFILE_V2).unwrap();
+
+    let tags = || Value::List(vec![Value::String("a".into())]);
+    let bytes = new
+        .struct_def("Profile")
+        .unwrap()
+        .builder()
+        .set("id", 9u64)
+        .set("tags", tags())
+        .set(
+            "nickname",
+            Value::Optional(Some(Box::new(Value::String("nick".into())))),
+        )
+        .try_build()
+        .unwrap()
+        .serialize();
+
+    let old_def = old.struct_def("Profile").unwrap();
+    let new_def = new.struct_def("Profile").unwrap();
+    assert!(matches!(old_def.decode(&bytes), Err(Error::MissingId(_))));
+
+    let lenient = DecodeOptions {
+        skip_trailing_fields: true,
+        accepted_ids: vec![new_def.id()],
+        ..Default::default()
+    };
+    let decoded = old_def.decode_with(&bytes, &lenient).unwrap();
+    let expected = old_def
+        .builder()
+        .set("id", 9u64)
+        .set("tags", tags())
+        .try_build()
+        .unwrap();
+    assert_eq!(decoded, expected);
+
+    // Messages from the same version still decode.
+    assert_eq!(
+        old_def
+            .decode_with(&expected.serialize(), &lenient)
+            .unwrap(),
+        expected
+    );
+
+    // Skipping fields alone doesn't accept the newer ID.
+    let unlisted = DecodeOptions {
+        skip_trailing_fields: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        old_def.decode_with(&bytes, &unlisted),
+        Err(Error::MissingId(new_def.id()))
+    );
+}
+
+#[test]
+fn skip_trailing_fields_rejects_foreign_ids() {
+    let parser = Parser::from_schema(
+        "struct Profile { id :u64; tags :List<string>; } struct Other { id :u64; tags :List<string>; }",
+    )
+    .unwrap();
+    let profile = parser.struct_def("Profile").unwrap();
+    let other = parser.struct_def("Other").unwrap();
+    let bytes = other
+        .builder()
+        .set("id", 9u64)
+        .set("tags", Value::List(Vec::new()))
+        .try_build()
+        .unwrap()
+        .serialize();
+
+    let mut lenient = DecodeOptions {
+        skip_trailing_fields: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        profile.decode_with(&bytes, &lenient),
+        Err(Error::MissingId(other.id()))
+    );
+    assert_eq!(
+        profile.decode_from_with(&mut &bytes[..], &lenient),
+        Err(Error::MissingId(other.id()))
+    );
+
+    lenient.accepted_ids.push(other.id());
+    assert_eq!(
+        profile.decode_with(&bytes, &lenient).unwrap().get_u64("id"),
+        Ok(9)
+    );
+}
+
+#[test]
+fn skip_trailing_fields_still_needs_known_fields() {
+    let mut old = Parser::default();
+    old.add_file_defs(PROFILE_V2).unwrap();
+    let mut new = Parser::default();
+    new.add_file_defs(PROFILE_V1).unwrap();
+
+    let bytes = new
+        .struct_def("Profile")
+        .unwrap()
+        .builder()
+        .set("id", 9u64)
+        .set("tags", Value::List(Vec::new()))
+        .try_build()
+        .unwrap()
+        .serialize();
+
+    let lenient = DecodeOptions {
+        skip_trailing_fields: true,
+        accepted_ids: vec![new.struct_def("Profile").unwrap().id()],
+        ..Default::default()
+    };
+    assert!(old
+        .struct_def("Profile")
+        .unwrap()
+        .decode_with(&bytes, &lenient)
+        .is_err());
+}
+
+const HASHED: &'static str = r#"
+struct Block {
+    hash :Array<u8, 32>;
+    parents :List<Array<u16, 2>>;
+}
+"#;
+
+#[test]
+fn array_has_no_length_prefix() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(HASHED).unwrap();
+    let def = parser.struct_def("Block").unwrap();
+
+    let message = def
+        .builder()
+        .set("hash", [7u8; 32])
+        .set("parents", vec![Value::from([1u16, 2])])
+        .try_build()
+        .unwrap();
+    let bytes = message.serialize();
+
+    assert_eq!(&bytes[8..40], &[7; 32]);
+    assert_eq!(&bytes[40..], &[4, 1, 0, 2, 0]);
+    assert_eq!(def.decode(&bytes).unwrap(), message);
+    assert_eq!(
+        def.decode(&bytes).unwrap()["hash"]
+            .as_list()
+            .map(|h| h.len()),
+        Some(32)
+    );
+}
+
+#[test]
+fn array_must_have_exact_length() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(HASHED).unwrap();
+    let def = parser.struct_def("Block").unwrap();
+
+    let result = def
+        .builder()
+        .set("hash", [7u8; 31])
+        .set("parents", Value::List(Vec::new()))
+        .try_build();
+    assert_eq!(
+        result.unwrap_err(),
+        BuildError::IncorrectType {
+            field: "Block.hash".to_string(),
+            expected: def.fields()[0].type_().clone(),
+            got: Type::Array(Box::new(Type::U8), 31),
+        }
+    );
+
+    // Decoding reads exactly 32 bytes, not whatever the buffer holds.
+    let id = def.id();
+    let short = id.iter().chain(&[7; 31]).copied().collect::<Vec<_>>();
+    assert!(matches!(def.decode(&short), Err(Error::ValueParsing(_))));
+}
+
+#[test]
+fn field_count_header_mismatch() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(MULTIPLE_NUMBERS).unwrap();
+
+    let id = parser.struct_def("Foo").unwrap().id();
+    let encoded = id.iter().chain(&[3]).cloned().collect::<Vec<_>>();
+
+    let options = DecodeOptions {
+        field_count_header: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        parser.parse_with(&encoded, &options).unwrap_err(),
+        Error::FieldCountMismatch {
+            expected: 2,
+            found: 3
+        }
+    );
+}
+
+const NESTED_LISTS: &'static str = r#"
+struct Leaf {
+    value :u8;
+}
+
+struct Tree {
+    levels :List<List<List<Leaf>>>;
+}
+"#;
+
+#[test]
+fn max_depth() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(NESTED_LISTS).unwrap();
+
+    let id = parser.struct_def("Tree").unwrap().id();
+    let encoded = id.iter().chain(&[3, 2, 1, 7]).cloned().collect::<Vec<_>>();
+
+    let within = DecodeOptions {
+        max_depth: 4,
+        ..Default::default()
+    };
+    let tree = parser.parse_with(&encoded, &within).unwrap();
+    assert_eq!(tree["levels"].as_list().map(|l| l.len()), Some(1));
+
+    let exceeded = DecodeOptions {
+        max_depth: 3,
+        ..Default::default()
+    };
+    assert_eq!(
+        parser.parse_with(&encoded, &exceeded).unwrap_err(),
+        Error::MaxDepthExceeded
+    );
+}
+
+const TREE: &'static str = r#"
+struct Node {
+    value :u8;
+    children :List<Node>;
+}
+
+struct Chain {
+    next :Optional<Chain>;
+}
+"#;
+
+#[test]
+fn recursive_tree_roundtrip() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(TREE).unwrap();
+
+    let node = parser.struct_def("Node").unwrap();
+    let leaf = |value: u8| {
+        node.builder()
+            .set("value", value)
+            .set("children", Vec::<u8>::new())
+            .try_build()
+            .unwrap()
+    };
+    let root = node
+        .builder()
+        .set("value", 1u8)
+        .set("children", vec![leaf(2), leaf(3)])
+        .try_build()
+        .unwrap();
+
+    let encoded = root.serialize();
+    assert_eq!(&encoded[8..], &[1, 4, 2, 0, 3, 0]);
+
+    let decoded = parser.parse(&encoded).unwrap();
+    assert_eq!(decoded, root);
+    let children = decoded["children"].as_list().unwrap();
+    assert_eq!(children[1].as_object().unwrap()["value"].as_u8(), Some(3));
+}
+
+#[test]
+fn recursive_chain_depth_limit() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(TREE).unwrap();
+
+    let id = parser.struct_def("Chain").unwrap().id();
+    let chain = |links: usize| {
+        let mut encoded = id.to_vec();
+        encoded.resize(encoded.len() + links, 1);
+        encoded.push(0);
+        encoded
+    };
+    let options = DecodeOptions {
+        max_depth: 20,
+        ..Default::default()
+    };
+
+    assert!(parser.parse_with(&chain(10), &options).is_ok());
+    assert_eq!(
+        parser.parse_with(&chain(100_000), &options).unwrap_err(),
+        Error::MaxDepthExceeded
+    );
+}
+
+const MUTUAL: &'static str = r#"
+struct A {
+    n :u8;
+    b :B;
+}
+
+struct B {
+    a :Optional<A>;
+}
+"#;
+
+#[test]
+fn mutually_recursive_builders() {
+    let parser = Parser::from_schema(MUTUAL).unwrap();
+    let a = parser.struct_def("A").unwrap();
+    let b = parser.struct_def("B").unwrap();
+
+    let empty_b = b.builder().try_build().unwrap();
+    let inner_a = a
+        .builder()
+        .set("n", 1u8)
+        .set("b", empty_b)
+        .try_build()
+        .unwrap();
+    let outer_b = b
+        .builder()
+        .set("a", Value::Optional(Some(Box::new(Value::Struct(inner_a)))))
+        .try_build()
+        .unwrap();
+    let outer_a = a
+        .builder()
+        .set("n", 2u8)
+        .set("b", outer_b)
+        .try_build()
+        .unwrap();
+
+    let encoded = outer_a.serialize();
+    assert_eq!(&encoded[8..], &[2, 1, 1, 0]);
+    assert_eq!(parser.parse(&encoded).unwrap().serialize(), encoded);
+}
+
+const OUT_OF_ORDER: &'static str = r#"
+struct Folder {
+    files :List<File>;
+}
+
+struct File {
+    name :string;
+    parent :Optional<Folder>;
+}
+"#;
+
+#[test]
+fn structs_defined_after_use() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(OUT_OF_ORDER).unwrap();
+
+    let file = parser.struct_def("File").unwrap();
+    let mut encoded = file.id().to_vec();
+    // name "a", then a parent folder holding one file "b" with no parent.
+    encoded.extend([1, b'a', 1, 3, 1, b'b', 0]);
+
+    let decoded = parser.parse(&encoded).unwrap();
+    let parent = decoded["parent"].as_optional().unwrap().unwrap();
+    let parent = parent.as_object().unwrap();
+    let files = parent["files"].as_list().unwrap();
+    assert_eq!(files[0].as_object().unwrap()["name"].as_string(), Some("b"));
+    assert_eq!(decoded.serialize(), encoded);
+}
+
+const ORDER: &'static str = r#"
+struct Customer {
+    id :u64;
+    name :string;
+}
+
+struct Order {
+    customer :Customer;
+    quantities :List<u32>;
+}
+"#;
+
+fn order_bytes(parser: &Parser) -> Vec<u8> {
+    let customer = parser.struct_def("Customer").unwrap();
+    let mut value = customer.new_value();
+    value
+        .set("id", 9u64)
+        .unwrap()
+        .set("name", "Ann".to_string())
+        .unwrap();
+    let customer = value.finish().unwrap();
+
+    let order = parser.struct_def("Order").unwrap();
+    let mut value = order.new_value();
+    value
+        .set("customer", customer)
+        .unwrap()
+        .set("quantities", vec![1u32, 20, 300])
+        .unwrap();
+    value.encode().unwrap()
+}
+
+#[test]
+fn decode_nested_struct_and_list() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(ORDER).unwrap();
+    let encoded = order_bytes(&parser);
+
+    let order = parser
+        .struct_def("Order")
+        .unwrap()
+        .decode(&encoded)
+        .unwrap();
+    let customer = order.get_struct("customer").unwrap();
+    assert_eq!(customer.get_u64("id"), Ok(9));
+    assert_eq!(customer.get_string("name"), Ok("Ann"));
+    let quantities = order.get_list("quantities").unwrap();
+    assert_eq!(quantities.len(), 3);
+    assert_eq!(quantities[2].as_u32(), Some(300));
+
+    assert_eq!(
+        order.get_u64("quantities"),
+        Err(Error::WrongFieldType {
+            field: "Order.quantities".to_string(),
+            requested: "u64",
+        })
+    );
+    assert_eq!(
+        order.get_string("total"),
+        Err(Error::UnknownField("total".to_string()))
+    );
+}
+
+#[test]
+fn decode_rejects_truncated_and_trailing_bytes() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(ORDER).unwrap();
+    let def = parser.struct_def("Order").unwrap();
+    let mut encoded = order_bytes(&parser);
+
+    assert!(def.decode(&encoded[..encoded.len() - 1]).is_err());
+    assert_eq!(def.decode(&encoded[..4]).unwrap_err(), Error::TooFewBytes);
+
+    encoded.extend([0, 0]);
+    assert_eq!(def.decode(&encoded).unwrap_err(), Error::TrailingBytes(2));
+}
+
+#[test]
+fn invalid_utf8_string() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(ORDER).unwrap();
+    let def = parser.struct_def("Customer").unwrap();
+
+    let mut encoded = def.id().to_vec();
+    encoded.extend(9u64.to_le_bytes());
+    // "ab", then a lead byte for a three byte sequence followed by one
+    // continuation byte and an ASCII byte.
+    encoded.extend([5, b'a', b'b', 0xe2, 0x82, b'c']);
+
+    assert_eq!(
+        def.decode(&encoded).unwrap_err(),
+        Error::InvalidUtf8 { offset: 2 }
+    );
+}
+
+#[test]
+fn huge_length_prefix() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(ORDER).unwrap();
+    let def = parser.struct_def("Order").unwrap();
+
+    let mut encoded = def.id().to_vec();
+    encoded.extend(9u64.to_le_bytes());
+    encoded.extend([3, b'A', b'n', b'n']);
+    // A list claiming 2^32 bytes of items followed by just one.
+    encoded.extend([0x80, 0x80, 0x80, 0x80, 0x10, 1, 0, 0, 0]);
+
+    assert_eq!(
+        def.decode(&encoded).unwrap_err(),
+        Error::LengthExceedsBuffer {
+            claimed: 1 << 32,
+            remaining: 4
+        }
+    );
+}
+
+const GRID: &'static str = r#"
+struct Grid {
+    rows :List<List<u8>>;
+    layers :Optional<List<List<List<u8>>>>;
+}
+"#;
+
+#[test]
+fn nested_lists_roundtrip() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(GRID).unwrap();
+    let def = parser.struct_def("Grid").unwrap();
+
+    let rows = vec![vec![1u8, 2], vec![], vec![3]];
+    let mut value = def.new_value();
+    value.set("rows", rows).unwrap();
+    let encoded = value.encode().unwrap();
+    // Each level has its own byte length: 3 + 1 + 2 for the rows, then the
+    // absent layers.
+    assert_eq!(&encoded[8..], &[6, 2, 1, 2, 0, 1, 3, 0]);
+
+    let decoded = def.decode(&encoded).unwrap();
+    let rows = decoded.get_list("rows").unwrap();
+    let lens = rows
+        .iter()
+        .map(|r| r.as_list().unwrap().len())
+        .collect::<Vec<_>>();
+    assert_eq!(lens, vec![2, 0, 1]);
+    assert_eq!(decoded.get_optional("layers"), Ok(None));
+}
+
+#[test]
+fn empty_inner_list_is_not_an_empty_outer_list() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(GRID).unwrap();
+    let def = parser.struct_def("Grid").unwrap();
+
+    let encode = |rows: Vec<Vec<u8>>, layers: Option<Vec<Vec<Vec<u8>>>>| {
+        let mut value = def.new_value();
+        value
+            .set("rows", rows)
+            .unwrap()
+            .set("layers", layers)
+            .unwrap();
+        value.encode().unwrap()
+    };
+
+    let empty = encode(vec![], Some(vec![]));
+    let one_empty = encode(vec![vec![]], Some(vec![vec![]]));
+    assert_eq!(&empty[8..], &[0, 1, 0]);
+    assert_eq!(&one_empty[8..], &[1, 0, 1, 1, 0]);
+
+    let decoded = def.decode(&one_empty).unwrap();
+    assert_eq!(decoded.get_list("rows").unwrap().len(), 1);
+    let layers = decoded.get_optional("layers").unwrap().unwrap();
+    assert_eq!(layers.as_list().unwrap()[0].as_list(), Some(&[][..]));
+    assert_eq!(def.decode(&empty).unwrap().get_list("rows"), Ok(&[][..]));
+}
+
+const ACCOUNT: &'static str = r#"
+enum Status {
+    Active;
+    Closed;
+    Pending;
+}
+
+struct Account {
+    id :u32;
+    status :Status;
+}
+"#;
+
+#[test]
+fn enum_encodes_ordinal() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(ACCOUNT).unwrap();
+    let status = parser.enum_def("Status").unwrap();
+    let def = parser.struct_def("Account").unwrap();
+
+    let mut value = def.new_value();
+    value
+        .set("id", 7u32)
+        .unwrap()
+        .set("status", status.value("Pending").unwrap())
+        .unwrap();
+    let encoded = value.encode().unwrap();
+    assert_eq!(&encoded[8..], &[7, 0, 0, 0, 2, 0, 0, 0]);
+
+    let decoded = def.decode(&encoded).unwrap();
+    assert_eq!(decoded.get_enum("status"), Ok("Pending"));
+}
+
+#[test]
+fn enum_rejects_unknown_ordinal() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(ACCOUNT).unwrap();
+    let def = parser.struct_def("Account").unwrap();
+
+    let mut encoded = def.id().to_vec();
+    encoded.extend([7, 0, 0, 0, 3, 0, 0, 0]);
+    assert_eq!(
+        def.decode(&encoded).unwrap_err(),
+        Error::UnknownEnumVariant {
+            enum_name: "Status".to_string(),
+            value: 3
+        }
+    );
+}
+
+const TOLERANT_ACCOUNT: &'static str = r#"
+enum Status {
+    Active;
+    Closed;
+    Unknown = 255;
+}
+
+struct Account {
+    id :u32;
+    status :Status;
+}
+"#;
+
+#[test]
+fn unknown_enum_policy() {
+    let mut parser = Parser::default();
+    parser.add_file_defs(TOLERANT_ACCOUNT).unwrap();
+    let def = parser.struct_def("Account").unwrap();
+
+    let mut encoded = def.id().to_vec();
+    encoded.extend([7, 0, 0, 0, 2, 0, 0, 0]);
+    let strict = DecodeOptions {
+        unknown_enum: UnknownEnum::Error,
+        ..Default::default()
+    };
+    let unknown = Error::UnknownEnumVariant {
+        enum_name: "Status".to_string(),
+        value: 2,
+    };
+    assert_eq!(def.decode_with(&encoded, &strict).unwrap_err(), unknown);
+    assert_eq!(def.decode(&encoded).unwrap_err(), unknown);
+
+    let lenient = DecodeOptions {
+        unknown_enum: UnknownEnum::Fallback(255),
+        ..Default::default()
+    };
+    let decoded = def.decode_with(&encoded, &lenient).unwrap();
+    assert_eq!(decoded.get_enum("status"), Ok("Unknown"));
+    assert_eq!(decoded.get_u32("id"), Ok(7));
+
+    // Known values are left alone.
+    encoded[def.id().len() + 4] = 1;
+    let decoded = def.decode_with(&encoded, &lenient).unwrap();
+    assert_eq!(decoded.get_enum("status"), Ok("Closed"));
+
+    // A fallback the enum doesn't have is no help.
+    encoded[def.id().len() + 4] = 2;
+    let missing = DecodeOptions {
+        unknown_enum: UnknownEnum::Fallback(3),
+        ..Default::default()
+    };
+    assert_eq!(def.decode_with(&encoded, &missing).unwrap_err(), unknown);
+}